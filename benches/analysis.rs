@@ -0,0 +1,50 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use ws_cleaner::{
+    filtering::{find_unused_pkgs, Dependency},
+    parsing::find,
+    synthetic,
+};
+
+const SIZES: [usize; 3] = [100, 1000, 5000];
+
+fn traversal(c: &mut Criterion) {
+    let mut group = c.benchmark_group("find");
+    for n in SIZES {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("upstream");
+        synthetic::write_tree(&root, n).unwrap();
+        group.bench_with_input(BenchmarkId::from_parameter(n), &root, |b, root| {
+            b.iter(|| find(root).unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn filtering(c: &mut Criterion) {
+    let mut group = c.benchmark_group("find_unused_pkgs");
+    for n in SIZES {
+        let ws = synthetic::workspace(n);
+        let upstream = synthetic::upstream(n);
+        group.bench_with_input(BenchmarkId::from_parameter(n), &n, |b, _| {
+            b.iter(|| find_unused_pkgs(&ws, &upstream, &Dependency::all))
+        });
+    }
+    group.finish();
+}
+
+fn pipeline(c: &mut Criterion) {
+    let mut group = c.benchmark_group("pipeline");
+    for n in SIZES {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("upstream");
+        synthetic::write_tree(&root, n).unwrap();
+        let ws = synthetic::workspace(n);
+        group.bench_with_input(BenchmarkId::from_parameter(n), &root, |b, root| {
+            b.iter(|| find_unused_pkgs(&ws, &find(root).unwrap(), &Dependency::all))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, traversal, filtering, pipeline);
+criterion_main!(benches);
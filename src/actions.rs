@@ -0,0 +1,802 @@
+use std::{
+    collections::BTreeSet,
+    fs::{self, OpenOptions},
+    io::{self, BufRead, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context, Result};
+use clap::ValueEnum;
+
+use crate::{
+    filtering::{Package, RemovalSafety},
+    output::DisplayOptions,
+    parsing::{IGNORE_MARKERS, VCS_DIRS},
+};
+
+#[derive(ValueEnum, Clone, Debug, PartialEq)]
+pub enum Action {
+    /// Print all packages that are unused
+    Print,
+    /// Print the upstream packages that are used instead, e.g. to vendor just those
+    PrintUsed,
+    /// Place a COLCON_IGNORE file
+    ColconIgnore,
+    /// Place a CATKIN_IGNORE file
+    CatkinIgnore,
+    /// Remove the package folder
+    Remove,
+    /// List every ignore marker below the upstream, without any dependency analysis
+    ListMarkers,
+}
+
+impl Action {
+    /// The ignore marker this action places, if any.
+    pub fn marker(&self) -> Option<&'static str> {
+        match self {
+            Action::ColconIgnore => Some("COLCON_IGNORE"),
+            Action::CatkinIgnore => Some("CATKIN_IGNORE"),
+            Action::Print | Action::PrintUsed | Action::Remove | Action::ListMarkers => None,
+        }
+    }
+
+    /// How the run summary calls what this action did to packages, if anything.
+    pub fn summary_verb(&self, dry_run: bool) -> Option<&'static str> {
+        match (self, dry_run) {
+            (Action::Print | Action::PrintUsed | Action::ListMarkers, _) => None,
+            (Action::ColconIgnore | Action::CatkinIgnore, false) => Some("ignored"),
+            (Action::ColconIgnore | Action::CatkinIgnore, true) => Some("would ignore"),
+            (Action::Remove, false) => Some("removed"),
+            (Action::Remove, true) => Some("would remove"),
+        }
+    }
+}
+
+pub fn touch(path: &Path) -> Result<()> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map(|_| {})
+        .with_context(|| format!("Could not create '{}'", path.display()))
+}
+
+/// Create the ignore marker at `path`, returning whether it didn't exist yet.
+pub fn create_marker(path: &Path) -> Result<bool> {
+    match OpenOptions::new().write(true).create_new(true).open(path) {
+        Ok(_) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(false),
+        Err(e) => Err(e).with_context(|| format!("Could not create '{}'", path.display())),
+    }
+}
+
+fn list_files(dir: &Path, results: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in fs::read_dir(dir).with_context(|| format!("While listing '{}'", dir.display()))? {
+        let entry = entry.with_context(|| format!("While listing '{}'", dir.display()))?;
+        // Don't follow symlinks, remove_dir_all doesn't either
+        if entry.file_type()?.is_dir() {
+            list_files(&entry.path(), results)?;
+        } else {
+            results.push(entry.path());
+        }
+    }
+    Ok(())
+}
+
+/// Every ignore marker file below `dir` and which of [`IGNORE_MARKERS`] it is, sorted by path.
+pub fn find_markers(dir: &Path) -> Result<Vec<(PathBuf, &'static str)>> {
+    let mut res = Vec::new();
+    find_markers_into(dir, &mut res)?;
+    res.sort();
+    Ok(res)
+}
+
+fn find_markers_into(dir: &Path, results: &mut Vec<(PathBuf, &'static str)>) -> Result<()> {
+    for entry in fs::read_dir(dir).with_context(|| format!("While listing '{}'", dir.display()))? {
+        let entry = entry.with_context(|| format!("While listing '{}'", dir.display()))?;
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if entry.file_type()?.is_dir() {
+            if !VCS_DIRS.contains(&name.as_ref()) {
+                find_markers_into(&entry.path(), results)?;
+            }
+        } else if let Some(marker) = IGNORE_MARKERS.iter().find(|m| **m == name) {
+            results.push((entry.path(), marker));
+        }
+    }
+    Ok(())
+}
+
+/// Every path `action` would create or delete for the given packages.
+///
+/// For [`Action::Remove`] this is the package directory itself, or every file contained
+/// in it if `expand` is set, followed by the directories below `prune_below` that are left
+/// empty, see [`empty_parents`]. Ignore markers that are already present aren't listed.
+pub fn affected_paths(
+    action: &Action,
+    packages: &[Package],
+    expand: bool,
+    prune_below: Option<&Path>,
+) -> Result<Vec<PathBuf>> {
+    if let Some(marker) = action.marker() {
+        return Ok(marker_diff(marker, packages, packages)?.create);
+    }
+    let mut res = Vec::new();
+    for pkg in packages {
+        match action {
+            Action::Print | Action::PrintUsed | Action::ListMarkers => {}
+            Action::ColconIgnore | Action::CatkinIgnore => unreachable!("Markers are listed above"),
+            Action::Remove if expand => {
+                let mut files = Vec::new();
+                list_files(&pkg.path, &mut files)?;
+                files.sort();
+                res.append(&mut files);
+            }
+            Action::Remove => res.push(pkg.path.clone()),
+        }
+    }
+    if let (Action::Remove, Some(root)) = (action, prune_below) {
+        res.extend(empty_parents(root, packages)?);
+    }
+    Ok(res)
+}
+
+/// Where [`move_to_trash`] would put `package` in `trash`: its folder name, with a numeric
+/// suffix like `pkg.1` if that is taken.
+pub fn trash_destination(package: &Path, trash: &Path) -> Result<PathBuf> {
+    let name = package
+        .file_name()
+        .with_context(|| format!("'{}' has no folder name", package.display()))?;
+    let mut dest = trash.join(name);
+    let mut n = 0;
+    while dest
+        .try_exists()
+        .with_context(|| format!("While trying to check '{}'", dest.display()))?
+    {
+        n += 1;
+        let mut suffixed = name.to_os_string();
+        suffixed.push(format!(".{n}"));
+        dest = trash.join(suffixed);
+    }
+    Ok(dest)
+}
+
+/// Copy `from` to `to` recursively, keeping symlinks as links.
+fn copy_recursively(from: &Path, to: &Path) -> Result<()> {
+    fs::create_dir(to).with_context(|| format!("Could not create '{}'", to.display()))?;
+    for entry in
+        fs::read_dir(from).with_context(|| format!("While listing '{}'", from.display()))?
+    {
+        let entry = entry.with_context(|| format!("While listing '{}'", from.display()))?;
+        let (src, dest) = (entry.path(), to.join(entry.file_name()));
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            copy_recursively(&src, &dest)?;
+        } else if file_type.is_symlink() {
+            #[cfg(unix)]
+            std::os::unix::fs::symlink(fs::read_link(&src)?, &dest)
+                .with_context(|| format!("Could not copy link '{}'", src.display()))?;
+            #[cfg(not(unix))]
+            fs::copy(&src, &dest).with_context(|| format!("Could not copy '{}'", src.display()))?;
+        } else {
+            fs::copy(&src, &dest).with_context(|| format!("Could not copy '{}'", src.display()))?;
+        }
+    }
+    Ok(())
+}
+
+/// Move the `package` folder into `trash` (created if missing) instead of deleting it, see
+/// [`trash_destination`]. Across file systems it is copied and then removed. Returns the new
+/// location.
+pub fn move_to_trash(package: &Path, trash: &Path) -> Result<PathBuf> {
+    fs::create_dir_all(trash).with_context(|| format!("Could not create '{}'", trash.display()))?;
+    let dest = trash_destination(package, trash)?;
+    match fs::rename(package, &dest) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+            copy_recursively(package, &dest)?;
+            fs::remove_dir_all(package)
+                .with_context(|| format!("Could not remove '{}'", package.display()))?;
+        }
+        Err(e) => {
+            return Err(e).with_context(|| {
+                format!(
+                    "Could not move '{}' to '{}'",
+                    package.display(),
+                    dest.display()
+                )
+            })
+        }
+    }
+    Ok(dest)
+}
+
+/// How the ignore markers on disk differ from what an ignore action wants.
+#[derive(Debug, Default, PartialEq)]
+pub struct MarkerDiff {
+    /// Markers that would be created for unused packages
+    pub create: Vec<PathBuf>,
+    /// Markers of unused packages that already exist
+    pub present: Vec<PathBuf>,
+    /// Markers of packages that are used now
+    pub stale: Vec<PathBuf>,
+}
+
+/// Compare the `marker` files of `upstream` packages (which must have been discovered without
+/// skipping that marker) against the ones an ignore action would place for `unused`.
+pub fn marker_diff(marker: &str, upstream: &[Package], unused: &[Package]) -> Result<MarkerDiff> {
+    let mut res = MarkerDiff::default();
+    for p in upstream {
+        let path = p.path.join(marker);
+        let exists = path
+            .try_exists()
+            .with_context(|| format!("While trying to check '{}'", path.display()))?;
+        match (unused.contains(p), exists) {
+            (true, false) => res.create.push(path),
+            (true, true) => res.present.push(path),
+            (false, true) => res.stale.push(path),
+            (false, false) => {}
+        }
+    }
+    Ok(res)
+}
+
+/// Ask about each package in turn with `Remove 'name' at 'path'? [y/N/a/q]`, returning those
+/// approved. `a` approves all remaining packages and `q` aborts, approving none.
+pub fn confirm_each<'a>(
+    packages: &'a [Package],
+    display: &DisplayOptions,
+    input: &mut impl BufRead,
+    output: &mut impl Write,
+) -> Result<Vec<&'a Package>> {
+    let mut res = Vec::new();
+    let mut all = false;
+    for p in packages {
+        if !all {
+            write!(
+                output,
+                "Remove '{}' at '{}'? [y/N/a/q] ",
+                p.name,
+                display.path(&p.path).display()
+            )?;
+            output.flush()?;
+            let mut answer = String::new();
+            input.read_line(&mut answer)?;
+            match answer.trim().to_lowercase().as_str() {
+                "y" | "yes" => {}
+                "a" | "all" => all = true,
+                "q" | "quit" => return Err(anyhow!("Aborted, nothing was removed")),
+                _ => continue,
+            }
+        }
+        res.push(p);
+    }
+    Ok(res)
+}
+
+/// What `--interactive` does with one package.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Decision {
+    Remove,
+    /// Place the ignore marker
+    Ignore,
+    Skip,
+}
+
+impl Decision {
+    /// The answer assumed for an empty line, following the global `action`.
+    pub fn default_for(action: &Action) -> Decision {
+        match action {
+            Action::Remove => Decision::Remove,
+            Action::ColconIgnore | Action::CatkinIgnore => Decision::Ignore,
+            Action::Print | Action::PrintUsed | Action::ListMarkers => Decision::Skip,
+        }
+    }
+}
+
+/// Show each unused package with the upstream packages depending on it, which are all unused
+/// as well, and ask `[r]emove / [i]gnore / [s]kip / [q]uit`. An empty answer takes `default`,
+/// others are asked again. `q` or the end of `input` skips all remaining packages, keeping the
+/// decisions made so far.
+pub fn decide_each<'a>(
+    packages: &[RemovalSafety<'a>],
+    default: Decision,
+    display: &DisplayOptions,
+    input: &mut impl BufRead,
+    output: &mut impl Write,
+) -> Result<Vec<(&'a Package, Decision)>> {
+    let default_key = match default {
+        Decision::Remove => "r",
+        Decision::Ignore => "i",
+        Decision::Skip => "s",
+    };
+    let mut res = Vec::new();
+    for safety in packages {
+        let p = safety.package;
+        writeln!(
+            output,
+            "\n'{}' at '{}'",
+            p.name,
+            display.path(&p.path).display()
+        )?;
+        if safety.dependents.is_empty() {
+            writeln!(output, "  Nothing depends on it")?;
+        } else {
+            let names: Vec<&str> = safety.dependents.iter().map(|d| d.name.as_str()).collect();
+            writeln!(output, "  Depended on by the unused {}", names.join(", "))?;
+        }
+        let decision = loop {
+            write!(
+                output,
+                "[r]emove / [i]gnore / [s]kip / [q]uit (default {default_key})? "
+            )?;
+            output.flush()?;
+            let mut answer = String::new();
+            if input.read_line(&mut answer)? == 0 {
+                return Ok(res);
+            }
+            match answer.trim().to_lowercase().as_str() {
+                "" => break default,
+                "r" | "remove" => break Decision::Remove,
+                "i" | "ignore" => break Decision::Ignore,
+                "s" | "skip" => break Decision::Skip,
+                "q" | "quit" => return Ok(res),
+                _ => {}
+            }
+        };
+        res.push((p, decision));
+    }
+    Ok(res)
+}
+
+/// List the folders of `packages` and ask once whether to delete them all, only accepting
+/// an explicit `y`.
+pub fn confirm_all(
+    packages: &[Package],
+    display: &DisplayOptions,
+    input: &mut impl BufRead,
+    output: &mut impl Write,
+) -> Result<bool> {
+    writeln!(output, "About to delete:")?;
+    for p in packages {
+        writeln!(output, "  {}", display.path(&p.path).display())?;
+    }
+    write!(output, "Delete {} directories? [y/N] ", packages.len())?;
+    output.flush()?;
+    let mut answer = String::new();
+    input.read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+/// Refuse to run `action` if it would remove `root` itself, e.g. because the upstream
+/// path points directly at a package or its manifest.
+pub fn check_root_kept(action: &Action, root: &Path, packages: &[Package]) -> Result<()> {
+    if *action == Action::Remove && packages.iter().any(|p| root.starts_with(&p.path)) {
+        return Err(anyhow!(
+            "Refusing to remove the upstream root directory '{}'",
+            root.display()
+        ));
+    }
+    Ok(())
+}
+
+/// The directories that become empty by removing `removed`, walking up from each package's
+/// parent towards `root`, which is never included. Gives the same result before and after the
+/// packages are removed, deepest directories first.
+pub fn empty_parents(root: &Path, removed: &[Package]) -> Result<Vec<PathBuf>> {
+    let mut gone: BTreeSet<PathBuf> = removed.iter().map(|p| p.path.clone()).collect();
+    let mut res = Vec::new();
+    for pkg in removed {
+        let mut dir = pkg.path.parent();
+        while let Some(current) = dir.filter(|d| d.starts_with(root) && *d != root) {
+            if gone.contains(current) {
+                break;
+            }
+            let mut entries = fs::read_dir(current)
+                .with_context(|| format!("While listing '{}'", current.display()))?;
+            if !entries.all(|e| e.is_ok_and(|e| gone.contains(&e.path()))) {
+                break;
+            }
+            gone.insert(current.to_path_buf());
+            res.push(current.to_path_buf());
+            dir = current.parent();
+        }
+    }
+    Ok(res)
+}
+
+/// Delete the directories that became empty by removing `removed`, see [`empty_parents`].
+/// Returns the deleted directories.
+pub fn remove_empty_parents(root: &Path, removed: &[Package]) -> Result<Vec<PathBuf>> {
+    let res = empty_parents(root, removed)?;
+    for dir in res.iter() {
+        fs::remove_dir(dir).with_context(|| format!("Could not remove '{}'", dir.display()))?;
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeSet, fs, path::Path};
+
+    use super::*;
+    use crate::{
+        filtering::{find_unused_pkgs, Dependency},
+        parsing::{find, find_with_options, ParseOptions},
+    };
+
+    fn package_at(root: &Path, name: &str) -> Package {
+        let path = root.join(name);
+        fs::create_dir_all(path.join("src")).unwrap();
+        fs::write(path.join("package.xml"), "<package/>").unwrap();
+        fs::write(path.join("src").join("main.cpp"), "").unwrap();
+        Package {
+            name: name.to_string(),
+            path,
+            deps: vec![],
+            ..Default::default()
+        }
+    }
+
+    fn snapshot(root: &Path) -> BTreeSet<PathBuf> {
+        let mut files = Vec::new();
+        list_files(root, &mut files).unwrap();
+        files.into_iter().collect()
+    }
+
+    #[test]
+    fn affected_files_match_ignore_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let pkgs = vec![package_at(dir.path(), "a"), package_at(dir.path(), "b")];
+        touch(&pkgs[1].path.join("COLCON_IGNORE")).unwrap();
+        let affected = affected_paths(&Action::ColconIgnore, &pkgs, false, None).unwrap();
+        assert_eq!(affected, [pkgs[0].path.join("COLCON_IGNORE")]);
+
+        let before = snapshot(dir.path());
+        for p in &affected {
+            touch(p).unwrap();
+        }
+        let after = snapshot(dir.path());
+        let created: BTreeSet<_> = after.difference(&before).cloned().collect();
+        assert_eq!(created, affected.into_iter().collect());
+    }
+
+    #[test]
+    fn affected_files_match_remove_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let pkgs = vec![package_at(dir.path(), "a"), package_at(dir.path(), "b")];
+        let kept = package_at(dir.path(), "kept");
+
+        let top_level = affected_paths(&Action::Remove, &pkgs, false, None).unwrap();
+        assert_eq!(top_level, vec![pkgs[0].path.clone(), pkgs[1].path.clone()]);
+        let expanded = affected_paths(&Action::Remove, &pkgs, true, None).unwrap();
+
+        let before = snapshot(dir.path());
+        for p in &pkgs {
+            fs::remove_dir_all(&p.path).unwrap();
+        }
+        let after = snapshot(dir.path());
+        let deleted: BTreeSet<_> = before.difference(&after).cloned().collect();
+        assert_eq!(deleted, expanded.into_iter().collect());
+        assert!(kept.path.join("package.xml").exists());
+    }
+
+    #[test]
+    fn affected_files_include_pruned_parents() {
+        let dir = tempfile::tempdir().unwrap();
+        let group = dir.path().join("group");
+        let pkgs = vec![package_at(&group, "a"), package_at(dir.path(), "b")];
+        package_at(dir.path(), "kept");
+
+        let affected = affected_paths(&Action::Remove, &pkgs, false, Some(dir.path())).unwrap();
+        assert_eq!(
+            affected,
+            [pkgs[0].path.clone(), pkgs[1].path.clone(), group]
+        );
+        assert_eq!(
+            affected_paths(&Action::Remove, &pkgs, false, None).unwrap(),
+            &affected[..2]
+        );
+    }
+
+    #[test]
+    fn refuses_to_remove_root() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap().join("lone");
+        fs::create_dir(&root).unwrap();
+        fs::write(
+            root.join("package.xml"),
+            "<package><name>lone</name></package>",
+        )
+        .unwrap();
+        let upstream = find(&root).unwrap();
+        let ws = vec![package_at(&root, "other")];
+        let unused = find_unused_pkgs(&ws, &upstream, &Dependency::all);
+        assert_eq!(unused, upstream);
+
+        check_root_kept(&Action::Remove, &root, &unused).expect_err("Should have refused");
+        let manifest = root.join("package.xml");
+        assert_eq!(find(&manifest).unwrap(), upstream);
+        check_root_kept(&Action::Remove, &manifest, &unused).expect_err("Should have refused");
+        check_root_kept(&Action::ColconIgnore, &root, &unused).unwrap();
+        check_root_kept(&Action::Remove, &root, &[]).unwrap();
+    }
+
+    #[test]
+    fn creates_markers_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let marker = dir.path().join("COLCON_IGNORE");
+        assert!(create_marker(&marker).unwrap());
+        assert!(!create_marker(&marker).unwrap());
+        assert!(marker.is_file());
+        assert!(create_marker(&dir.path().join("missing").join("COLCON_IGNORE")).is_err());
+    }
+
+    #[test]
+    fn moves_to_trash() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("upstream");
+        let trash = dir.path().join("trash");
+        let first = package_at(&root, "pkg");
+        let second = package_at(&root.join("other"), "pkg");
+        let third = package_at(&root.join("third"), "pkg");
+
+        let moved: Vec<PathBuf> = [&first, &second, &third]
+            .iter()
+            .map(|p| move_to_trash(&p.path, &trash).unwrap())
+            .collect();
+        assert_eq!(
+            moved,
+            [trash.join("pkg"), trash.join("pkg.1"), trash.join("pkg.2")]
+        );
+        assert!(!first.path.exists());
+        assert!(moved[0].join("package.xml").exists());
+        assert!(moved[0].join("src").join("main.cpp").exists());
+
+        // Copying (across file systems) keeps the contents too
+        let copy = dir.path().join("copy");
+        copy_recursively(&moved[1], &copy).unwrap();
+        assert!(copy.join("src").join("main.cpp").exists());
+    }
+
+    #[test]
+    fn removes_emptied_parents() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("upstream");
+        let vendor = root.join("src").join("vendor");
+        let lone = package_at(&vendor.join("nested"), "lone");
+        let shared = root.join("shared");
+        let gone = package_at(&shared, "gone");
+        let kept = package_at(&shared, "kept");
+
+        // A dry run sees the same directories before anything is removed
+        let planned = empty_parents(&root, &[lone.clone(), gone.clone()]).unwrap();
+        assert_eq!(
+            planned,
+            [vendor.join("nested"), vendor.clone(), root.join("src")]
+        );
+        for p in [&lone, &gone] {
+            fs::remove_dir_all(&p.path).unwrap();
+        }
+        let removed = remove_empty_parents(&root, &[lone, gone]).unwrap();
+        assert_eq!(removed, planned);
+        assert!(!vendor.exists());
+        assert!(kept.path.join("package.xml").exists());
+        assert!(root.exists());
+    }
+
+    #[test]
+    fn marker_inventory() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("upstream");
+        let a = package_at(&root, "a");
+        let b = package_at(&root.join("nested"), "b");
+        let c = package_at(&root, "c");
+        for path in [
+            a.path.join("COLCON_IGNORE"),
+            a.path.join("AMENT_IGNORE"),
+            b.path.join("COLCON_IGNORE"),
+            c.path.join("CATKIN_IGNORE"),
+            root.join(".git").join("COLCON_IGNORE"),
+        ] {
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            touch(&path).unwrap();
+        }
+        let markers = find_markers(&root).unwrap();
+        assert_eq!(
+            markers,
+            [
+                (a.path.join("AMENT_IGNORE"), "AMENT_IGNORE"),
+                (a.path.join("COLCON_IGNORE"), "COLCON_IGNORE"),
+                (c.path.join("CATKIN_IGNORE"), "CATKIN_IGNORE"),
+                (b.path.join("COLCON_IGNORE"), "COLCON_IGNORE"),
+            ]
+        );
+        let counts: Vec<usize> = IGNORE_MARKERS
+            .iter()
+            .map(|m| markers.iter().filter(|(_, t)| t == m).count())
+            .collect();
+        assert_eq!(counts, [2, 1, 1]);
+    }
+
+    #[test]
+    fn confirms_all_removals() {
+        let pkgs = vec![
+            Package {
+                name: "a".into(),
+                path: "/upstream/a".into(),
+                ..Default::default()
+            },
+            Package {
+                name: "b".into(),
+                path: "/upstream/b".into(),
+                ..Default::default()
+            },
+        ];
+        let mut prompt = Vec::new();
+        let display = DisplayOptions::default();
+        assert!(confirm_all(&pkgs, &display, &mut "y\n".as_bytes(), &mut prompt).unwrap());
+        assert_eq!(
+            String::from_utf8(prompt).unwrap(),
+            "About to delete:\n  /upstream/a\n  /upstream/b\nDelete 2 directories? [y/N] "
+        );
+        for answer in ["\n", "n\n", "a\n", ""] {
+            let mut input = answer.as_bytes();
+            assert!(!confirm_all(&pkgs, &display, &mut input, &mut Vec::new()).unwrap());
+        }
+    }
+
+    #[test]
+    fn confirms_each_removal() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("upstream");
+        let pkgs: Vec<Package> = ["a", "b", "c", "d", "e"]
+            .iter()
+            .map(|n| package_at(&root, n))
+            .collect();
+        let mut prompts = Vec::new();
+        let mut input = "y\n\nn\na\n".as_bytes();
+        let approved =
+            confirm_each(&pkgs, &DisplayOptions::default(), &mut input, &mut prompts).unwrap();
+        for p in approved.iter() {
+            fs::remove_dir_all(&p.path).unwrap();
+        }
+        let prompts = String::from_utf8(prompts).unwrap();
+        assert_eq!(prompts.matches("[y/N/a/q]").count(), 4);
+        assert!(prompts.starts_with(&format!(
+            "Remove 'a' at '{}'? [y/N/a/q] ",
+            pkgs[0].path.display()
+        )));
+        let remaining: Vec<bool> = pkgs.iter().map(|p| p.path.exists()).collect();
+        assert_eq!(remaining, [false, true, true, false, false]);
+
+        let mut quit = "y\nq\n".as_bytes();
+        confirm_each(
+            &pkgs,
+            &DisplayOptions::default(),
+            &mut quit,
+            &mut Vec::new(),
+        )
+        .expect_err("Should have aborted");
+        // Running out of input declines the rest
+        let none = confirm_each(
+            &pkgs,
+            &DisplayOptions::default(),
+            &mut "".as_bytes(),
+            &mut Vec::new(),
+        );
+        assert!(none.unwrap().is_empty());
+    }
+
+    #[test]
+    fn decides_each_package() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("upstream");
+        let pkgs: Vec<Package> = ["a", "b", "c", "d", "e"]
+            .iter()
+            .map(|n| package_at(&root, n))
+            .collect();
+        let safety: Vec<RemovalSafety> = pkgs
+            .iter()
+            .map(|p| RemovalSafety {
+                package: p,
+                dependents: if p.name == "a" {
+                    vec![&pkgs[1]]
+                } else {
+                    vec![]
+                },
+                kept_dependents: vec![],
+            })
+            .collect();
+        let mut prompts = Vec::new();
+        let mut input = "x
+
+i
+R
+q
+"
+        .as_bytes();
+        let decisions = decide_each(
+            &safety,
+            Decision::default_for(&Action::Remove),
+            &DisplayOptions::default(),
+            &mut input,
+            &mut prompts,
+        )
+        .unwrap();
+        assert_eq!(
+            decisions,
+            [
+                (&pkgs[0], Decision::Remove),
+                (&pkgs[1], Decision::Ignore),
+                (&pkgs[2], Decision::Remove)
+            ]
+        );
+        let prompts = String::from_utf8(prompts).unwrap();
+        assert_eq!(prompts.matches("[q]uit (default r)? ").count(), 5);
+        assert!(prompts.contains("Depended on by the unused b"));
+
+        let skipped = decide_each(
+            &safety,
+            Decision::default_for(&Action::Print),
+            &DisplayOptions::default(),
+            &mut "\n".as_bytes(),
+            &mut Vec::new(),
+        )
+        .unwrap();
+        assert_eq!(skipped, [(&pkgs[0], Decision::Skip)]);
+    }
+
+    #[test]
+    fn marker_differences() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("upstream");
+        let write = |name: &str, marker: bool| {
+            let path = root.join(name);
+            fs::create_dir_all(&path).unwrap();
+            fs::write(
+                path.join("package.xml"),
+                format!("<package><name>{name}</name></package>"),
+            )
+            .unwrap();
+            if marker {
+                touch(&path.join("COLCON_IGNORE")).unwrap();
+            }
+            path
+        };
+        write("used", false);
+        let missing = write("missing", false);
+        let present = write("present", true);
+        let stale = write("stale", true);
+
+        let mut options = ParseOptions::default();
+        options.ignore_markers.retain(|m| m != "COLCON_IGNORE");
+        let upstream = find_with_options(&root, &options).unwrap();
+        assert_eq!(upstream.len(), 4);
+        assert_eq!(find(&root).unwrap().len(), 2);
+
+        let ws = vec![Package {
+            deps: vec![
+                Dependency {
+                    name: "used".into(),
+                    dep_type: crate::filtering::DepType::All,
+                },
+                Dependency {
+                    name: "stale".into(),
+                    dep_type: crate::filtering::DepType::All,
+                },
+            ],
+            ..package_at(dir.path(), "ws")
+        }];
+        let unused = find_unused_pkgs(&ws, &upstream, &Dependency::all);
+        let diff = marker_diff("COLCON_IGNORE", &upstream, &unused).unwrap();
+        assert_eq!(
+            diff,
+            MarkerDiff {
+                create: vec![missing.join("COLCON_IGNORE")],
+                present: vec![present.join("COLCON_IGNORE")],
+                stale: vec![stale.join("COLCON_IGNORE")],
+            }
+        );
+    }
+}
@@ -0,0 +1,366 @@
+//! The unused package computation as a library call, without any action on the packages.
+
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context, Result};
+use glob::Pattern;
+
+use crate::{
+    filtering::{
+        dedup_packages, find_unused_pkgs, merge_packages, split_matching, split_under, DepFilter,
+        DepType, Dependency, MergeStrategy, Package,
+    },
+    parsing::{canonicalize_all, find_in_reporting, InvalidManifest, ParseOptions},
+    vfs::LocalFilesystem,
+};
+
+/// What [`analyze_with`] found, each package list sorted by name and path.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Analysis {
+    pub workspace: Vec<Package>,
+    pub upstream: Vec<Package>,
+    pub unused: Vec<Package>,
+    /// The canonical upstream directories that were searched
+    pub upstream_paths: Vec<PathBuf>,
+    /// The canonical workspace directories that were searched, in the given order
+    pub workspace_paths: Vec<PathBuf>,
+    /// Workspace directories that couldn't be normalized and weren't searched, with why
+    pub invalid_workspaces: Vec<(PathBuf, String)>,
+    /// Manifests skipped with [`ParseOptions::skip_invalid`], with why they failed to parse
+    pub invalid_manifests: Vec<(PathBuf, String)>,
+    /// Workspace packages left out by [`AnalysisOptions::workspace_exclude`]
+    pub excluded: Vec<Package>,
+}
+
+/// How [`analyze_with`] searches for packages and which of them make up the workspace.
+pub struct AnalysisOptions<'a> {
+    /// How the upstream directories are searched
+    pub upstream: ParseOptions,
+    /// How the workspace directories are searched
+    pub workspace: ParseOptions,
+    /// Upstream packages matching any of these (names or globs like `nav2_*`) are added to
+    /// the workspace
+    pub packages: Vec<Pattern>,
+    /// Upstream packages at these canonical roots are added to the workspace, e.g. those
+    /// of a `compile_commands.json`
+    pub package_roots: Vec<PathBuf>,
+    /// Upstream packages below these directories are moved into the workspace
+    pub treat_as_workspace: Vec<PathBuf>,
+    /// Workspace packages matching any of these are left out of it
+    pub workspace_exclude: Vec<Pattern>,
+    /// Fail if the workspace ends up empty, as every upstream package would be unused
+    pub require_workspace: bool,
+    /// The dependencies that keep packages used
+    pub filter: &'a DepFilter,
+}
+
+impl Default for AnalysisOptions<'_> {
+    fn default() -> Self {
+        AnalysisOptions {
+            upstream: ParseOptions::default(),
+            workspace: ParseOptions::default(),
+            packages: Vec::new(),
+            package_roots: Vec::new(),
+            treat_as_workspace: Vec::new(),
+            workspace_exclude: Vec::new(),
+            require_workspace: true,
+            filter: &Dependency::all,
+        }
+    }
+}
+
+/// The packages below `paths`, and the manifests skipped with `skip_invalid`.
+fn find_all(
+    paths: &[PathBuf],
+    options: &ParseOptions,
+    what: &str,
+) -> Result<(Vec<Package>, Vec<InvalidManifest>)> {
+    let mut lists = Vec::new();
+    let mut invalid = Vec::new();
+    for path in paths {
+        let (packages, skipped) = find_in_reporting(&LocalFilesystem, path, options)
+            .with_context(|| format!("Could not enumerate {what} '{}'", path.display()))?;
+        lists.push(packages);
+        invalid.extend(skipped);
+    }
+    let mut res = merge_packages(&lists, MergeStrategy::KeepAll)?;
+    dedup_packages(&mut res);
+    Ok((res, invalid))
+}
+
+/// Fail if any of the `workspaces` is the same directory as one of the `upstream` ones, all
+/// canonical. Every package would count as used, or with a typo, nearly none.
+pub fn check_distinct(upstream: &[PathBuf], workspaces: &[PathBuf]) -> Result<()> {
+    match upstream.iter().find(|u| workspaces.contains(u)) {
+        Some(path) => Err(anyhow!(
+            "'{}' is given as both upstream and workspace, they should be distinct directories",
+            path.display()
+        )),
+        None => Ok(()),
+    }
+}
+
+fn canonicalize(path: &Path) -> Result<PathBuf> {
+    path.canonicalize()
+        .with_context(|| format!("Could not normalize '{}'", path.display()))
+}
+
+/// The workspace directories to search: `workspaces`, or the current directory if neither
+/// workspaces nor upstream packages are given.
+pub fn workspace_roots(workspaces: &[PathBuf], has_packages: bool) -> Vec<PathBuf> {
+    if workspaces.is_empty() && !has_packages {
+        vec![PathBuf::from(".")]
+    } else {
+        workspaces.to_vec()
+    }
+}
+
+/// Fail if `workspace` is empty, naming the requested `workspaces` and `packages`.
+fn check_workspace(
+    workspace: &[Package],
+    workspaces: &[PathBuf],
+    packages: &[Pattern],
+) -> Result<()> {
+    if !workspace.is_empty() {
+        return Ok(());
+    }
+    let or_none = |names: Vec<String>| match names.is_empty() {
+        true => "<none>".to_string(),
+        false => names.join(", "),
+    };
+    Err(anyhow!(
+        "The filtered workspace is empty! This would remove all packages. Check your command line!\nRequested workspace: {}\nRequested packages: {}",
+        or_none(workspaces.iter().map(|w| w.display().to_string()).collect()),
+        or_none(packages.iter().map(|p| p.as_str().to_string()).collect())
+    ))
+}
+
+/// Find the packages below `upstream` that the workspace doesn't depend on: the packages
+/// below `workspaces`, along with the upstream packages selected by `options`. Unlike
+/// [`analyze`], the `workspaces` are searched as given, and those that can't be normalized
+/// are only reported, unless none can.
+pub fn analyze_with(
+    upstream: &[PathBuf],
+    workspaces: &[PathBuf],
+    options: &AnalysisOptions,
+) -> Result<Analysis> {
+    let upstream_paths = upstream
+        .iter()
+        .map(|p| canonicalize(p))
+        .collect::<Result<Vec<_>>>()?;
+    let (mut workspace_paths, invalid_workspaces) = canonicalize_all(workspaces);
+    if workspace_paths.is_empty() && !invalid_workspaces.is_empty() {
+        let reasons: Vec<String> = invalid_workspaces
+            .iter()
+            .map(|(path, e)| format!("'{}': {e}", path.display()))
+            .collect();
+        return Err(anyhow!(
+            "None of the given workspaces are valid: {}",
+            reasons.join(", ")
+        ));
+    }
+    // Keep the first of repeated workspaces, as each is reported on in turn
+    let mut seen = HashSet::new();
+    workspace_paths.retain(|p| seen.insert(p.clone()));
+    check_distinct(&upstream_paths, &workspace_paths)?;
+
+    let (mut upstream, mut invalid) = find_all(&upstream_paths, &options.upstream, "upstream")?;
+    let (mut workspace, skipped) = find_all(&workspace_paths, &options.workspace, "workspace")?;
+    invalid.extend(skipped);
+
+    let (_, selected) = split_matching(upstream.clone(), &options.packages);
+    workspace.extend(selected);
+    workspace.extend(
+        upstream
+            .iter()
+            .filter(|p| options.package_roots.contains(&p.path))
+            .cloned(),
+    );
+    if !options.treat_as_workspace.is_empty() {
+        let dirs = options
+            .treat_as_workspace
+            .iter()
+            .map(|p| canonicalize(p))
+            .collect::<Result<Vec<_>>>()?;
+        let promoted;
+        (upstream, promoted) = split_under(upstream, &dirs);
+        workspace.extend(promoted);
+    }
+    dedup_packages(&mut workspace);
+    let (workspace, excluded) = split_matching(workspace, &options.workspace_exclude);
+    if options.require_workspace {
+        check_workspace(&workspace, &workspace_paths, &options.packages)?;
+    }
+
+    let mut unused = find_unused_pkgs(&workspace, &upstream, options.filter);
+    dedup_packages(&mut unused);
+    Ok(Analysis {
+        workspace,
+        upstream,
+        unused,
+        upstream_paths,
+        workspace_paths,
+        invalid_workspaces: invalid_workspaces
+            .into_iter()
+            .map(|(path, e)| (path, e.to_string()))
+            .collect(),
+        invalid_manifests: invalid
+            .into_iter()
+            .map(|(path, e)| (path, format!("{e:#}")))
+            .collect(),
+        excluded,
+    })
+}
+
+/// Find the packages below `upstream` that neither the packages below `workspaces` nor
+/// the upstream `packages` (names or globs like `nav2_*`) depend on, following only
+/// dependencies of `types`, or all if empty. Without workspaces and packages, the current
+/// directory is the workspace, see [`workspace_roots`].
+pub fn analyze(
+    upstream: &[PathBuf],
+    workspaces: &[PathBuf],
+    packages: &[String],
+    types: &[DepType],
+) -> Result<Analysis> {
+    let patterns = packages
+        .iter()
+        .map(|p| Pattern::new(p).with_context(|| format!("Invalid package pattern '{p}'")))
+        .collect::<Result<Vec<_>>>()?;
+    let matcher = Dependency::matcher(types.to_vec());
+    let options = AnalysisOptions {
+        packages: patterns,
+        filter: if types.is_empty() {
+            &Dependency::all
+        } else {
+            &matcher
+        },
+        ..Default::default()
+    };
+    let res = analyze_with(
+        upstream,
+        &workspace_roots(workspaces, !packages.is_empty()),
+        &options,
+    )?;
+    match res.invalid_workspaces.first() {
+        Some((path, e)) => Err(anyhow!("Could not normalize '{}': {e}", path.display())),
+        None => Ok(res),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use super::*;
+    use crate::parsing::read_path_list;
+
+    fn write_package(dir: &Path, name: &str, deps: &[(&str, &str)]) {
+        fs::create_dir_all(dir.join(name)).unwrap();
+        let deps: String = deps
+            .iter()
+            .map(|(tag, dep)| format!("<{tag}>{dep}</{tag}>"))
+            .collect();
+        fs::write(
+            dir.join(name).join("package.xml"),
+            format!("<package><name>{name}</name>{deps}</package>"),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn analyzes_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        let upstream = vec![dir.path().join("upstream")];
+        let ws = vec![dir.path().join("ws")];
+        write_package(&upstream[0], "a", &[]);
+        write_package(&upstream[0], "b", &[]);
+        write_package(&upstream[0], "c", &[("depend", "a")]);
+        write_package(&upstream[0], "tools", &[]);
+        write_package(&ws[0], "robot", &[("depend", "c"), ("test_depend", "b")]);
+
+        let res = analyze(&upstream, &ws, &[], &[]).unwrap();
+        let names = |packages: &[Package]| -> Vec<String> {
+            packages.iter().map(|p| p.name.clone()).collect()
+        };
+        assert_eq!(names(&res.workspace), ["robot"]);
+        assert_eq!(names(&res.upstream), ["a", "b", "c", "tools"]);
+        assert_eq!(names(&res.unused), ["tools"]);
+        assert!(res.unused[0]
+            .path
+            .starts_with(upstream[0].canonicalize().unwrap()));
+
+        let res = analyze(&upstream, &ws, &["to*".into()], &[DepType::Build]).unwrap();
+        assert_eq!(names(&res.unused), ["b"]);
+
+        assert!(analyze(&upstream, &[], &["nothing".into()], &[]).is_err());
+        assert!(analyze(&upstream, &[dir.path().join("missing")], &[], &[]).is_err());
+
+        let same = vec![upstream[0].join(".")];
+        let err = analyze(&upstream, &same, &[], &[]).unwrap_err();
+        assert!(err.to_string().contains("both upstream and workspace"));
+    }
+
+    #[test]
+    fn analyzes_with_options() {
+        let dir = tempfile::tempdir().unwrap();
+        let upstream = vec![dir.path().join("upstream")];
+        let ws = dir.path().join("ws");
+        write_package(&upstream[0], "a", &[]);
+        write_package(&upstream[0].join("tools"), "b", &[("depend", "a")]);
+        write_package(&upstream[0], "c", &[]);
+        write_package(&ws, "robot", &[]);
+        write_package(&ws, "robot_example", &[("depend", "c")]);
+        let names = |packages: &[Package]| -> Vec<String> {
+            packages.iter().map(|p| p.name.clone()).collect()
+        };
+
+        let options = AnalysisOptions {
+            treat_as_workspace: vec![upstream[0].join("tools")],
+            workspace_exclude: vec![Pattern::new("*_example").unwrap()],
+            ..Default::default()
+        };
+        let missing = dir.path().join("missing");
+        let res = analyze_with(&upstream, &[ws.clone(), missing.clone()], &options).unwrap();
+        assert_eq!(names(&res.workspace), ["b", "robot"]);
+        assert_eq!(names(&res.upstream), ["a", "c"]);
+        assert_eq!(names(&res.unused), ["c"]);
+        assert_eq!(names(&res.excluded), ["robot_example"]);
+        assert_eq!(res.workspace_paths, [ws.canonicalize().unwrap()]);
+        assert_eq!(res.invalid_workspaces[0].0, missing);
+
+        let err = analyze_with(&upstream, &[missing], &options).unwrap_err();
+        assert!(err.to_string().contains("None of the given workspaces"));
+        let err = analyze_with(&upstream, &[], &AnalysisOptions::default()).unwrap_err();
+        assert!(err.to_string().contains("workspace is empty"));
+        let upstream_only = AnalysisOptions {
+            require_workspace: false,
+            ..Default::default()
+        };
+        let res = analyze_with(&upstream, &[], &upstream_only).unwrap();
+        assert_eq!(res.unused.len(), 3);
+    }
+
+    #[test]
+    fn workspace_lists() {
+        let dir = tempfile::tempdir().unwrap();
+        let upstream = vec![dir.path().join("upstream")];
+        let ws = dir.path().join("ws");
+        write_package(&upstream[0], "a", &[]);
+        write_package(&upstream[0], "b", &[]);
+        write_package(&ws, "robot", &[("depend", "a")]);
+
+        assert_eq!(workspace_roots(&[], false), [PathBuf::from(".")]);
+        assert!(workspace_roots(&[], true).is_empty());
+
+        let list = format!("{}\n\n  {}  \n", ws.display(), ws.join(".").display());
+        let listed = read_path_list(list.as_bytes()).unwrap();
+        assert_eq!(listed.len(), 2);
+        assert_eq!(
+            analyze(&upstream, &listed, &[], &[]).unwrap(),
+            analyze(&upstream, &[ws.join(".")], &[], &[]).unwrap()
+        );
+    }
+}
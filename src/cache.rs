@@ -0,0 +1,349 @@
+//! Parsed manifests kept on disk between runs (`--cache`), so unchanged `package.xml` files
+//! aren't parsed again. Entries are keyed by the canonical manifest path and only used while
+//! its modification time is the same. Their dependencies are [sorted](Package::sorted), so the
+//! file doesn't depend on declaration order.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{Context, Result};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{filtering::Package, output::write_atomically, parsing::ParseOptions};
+
+/// The condition variables of REP 149, which change how manifests parse when not given
+/// explicitly.
+static CONDITION_VARS: [&str; 3] = ["ROS_VERSION", "ROS_DISTRO", "ROS_PYTHON_VERSION"];
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Entry {
+    modified: SystemTime,
+    /// How long parsing the manifest took, to estimate the time saved by reusing it
+    #[serde(default)]
+    parse_time: Duration,
+    package: Package,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct CacheFile {
+    /// What the entries were parsed with, see [`options_key`]
+    options: String,
+    entries: BTreeMap<PathBuf, Entry>,
+}
+
+/// The parse options a cached package depends on. Entries parsed with others are dropped.
+/// Along with how manifests are read, this includes what decides where packages are found.
+fn options_key(options: &ParseOptions) -> String {
+    let vars: BTreeMap<&str, Option<String>> = match &options.condition_vars {
+        Some(vars) => vars
+            .iter()
+            .map(|(k, v)| (k.as_str(), Some(v.clone())))
+            .collect(),
+        None => CONDITION_VARS
+            .iter()
+            .map(|&var| (var, std::env::var(var).ok()))
+            .collect(),
+    };
+    format!(
+        "{} {} {} {:?} {} {:?}",
+        options.lenient_structure,
+        options.manifest_subpath.display(),
+        options.max_manifest_bytes,
+        options.ignore_markers,
+        options.legacy_manifest,
+        vars
+    )
+}
+
+/// What the cache of one run was used for, reported by `--cache-stats`.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct CacheStats {
+    /// Packages taken from the cache
+    pub hits: usize,
+    /// Manifests that weren't in the cache
+    pub misses: usize,
+    /// Cached manifests that were modified since, and parsed again
+    pub modified: usize,
+    /// Entries dropped when loading, as they were parsed with other options
+    pub invalidated: usize,
+    /// How long parsing the hits took when they were cached, an estimate of the time saved
+    pub saved: Duration,
+}
+
+impl std::fmt::Display for CacheStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Cache: {} hits, {} misses, {} modified, {} invalidated by other options, \
+             ~{:.2} ms saved",
+            self.hits,
+            self.misses,
+            self.modified,
+            self.invalidated,
+            self.saved.as_secs_f64() * 1000.0
+        )
+    }
+}
+
+/// The cache of one run, shared by the threads of every search using it.
+#[derive(Debug, Default)]
+pub struct ManifestCache {
+    file: Mutex<CacheFile>,
+    stats: Mutex<CacheStats>,
+}
+
+impl ManifestCache {
+    /// The entries in `path` that were parsed with `options`, or none if it doesn't exist or
+    /// can't be read.
+    pub fn load(path: &Path, options: &ParseOptions) -> Result<ManifestCache> {
+        let key = options_key(options);
+        let mut stats = CacheStats::default();
+        let file = match fs::read_to_string(path) {
+            Ok(contents) => match serde_json::from_str::<CacheFile>(&contents) {
+                Ok(file) if file.options == key => file,
+                Ok(file) => {
+                    debug!("Dropping '{}', parsed with other options", path.display());
+                    stats.invalidated = file.entries.len();
+                    CacheFile::default()
+                }
+                Err(e) => {
+                    warn!("Ignoring unreadable cache '{}': {e}", path.display());
+                    CacheFile::default()
+                }
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => CacheFile::default(),
+            Err(e) => return Err(e).with_context(|| format!("While reading '{}'", path.display())),
+        };
+        Ok(ManifestCache {
+            file: Mutex::new(CacheFile {
+                options: key,
+                ..file
+            }),
+            stats: Mutex::new(stats),
+        })
+    }
+
+    /// The package parsed from `manifest` if it hasn't been modified since, at `pkg_root`.
+    pub fn get(&self, manifest: &Path, modified: SystemTime, pkg_root: &Path) -> Option<Package> {
+        let file = self.file.lock().unwrap();
+        let mut stats = self.stats.lock().unwrap();
+        let Some(entry) = file.entries.get(manifest) else {
+            stats.misses += 1;
+            return None;
+        };
+        if entry.modified != modified {
+            stats.modified += 1;
+            return None;
+        }
+        stats.hits += 1;
+        stats.saved += entry.parse_time;
+        Some(Package {
+            path: pkg_root.to_path_buf(),
+            ..entry.package.clone()
+        })
+    }
+
+    /// Keep `package`, which took `parse_time` to parse from `manifest`.
+    pub fn insert(
+        &self,
+        manifest: &Path,
+        modified: SystemTime,
+        package: &Package,
+        parse_time: Duration,
+    ) {
+        let entry = Entry {
+            modified,
+            parse_time,
+            package: package.clone(),
+        };
+        let mut file = self.file.lock().unwrap();
+        file.entries.insert(manifest.to_path_buf(), entry);
+    }
+
+    /// How many packages were taken from the cache.
+    pub fn hits(&self) -> usize {
+        self.stats.lock().unwrap().hits
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.stats.lock().unwrap().clone()
+    }
+
+    /// Write the entries to `path`, dropping those of manifests that no longer exist.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut file = self.file.lock().unwrap();
+        file.entries.retain(|manifest, _| manifest.exists());
+        write_atomically(path, &serde_json::to_string(&*file)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::File, sync::Arc, time::Duration};
+
+    use super::*;
+    use crate::parsing::find_with_options;
+
+    #[test]
+    fn reuses_unchanged_manifests() {
+        let dir = tempfile::tempdir().unwrap();
+        let ws = dir.path().join("ws");
+        let cache_file = dir.path().join("cache.json");
+        for name in ["a", "b"] {
+            fs::create_dir_all(ws.join(name)).unwrap();
+            let manifest = format!("<package><name>{name}</name><depend>c</depend></package>");
+            fs::write(ws.join(name).join("package.xml"), manifest).unwrap();
+        }
+        let run = || {
+            let mut options = ParseOptions::default();
+            let cache = Arc::new(ManifestCache::load(&cache_file, &options).unwrap());
+            options.cache = Some(cache.clone());
+            let names: Vec<String> = find_with_options(&ws, &options)
+                .unwrap()
+                .into_iter()
+                .map(|p| p.name)
+                .collect();
+            cache.save(&cache_file).unwrap();
+            (names, cache.hits())
+        };
+        assert_eq!(run(), (vec!["a".into(), "b".into()], 0));
+        assert_eq!(run(), (vec!["a".into(), "b".into()], 2));
+
+        let manifest = ws.join("b").join("package.xml");
+        fs::write(&manifest, "<package><name>renamed</name></package>").unwrap();
+        let later = SystemTime::now() + Duration::from_secs(10);
+        File::options()
+            .write(true)
+            .open(&manifest)
+            .unwrap()
+            .set_modified(later)
+            .unwrap();
+        assert_eq!(run(), (vec!["a".into(), "renamed".into()], 1));
+
+        fs::remove_dir_all(ws.join("a")).unwrap();
+        run();
+        let saved: CacheFile =
+            serde_json::from_str(&fs::read_to_string(&cache_file).unwrap()).unwrap();
+        assert_eq!(saved.entries.len(), 1);
+    }
+
+    #[test]
+    fn sorts_cached_deps() {
+        let dir = tempfile::tempdir().unwrap();
+        let ws = dir.path().join("ws");
+        let cache_file = dir.path().join("cache.json");
+        fs::create_dir_all(ws.join("a")).unwrap();
+        let manifest = "<package><name>a</name><test_depend>z</test_depend>\
+                        <depend>y</depend><build_depend>x</build_depend></package>";
+        fs::write(ws.join("a").join("package.xml"), manifest).unwrap();
+        let run = || {
+            let cache =
+                Arc::new(ManifestCache::load(&cache_file, &ParseOptions::default()).unwrap());
+            let options = ParseOptions {
+                cache: Some(cache.clone()),
+                ..Default::default()
+            };
+            let mut found = find_with_options(&ws, &options).unwrap();
+            cache.save(&cache_file).unwrap();
+            found.remove(0).deps
+        };
+        let (cold, warm) = (run(), run());
+        assert_eq!(cold, warm);
+
+        // Declaration order is kept without the cache
+        let uncached = find_with_options(&ws, &ParseOptions::default())
+            .unwrap()
+            .remove(0);
+        let names: Vec<&str> = uncached.deps.iter().map(|d| d.name.as_str()).collect();
+        assert_eq!(names, ["z", "y", "x"]);
+        assert_eq!(cold, uncached.sorted().deps);
+
+        let saved: CacheFile =
+            serde_json::from_str(&fs::read_to_string(&cache_file).unwrap()).unwrap();
+        let entry = saved.entries.values().next().unwrap();
+        assert_eq!(entry.package.deps, cold);
+    }
+
+    #[test]
+    fn counts_hits_and_misses() {
+        let dir = tempfile::tempdir().unwrap();
+        let ws = dir.path().join("ws");
+        let cache_file = dir.path().join("cache.json");
+        for name in ["a", "b", "c"] {
+            fs::create_dir_all(ws.join(name)).unwrap();
+            let manifest = format!("<package><name>{name}</name></package>");
+            fs::write(ws.join(name).join("package.xml"), manifest).unwrap();
+        }
+        let run = |options: ParseOptions| {
+            let cache = Arc::new(ManifestCache::load(&cache_file, &options).unwrap());
+            let options = ParseOptions {
+                cache: Some(cache.clone()),
+                ..options
+            };
+            find_with_options(&ws, &options).unwrap();
+            cache.save(&cache_file).unwrap();
+            cache.stats()
+        };
+        let cold = run(ParseOptions::default());
+        assert_eq!((cold.hits, cold.misses, cold.invalidated), (0, 3, 0));
+        let warm = run(ParseOptions::default());
+        assert_eq!((warm.hits, warm.misses, warm.modified), (3, 0, 0));
+
+        let lenient = run(ParseOptions {
+            lenient_structure: true,
+            ..Default::default()
+        });
+        assert_eq!((lenient.hits, lenient.misses), (0, 3));
+        assert_eq!(lenient.invalidated, 3);
+    }
+
+    #[test]
+    fn changed_limits_invalidate() {
+        let dir = tempfile::tempdir().unwrap();
+        let ws = dir.path().join("ws");
+        let cache_file = dir.path().join("cache.json");
+        fs::create_dir_all(ws.join("a")).unwrap();
+        fs::write(
+            ws.join("a").join("package.xml"),
+            "<package><name>a</name></package>",
+        )
+        .unwrap();
+        let run = |options: ParseOptions| {
+            let cache = Arc::new(ManifestCache::load(&cache_file, &options).unwrap());
+            let options = ParseOptions {
+                cache: Some(cache.clone()),
+                ..options
+            };
+            let found = find_with_options(&ws, &options);
+            cache.save(&cache_file).unwrap();
+            (found.map(|pkgs| pkgs.len()).ok(), cache.stats().invalidated)
+        };
+        assert_eq!(run(ParseOptions::default()), (Some(1), 0));
+        let small = || ParseOptions {
+            max_manifest_bytes: 8,
+            ..Default::default()
+        };
+        assert_eq!(run(small()), (None, 1));
+
+        let key = options_key(&ParseOptions::default());
+        for options in [
+            small(),
+            ParseOptions {
+                ignore_markers: vec!["SKIP".into()],
+                ..Default::default()
+            },
+            ParseOptions {
+                legacy_manifest: true,
+                ..Default::default()
+            },
+        ] {
+            assert_ne!(options_key(&options), key);
+        }
+    }
+}
@@ -0,0 +1,189 @@
+//! Evaluation of the `condition` attribute of package format 3 (REP 149), like
+//! `$ROS_VERSION == 2 and $ROS_DISTRO != foxy`.
+
+use anyhow::{anyhow, Result};
+
+#[derive(Debug, PartialEq)]
+enum Token<'a> {
+    Open,
+    Close,
+    Eq,
+    Ne,
+    And,
+    Or,
+    Variable(&'a str),
+    Literal(&'a str),
+}
+
+fn tokenize(expr: &str) -> Result<Vec<Token<'_>>> {
+    let mut res = Vec::new();
+    let mut rest = expr.trim_start();
+    while let Some(c) = rest.chars().next() {
+        let len = match c {
+            '(' => {
+                res.push(Token::Open);
+                1
+            }
+            ')' => {
+                res.push(Token::Close);
+                1
+            }
+            '=' | '!' => {
+                if !rest[1..].starts_with('=') {
+                    return Err(anyhow!("Expected '{c}=' in condition '{expr}'"));
+                }
+                res.push(if c == '=' { Token::Eq } else { Token::Ne });
+                2
+            }
+            '"' | '\'' => {
+                let end = rest[1..]
+                    .find(c)
+                    .ok_or_else(|| anyhow!("Unterminated string in condition '{expr}'"))?;
+                res.push(Token::Literal(&rest[1..end + 1]));
+                end + 2
+            }
+            _ => {
+                let len = rest
+                    .find(|c: char| c.is_whitespace() || "()=!\"'".contains(c))
+                    .unwrap_or(rest.len());
+                let word = &rest[..len];
+                res.push(match word {
+                    "and" => Token::And,
+                    "or" => Token::Or,
+                    _ => match word.strip_prefix('$') {
+                        Some("") => return Err(anyhow!("Empty variable in condition '{expr}'")),
+                        Some(var) => Token::Variable(var),
+                        None => Token::Literal(word),
+                    },
+                });
+                len
+            }
+        };
+        rest = rest[len..].trim_start();
+    }
+    Ok(res)
+}
+
+struct Parser<'a, 't, F> {
+    tokens: &'t [Token<'a>],
+    pos: usize,
+    lookup: &'t F,
+    expr: &'t str,
+}
+
+impl<'a, 't, F: Fn(&str) -> Option<String>> Parser<'a, 't, F> {
+    fn error(&self) -> anyhow::Error {
+        anyhow!("Invalid condition '{}'", self.expr)
+    }
+
+    fn next(&mut self) -> Option<&'t Token<'a>> {
+        let res = self.tokens.get(self.pos);
+        self.pos += 1;
+        res
+    }
+
+    fn or(&mut self) -> Result<bool> {
+        let mut res = self.and()?;
+        while self.tokens.get(self.pos) == Some(&Token::Or) {
+            self.pos += 1;
+            res |= self.and()?;
+        }
+        Ok(res)
+    }
+
+    fn and(&mut self) -> Result<bool> {
+        let mut res = self.atom()?;
+        while self.tokens.get(self.pos) == Some(&Token::And) {
+            self.pos += 1;
+            res &= self.atom()?;
+        }
+        Ok(res)
+    }
+
+    fn atom(&mut self) -> Result<bool> {
+        if self.tokens.get(self.pos) == Some(&Token::Open) {
+            self.pos += 1;
+            let res = self.or()?;
+            return match self.next() {
+                Some(Token::Close) => Ok(res),
+                _ => Err(self.error()),
+            };
+        }
+        let lhs = self.value()?;
+        let equal = match self.next() {
+            Some(Token::Eq) => true,
+            Some(Token::Ne) => false,
+            _ => return Err(self.error()),
+        };
+        let rhs = self.value()?;
+        Ok((lhs == rhs) == equal)
+    }
+
+    fn value(&mut self) -> Result<String> {
+        match self.next() {
+            Some(Token::Literal(s)) => Ok(s.to_string()),
+            // Unset variables are empty, as in ROS tooling
+            Some(Token::Variable(v)) => Ok((self.lookup)(v).unwrap_or_default()),
+            _ => Err(self.error()),
+        }
+    }
+}
+
+/// Evaluate the condition `expr`, looking up `$VARIABLES` with `lookup`.
+pub fn evaluate(expr: &str, lookup: &impl Fn(&str) -> Option<String>) -> Result<bool> {
+    let tokens = tokenize(expr)?;
+    let mut parser = Parser {
+        tokens: &tokens,
+        pos: 0,
+        lookup,
+        expr,
+    };
+    let res = parser.or()?;
+    if parser.pos != tokens.len() {
+        return Err(parser.error());
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ros2(var: &str) -> Option<String> {
+        match var {
+            "ROS_VERSION" => Some("2".into()),
+            "ROS_DISTRO" => Some("jazzy".into()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn evaluates_conditions() {
+        assert!(evaluate("$ROS_VERSION == 2", &ros2).unwrap());
+        assert!(!evaluate("$ROS_VERSION == 1", &ros2).unwrap());
+        assert!(evaluate("$ROS_VERSION != 1 and $ROS_DISTRO == 'jazzy'", &ros2).unwrap());
+        assert!(evaluate("$ROS_VERSION == 1 or ($ROS_DISTRO == jazzy)", &ros2).unwrap());
+        assert!(!evaluate(
+            "$ROS_VERSION == 1 or $ROS_DISTRO == humble and 2 == 2",
+            &ros2
+        )
+        .unwrap());
+        assert!(evaluate("$UNSET == ''", &ros2).unwrap());
+    }
+
+    #[test]
+    fn rejects_malformed_conditions() {
+        for expr in [
+            "",
+            "$ROS_VERSION",
+            "$ROS_VERSION = 2",
+            "$ROS_VERSION == 2 and",
+            "($ROS_VERSION == 2",
+            "$ROS_VERSION == 2)",
+            "$ == 2",
+            "'open == 2",
+        ] {
+            evaluate(expr, &ros2).expect_err(expr);
+        }
+    }
+}
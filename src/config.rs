@@ -0,0 +1,117 @@
+//! Defaults for command line options from a `.ws_cleaner.toml` file, e.g.
+//!
+//! ```toml
+//! dep-type = ["build", "exec"]
+//! action = "colcon-ignore"
+//! exclude = ["rviz2"]
+//! ignore-markers = ["COLCON_IGNORE"]
+//! ```
+//!
+//! Options given on the command line take precedence over the file, which takes precedence
+//! over the built-in defaults.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context, Result};
+use clap::ValueEnum;
+use serde::Deserialize;
+
+use crate::{actions::Action, filtering::DepType};
+
+/// The name of the config file searched for in the current directory and its parents.
+pub const CONFIG_FILE: &str = ".ws_cleaner.toml";
+
+/// The file as written, with the value names of the command line.
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct RawConfig {
+    dep_type: Vec<String>,
+    action: Option<String>,
+    exclude: Vec<String>,
+    ignore_markers: Vec<String>,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Config {
+    /// Like `--type`
+    pub dep_type: Vec<DepType>,
+    /// Like `--action`
+    pub action: Option<Action>,
+    /// Like `--exclude`
+    pub exclude: Vec<String>,
+    /// Like `--ignore-marker`
+    pub ignore_markers: Vec<String>,
+}
+
+fn value<T: ValueEnum>(key: &str, value: &str) -> Result<T> {
+    T::from_str(value, false).map_err(|_| anyhow!("Invalid {key} '{value}'"))
+}
+
+impl Config {
+    pub fn parse(contents: &str) -> Result<Config> {
+        let raw: RawConfig = toml::from_str(contents)?;
+        Ok(Config {
+            dep_type: raw
+                .dep_type
+                .iter()
+                .map(|t| value("dep-type", t))
+                .collect::<Result<_>>()?,
+            action: raw.action.map(|a| value("action", &a)).transpose()?,
+            exclude: raw.exclude,
+            ignore_markers: raw.ignore_markers,
+        })
+    }
+
+    pub fn load(path: &Path) -> Result<Config> {
+        let context = || format!("While reading '{}'", path.display());
+        Config::parse(&fs::read_to_string(path).with_context(context)?).with_context(context)
+    }
+
+    /// The nearest [`CONFIG_FILE`] in `dir` or its parents.
+    pub fn discover(dir: &Path) -> Option<PathBuf> {
+        dir.ancestors()
+            .map(|d| d.join(CONFIG_FILE))
+            .find(|f| f.is_file())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loads_configs() {
+        let config = Config::parse(
+            r#"
+            dep-type = ["build", "buildtool"]
+            action = "colcon-ignore"
+            exclude = ["rviz2"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config,
+            Config {
+                dep_type: vec![DepType::Build, DepType::BuildTool],
+                action: Some(Action::ColconIgnore),
+                exclude: vec!["rviz2".into()],
+                ignore_markers: Vec::new(),
+            }
+        );
+        assert_eq!(Config::parse("").unwrap(), Config::default());
+        assert!(Config::parse("dep-type = [\"nope\"]").is_err());
+        assert!(Config::parse("unknown = 1").is_err());
+
+        let dir = tempfile::tempdir().unwrap();
+        let nested = dir.path().join("ws").join("src");
+        fs::create_dir_all(&nested).unwrap();
+        fs::write(dir.path().join("ws").join(CONFIG_FILE), "").unwrap();
+        assert_eq!(
+            Config::discover(&nested),
+            Some(dir.path().join("ws").join(CONFIG_FILE))
+        );
+    }
+}
@@ -1,37 +1,158 @@
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
     fmt::Display,
-    path::PathBuf,
+    path::{Path, PathBuf},
+    str::FromStr,
 };
 
+use anyhow::{anyhow, Result};
 use clap::ValueEnum;
+use glob::Pattern;
+use serde::{Deserialize, Serialize};
 
-#[derive(ValueEnum, PartialOrd, PartialEq, Eq, Ord, Clone, Default, Debug)]
+use crate::versions::VersionConstraint;
+
+#[derive(
+    ValueEnum, PartialOrd, PartialEq, Eq, Ord, Clone, Default, Debug, Serialize, Deserialize,
+)]
 pub enum DepType {
     #[default]
     All,
     Build,
     Exec,
     Test,
+    /// `build_export_depend`, e.g. headers needed by dependents
+    BuildExport,
+    /// `buildtool_depend`, e.g. `ament_cmake`
+    #[value(name = "buildtool")]
+    BuildTool,
+    /// `doc_depend`, only needed to build documentation
+    Doc,
 }
 
 impl DepType {
     pub fn matches(&self, b: &DepType) -> bool {
         *self == DepType::All || *b == DepType::All || *self == *b
     }
+
+    /// Every dependency type, in declaration order.
+    pub fn all_variants() -> &'static [DepType] {
+        <DepType as ValueEnum>::value_variants()
+    }
+}
+
+impl FromStr for DepType {
+    type Err = anyhow::Error;
+
+    /// Either a `--type` name like `build`, or the `package.xml` tag it is read from, like
+    /// `build_depend`, ignoring case.
+    fn from_str(s: &str) -> Result<Self> {
+        let lower = s.to_lowercase();
+        let name = match lower.as_str() {
+            "depend" => "all",
+            "build_depend" => "build",
+            "exec_depend" | "run_depend" => "exec",
+            "test_depend" => "test",
+            "build_export_depend" | "buildtool_export_depend" => "build-export",
+            "buildtool_depend" => "buildtool",
+            "doc_depend" => "doc",
+            other => other,
+        };
+        <DepType as ValueEnum>::from_str(name, true)
+            .map_err(|_| anyhow!("Unknown dependency type '{s}'"))
+    }
 }
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub struct Dependency {
     pub name: String,
     pub dep_type: DepType,
 }
 
-#[derive(Debug, PartialEq, Clone)]
+/// A `<maintainer>` of a package.
+#[derive(Debug, PartialEq, Clone, Default, Serialize, Deserialize)]
+pub struct Maintainer {
+    pub name: String,
+    pub email: Option<String>,
+}
+
+impl Display for Maintainer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.email {
+            Some(email) => write!(f, "{} <{email}>", self.name),
+            None => write!(f, "{}", self.name),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Default, Serialize, Deserialize)]
 pub struct Package {
     pub name: String,
     pub path: PathBuf,
     pub deps: Vec<Dependency>,
+    pub version: Option<String>,
+    /// Packages this one is a drop-in replacement for
+    pub replaces: Vec<String>,
+    /// Packages that can't be installed alongside this one
+    pub conflicts: Vec<String>,
+    /// Directives given in `<!-- ws-cleaner: ... -->` comments
+    pub directives: Vec<String>,
+    /// Number of dependency tags without a package name
+    pub empty_deps: usize,
+    /// Requirements from `version_*` attributes of dependency tags
+    pub version_constraints: Vec<VersionConstraint>,
+    /// The `<build_type>` exported by the package, e.g. `ament_cmake`
+    pub build_type: Option<String>,
+    /// Dependency groups this package is a `<member_of_group>` of
+    pub groups: Vec<String>,
+    /// Groups from `<group_depend>`, standing for all their members
+    pub group_deps: Vec<String>,
+    pub maintainers: Vec<Maintainer>,
+}
+
+impl Package {
+    /// The dependencies in declaration order.
+    ///
+    /// ```no_run
+    /// # fn main() -> anyhow::Result<()> {
+    /// for package in ws_cleaner::parsing::find(std::path::Path::new("upstream_ws"))? {
+    ///     for dep in package.deps() {
+    ///         println!("{} -> {} ({:?})", package.name, dep.name(), dep.dep_type());
+    ///     }
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn deps(&self) -> &[Dependency] {
+        &self.deps
+    }
+
+    /// The `<version>`, if given.
+    pub fn version(&self) -> Option<&str> {
+        self.version.as_deref()
+    }
+
+    pub fn maintainers(&self) -> &[Maintainer] {
+        &self.maintainers
+    }
+
+    pub fn has_directive(&self, directive: &str) -> bool {
+        self.directives.iter().any(|d| d == directive)
+    }
+
+    /// Whether empty dependency tags should be reported, unless allowed by `allow-empty-dep`.
+    pub fn warn_empty_deps(&self) -> bool {
+        self.empty_deps > 0 && !self.has_directive("allow-empty-dep")
+    }
+
+    /// This package with its dependencies sorted by type and name rather than in declaration
+    /// order, for stable output.
+    pub fn sorted(&self) -> Package {
+        let mut res = self.clone();
+        res.deps
+            .sort_by(|a, b| a.dep_type.cmp(&b.dep_type).then(a.name.cmp(&b.name)));
+        res
+    }
 }
 
 impl Display for Package {
@@ -43,6 +164,14 @@ impl Display for Package {
 pub type DepFilter = dyn Fn(&Dependency) -> bool;
 
 impl Dependency {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn dep_type(&self) -> &DepType {
+        &self.dep_type
+    }
+
     pub fn all(_candidate: &Dependency) -> bool {
         true
     }
@@ -51,19 +180,404 @@ impl Dependency {
         candidate.dep_type.matches(&DepType::Build)
     }
 
+    /// Edges needed at runtime: `exec_depend`, `build_export_depend` and `depend`.
+    pub fn runtime(candidate: &Dependency) -> bool {
+        candidate.dep_type.matches(&DepType::Exec)
+            || candidate.dep_type.matches(&DepType::BuildExport)
+    }
+
     pub fn matcher(mut types: Vec<DepType>) -> impl Fn(&Dependency) -> bool {
         types.sort();
         types.dedup();
         move |candidate: &Dependency| types.iter().any(|t| t.matches(&candidate.dep_type))
     }
+
+    /// Like [`Dependency::matcher`] for `include`, or all types if empty, minus the `exclude`
+    /// types. These are compared exactly, so excluding `test` still follows `<depend>`.
+    pub fn matcher_excluding(
+        include: Vec<DepType>,
+        exclude: Vec<DepType>,
+    ) -> impl Fn(&Dependency) -> bool {
+        let included = (!include.is_empty()).then(|| Dependency::matcher(include));
+        move |candidate: &Dependency| {
+            !exclude.contains(&candidate.dep_type)
+                && included.as_ref().is_none_or(|matches| matches(candidate))
+        }
+    }
+}
+
+/// The names of the `upstream` members of each dependency group.
+fn group_members(upstream: &[Package]) -> HashMap<&str, Vec<&str>> {
+    let mut res = HashMap::<&str, Vec<&str>>::new();
+    for p in upstream {
+        for group in p.groups.iter() {
+            res.entry(group).or_default().push(&p.name);
+        }
+    }
+    res
+}
+
+/// Remove `pkg` and everything it transitively depends on from `unused`, recording in
+/// `parents` which package pulled in each removed one (`parent` for `pkg`). Packages are only
+/// expanded when first removed, so cycles terminate, and a worklist rather than recursion keeps
+/// long dependency chains from overflowing the stack.
+///
+/// `pkg` is at depth 0, and the dependencies of packages at `max_depth` are not followed.
+/// `depths` holds the lowest depth each removed package was reached at, so one first reached
+/// on a long path is expanded again when a shorter one turns up.
+fn remove_recursively<'a>(
+    unused: &mut HashMap<&'a str, &'a Package>,
+    members: &HashMap<&'a str, Vec<&'a str>>,
+    parents: &mut HashMap<&'a str, &'a str>,
+    depths: &mut HashMap<&'a str, (&'a Package, usize)>,
+    (pkg, parent): (&'a str, &'a str),
+    filter: &DepFilter,
+    max_depth: Option<usize>,
+) {
+    let mut pending = vec![(pkg, parent, 0)];
+    while let Some((name, parent, depth)) = pending.pop() {
+        let v = if let Some(v) = unused.remove(name) {
+            parents.insert(name, parent);
+            v
+        } else {
+            match depths.get(name) {
+                Some(&(v, seen)) if max_depth.is_some() && depth < seen => v,
+                _ => continue,
+            }
+        };
+        depths.insert(name, (v, depth));
+        if max_depth.is_some_and(|max| depth >= max) {
+            continue;
+        }
+        let by = v.name.as_str();
+        pending.extend(
+            v.deps
+                .iter()
+                .filter(|x| filter(x))
+                .map(|x| (x.name.as_str(), by, depth + 1)),
+        );
+        for group in v.group_deps.iter() {
+            let names = members.get(group.as_str()).into_iter().flatten();
+            pending.extend(names.map(|&n| (n, by, depth + 1)));
+        }
+    }
+}
+
+/// The path of `package` with symlinks resolved, or as is if that fails.
+fn canonical_path(package: &Package) -> PathBuf {
+    package
+        .path
+        .canonicalize()
+        .unwrap_or_else(|_| package.path.clone())
+}
+
+/// Sort `packages` by name and path and drop repeated entries for the same package, i.e.
+/// the same name at the same canonical path, regardless of their dependencies.
+pub fn dedup_packages(packages: &mut Vec<Package>) {
+    packages.sort_by_cached_key(|p| (p.name.clone(), canonical_path(p)));
+    packages.dedup_by(|a, b| a.name == b.name && canonical_path(a) == canonical_path(b));
+}
+
+/// Package names found at more than one path, with all of those paths, sorted by name.
+pub fn duplicate_names(packages: &[Package]) -> Vec<(&str, Vec<&Path>)> {
+    let mut by_name = BTreeMap::<&str, Vec<&Path>>::new();
+    for p in packages {
+        by_name.entry(&p.name).or_default().push(&p.path);
+    }
+    by_name
+        .into_iter()
+        .filter_map(|(name, mut paths)| {
+            paths.sort();
+            paths.dedup();
+            (paths.len() > 1).then_some((name, paths))
+        })
+        .collect()
+}
+
+/// What [`merge_packages`] does when a package name shows up at different paths.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Default)]
+pub enum MergeStrategy {
+    /// Keep the package from the earliest list
+    First,
+    /// Keep the package from the latest list
+    Last,
+    /// Fail the merge
+    Error,
+    /// Keep all of them
+    #[default]
+    KeepAll,
+}
+
+/// Merge package lists from several sources, sorted by name and path.
+///
+/// Packages with the same name and canonical path are only kept once; name clashes at
+/// different paths are resolved with `strategy`.
+pub fn merge_packages(lists: &[Vec<Package>], strategy: MergeStrategy) -> Result<Vec<Package>> {
+    let mut all = lists.concat();
+    if strategy == MergeStrategy::Last {
+        all.reverse();
+    }
+    let mut seen = HashMap::<String, Vec<PathBuf>>::new();
+    let mut res = Vec::new();
+    for p in all {
+        let path = canonical_path(&p);
+        let paths = seen.entry(p.name.clone()).or_default();
+        if paths.contains(&path) {
+            continue;
+        }
+        if let Some(existing) = paths.first() {
+            match strategy {
+                MergeStrategy::First | MergeStrategy::Last => continue,
+                MergeStrategy::Error => {
+                    return Err(anyhow!(
+                        "Package '{}' found at both '{}' and '{}'",
+                        p.name,
+                        existing.display(),
+                        path.display()
+                    ))
+                }
+                MergeStrategy::KeepAll => {}
+            }
+        }
+        paths.push(path);
+        res.push(p);
+    }
+    res.sort_by_cached_key(|p| (p.name.clone(), canonical_path(p)));
+    Ok(res)
+}
+
+/// Add `assumed` dependencies (package name and its extra dependencies) to `packages`.
+pub fn assume_deps(packages: &mut [Package], assumed: &[(String, Vec<String>)]) {
+    for (name, deps) in assumed {
+        for p in packages.iter_mut().filter(|p| p.name == *name) {
+            p.deps.extend(deps.iter().map(|dep| Dependency {
+                name: dep.clone(),
+                dep_type: DepType::All,
+            }));
+        }
+    }
+}
+
+/// Where the dependency edges of packages come from.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Default)]
+pub enum DepsSource {
+    /// The `package.xml` manifests
+    #[default]
+    Manifest,
+    /// A secondary source such as the ament index of an install space
+    Index,
+    /// The union of both
+    Both,
+}
+
+/// Combine the manifest dependencies of `packages` with those from a secondary source
+/// (package name and its dependencies of type `dep_type`) according to `source`.
+pub fn apply_deps_source(
+    packages: &mut [Package],
+    source: DepsSource,
+    secondary: &[(String, Vec<String>)],
+    dep_type: DepType,
+) {
+    if source == DepsSource::Manifest {
+        return;
+    }
+    for p in packages.iter_mut() {
+        if source == DepsSource::Index {
+            p.deps.clear();
+        }
+        for (_, deps) in secondary.iter().filter(|(name, _)| *name == p.name) {
+            for dep in deps {
+                let new = Dependency {
+                    name: dep.clone(),
+                    dep_type: dep_type.clone(),
+                };
+                if !p.deps.contains(&new) {
+                    p.deps.push(new);
+                }
+            }
+        }
+    }
+}
+
+/// The `expected` package names missing from `discovered`, and the discovered packages
+/// that aren't expected.
+pub fn distribution_diff<'a>(
+    expected: &[String],
+    discovered: &'a [Package],
+) -> (Vec<String>, Vec<&'a Package>) {
+    let missing = expected
+        .iter()
+        .filter(|name| !discovered.iter().any(|p| p.name == **name))
+        .cloned()
+        .collect();
+    let extra = discovered
+        .iter()
+        .filter(|p| !expected.contains(&p.name))
+        .collect();
+    (missing, extra)
+}
+
+/// Make every dependency on a replaced package also depend on the packages in `providers`
+/// that declare they `<replace>` it, so the replacements are kept.
+pub fn resolve_replacements(packages: &mut [Package], providers: &[Package]) {
+    let mut replacements = HashMap::<&str, Vec<&str>>::new();
+    for p in providers {
+        for replaced in p.replaces.iter() {
+            replacements.entry(replaced).or_default().push(&p.name);
+        }
+    }
+    for p in packages.iter_mut() {
+        let extra: Vec<Dependency> = p
+            .deps
+            .iter()
+            .flat_map(|dep| {
+                replacements
+                    .get(dep.name.as_str())
+                    .into_iter()
+                    .flatten()
+                    .map(|&name| Dependency {
+                        name: name.to_string(),
+                        dep_type: dep.dep_type.clone(),
+                    })
+            })
+            .collect();
+        p.deps.extend(extra);
+    }
+}
+
+/// Rename dependencies of `packages` that only differ in case from a `known` package name to
+/// that name, e.g. `Boost` to `boost`, so they match regardless of case.
+pub fn fold_name_case(packages: &mut [Package], known: &[Package]) {
+    let names: HashSet<&str> = known.iter().map(|p| p.name.as_str()).collect();
+    let by_folded: HashMap<String, &str> = known
+        .iter()
+        .map(|p| (p.name.to_lowercase(), p.name.as_str()))
+        .collect();
+    for dep in packages.iter_mut().flat_map(|p| p.deps.iter_mut()) {
+        if names.contains(dep.name.as_str()) {
+            continue;
+        }
+        if let Some(&name) = by_folded.get(&dep.name.to_lowercase()) {
+            dep.name = name.to_string();
+        }
+    }
+}
+
+/// `packages` listing themselves as a dependency, a mistake in their manifest.
+pub fn self_dependent(packages: &[Package]) -> Vec<&Package> {
+    packages
+        .iter()
+        .filter(|p| p.deps.iter().any(|d| d.name == p.name))
+        .collect()
+}
+
+/// Pairs of `packages` where the first declares a `<conflict>` with the second.
+pub fn conflicting_pkgs(packages: &[Package]) -> Vec<(&Package, &Package)> {
+    packages
+        .iter()
+        .flat_map(|p| {
+            packages
+                .iter()
+                .filter(|other| p.conflicts.contains(&other.name))
+                .map(move |other| (p, other))
+        })
+        .collect()
 }
 
-fn remove_recursively(unused: &mut HashMap<&str, &Package>, pkg: &str, filter: &DepFilter) {
-    if let Some(v) = unused.remove(pkg) {
-        for p in v.deps.iter().filter(|x| filter(x)) {
-            remove_recursively(unused, &p.name, filter);
+/// Splits `packages` into those not matching any of `patterns` and those that do.
+pub fn split_matching(
+    packages: Vec<Package>,
+    patterns: &[Pattern],
+) -> (Vec<Package>, Vec<Package>) {
+    packages
+        .into_iter()
+        .partition(|p| !patterns.iter().any(|pattern| pattern.matches(&p.name)))
+}
+
+/// All upstream packages reachable from `root` through dependencies passing `filter`.
+pub fn reachable<'a>(
+    root: &Package,
+    upstream: &'a [Package],
+    filter: &DepFilter,
+) -> Vec<&'a Package> {
+    let by_name: HashMap<&str, &Package> = upstream.iter().map(|p| (p.name.as_str(), p)).collect();
+    let mut seen = HashSet::<&str>::new();
+    let mut res = Vec::new();
+    let mut pending: Vec<&str> = root
+        .deps
+        .iter()
+        .filter(|x| filter(x))
+        .map(|x| x.name.as_str())
+        .collect();
+    while let Some(name) = pending.pop() {
+        if !seen.insert(name) {
+            continue;
+        }
+        if let Some(&p) = by_name.get(name) {
+            res.push(p);
+            pending.extend(p.deps.iter().filter(|x| filter(x)).map(|x| x.name.as_str()));
         }
     }
+    res
+}
+
+/// Every package among `packages` that depends on `target` through dependencies passing
+/// `filter`, each as its shortest chain of dependencies ending in `target`. Sorted by chain
+/// length, then name.
+pub fn reverse_deps<'a>(
+    packages: &'a [Package],
+    target: &'a str,
+    filter: &DepFilter,
+) -> Vec<Vec<&'a str>> {
+    let mut dependents = HashMap::<&str, Vec<&str>>::new();
+    for p in packages {
+        for dep in p.deps.iter().filter(|x| filter(x)) {
+            dependents.entry(&dep.name).or_default().push(&p.name);
+        }
+    }
+    // Breadth first, so each package is reached by a shortest chain first
+    let mut next = HashMap::<&str, &str>::new();
+    let mut queue = VecDeque::from([target]);
+    let mut res = Vec::new();
+    while let Some(name) = queue.pop_front() {
+        let mut direct = dependents.get(name).cloned().unwrap_or_default();
+        direct.sort_unstable();
+        for d in direct {
+            if d == target || next.contains_key(d) {
+                continue;
+            }
+            next.insert(d, name);
+            queue.push_back(d);
+            let mut chain = vec![d];
+            while let Some(&n) = next.get(chain[chain.len() - 1]) {
+                chain.push(n);
+            }
+            res.push(chain);
+        }
+    }
+    res.sort_by(|a, b| (a.len(), &a[0]).cmp(&(b.len(), &b[0])));
+    res
+}
+
+/// Roots whose transitive closure under `filter` contains more than `max` packages.
+pub fn oversized_closures<'a>(
+    roots: &'a [Package],
+    upstream: &[Package],
+    filter: &DepFilter,
+    max: usize,
+) -> Vec<(&'a Package, usize)> {
+    roots
+        .iter()
+        .map(|root| (root, reachable(root, upstream, filter).len()))
+        .filter(|(_, size)| *size > max)
+        .collect()
+}
+
+/// Splits `packages` into those outside of all `dirs` and those inside any of them.
+pub fn split_under(packages: Vec<Package>, dirs: &[PathBuf]) -> (Vec<Package>, Vec<Package>) {
+    packages
+        .into_iter()
+        .partition(|p| !dirs.iter().any(|dir| p.path.starts_with(dir)))
 }
 
 pub fn find_unused_pkgs(
@@ -71,30 +585,358 @@ pub fn find_unused_pkgs(
     upstream: &[Package],
     filter: &DepFilter,
 ) -> Vec<Package> {
-    let mut used = HashSet::<&str>::new();
+    find_unused_pkgs_staged(build_space, upstream, filter, filter)
+}
+
+/// Like [`find_unused_pkgs`], but only the direct dependencies of the build space are
+/// selected by `filter`. Dependencies of upstream packages are followed if they pass
+/// `transitive_filter`, e.g. [`Dependency::runtime`] to model a runtime closure.
+pub fn find_unused_pkgs_staged(
+    build_space: &[Package],
+    upstream: &[Package],
+    filter: &DepFilter,
+    transitive_filter: &DepFilter,
+) -> Vec<Package> {
+    find_unused_pkgs_within(build_space, upstream, filter, transitive_filter, None)
+}
+
+/// Like [`find_unused_pkgs_staged`], but only keeping dependencies up to `max_depth` levels
+/// below the direct dependencies of the build space, which are at level 0. Deeper ones are
+/// unused unless also reachable on a shorter path.
+pub fn find_unused_pkgs_within(
+    build_space: &[Package],
+    upstream: &[Package],
+    filter: &DepFilter,
+    transitive_filter: &DepFilter,
+    max_depth: Option<usize>,
+) -> Vec<Package> {
+    let (unused, _) = walk_used(build_space, upstream, filter, transitive_filter, max_depth);
+    unused
+        .values()
+        .map(|&x| x.clone())
+        .collect::<Vec<Package>>()
+}
+
+/// The unused upstream packages by name, and for every used one the package that pulled it in.
+fn walk_used<'a>(
+    build_space: &'a [Package],
+    upstream: &'a [Package],
+    filter: &DepFilter,
+    transitive_filter: &DepFilter,
+    max_depth: Option<usize>,
+) -> (HashMap<&'a str, &'a Package>, HashMap<&'a str, &'a str>) {
+    let members = group_members(upstream);
+    // Dependencies with the build space package depending on them, in declaration order
+    let mut used = Vec::<(&str, &str)>::new();
     for p in build_space {
         for dep in p.deps.iter().filter(|x| filter(x)) {
-            used.insert(&dep.name);
+            used.push((&dep.name, &p.name));
+        }
+        // Group dependencies aren't typed and are followed under any filter
+        for group in p.group_deps.iter() {
+            let names = members.get(group.as_str()).into_iter().flatten();
+            used.extend(names.map(|&n| (n, p.name.as_str())));
         }
     }
 
     let mut unused = HashMap::<&str, &Package>::new();
 
+    // Necessary in case the workspaces overlap. The same package may have been parsed
+    // differently, e.g. with other options, so compare where it is rather than its contents
+    let in_build_space: HashSet<(&str, PathBuf)> = build_space
+        .iter()
+        .map(|p| (p.name.as_str(), canonical_path(p)))
+        .collect();
     for p in upstream {
-        // necessary in case the workspaces overlap.
-        if !build_space.contains(p) {
+        if !in_build_space.contains(&(p.name.as_str(), canonical_path(p))) {
             unused.insert(&p.name, p);
         }
     }
 
+    let mut parents = HashMap::new();
+    let mut depths = HashMap::new();
     for &p in used.iter() {
-        remove_recursively(&mut unused, p, filter);
+        remove_recursively(
+            &mut unused,
+            &members,
+            &mut parents,
+            &mut depths,
+            p,
+            transitive_filter,
+            max_depth,
+        );
     }
+    (unused, parents)
+}
 
+/// Why each kept upstream package is kept: the chain of dependents leading from it to a
+/// build space package, e.g. `["boost_sml", "nav2_core", "my_robot_bringup"]`. Sorted by
+/// the kept package. `max_depth` is as for [`find_unused_pkgs_within`].
+pub fn kept_chains<'a>(
+    build_space: &'a [Package],
+    upstream: &'a [Package],
+    filter: &DepFilter,
+    transitive_filter: &DepFilter,
+    max_depth: Option<usize>,
+) -> Vec<Vec<&'a str>> {
+    let (_, parents) = walk_used(build_space, upstream, filter, transitive_filter, max_depth);
+    let mut res: Vec<Vec<&str>> = parents
+        .keys()
+        .map(|&kept| {
+            let mut chain = vec![kept];
+            while let Some(&parent) = parents.get(chain[chain.len() - 1]) {
+                chain.push(parent);
+                // A build space package sharing its name with a kept one
+                if chain.len() > parents.len() {
+                    break;
+                }
+            }
+            chain
+        })
+        .collect();
+    res.sort();
+    res
+}
+
+#[derive(ValueEnum, Clone, Debug, PartialEq, Default)]
+pub enum UnusedMode {
+    /// Unused by all workspaces together
+    #[default]
+    Union,
+    /// Additionally report what each workspace doesn't use
+    PerWorkspace,
+}
+
+/// The `roots` under each of the `workspaces`, along with those under none of them (e.g.
+/// given by `--package`) which are shared by all. Without workspaces the roots form a single
+/// group, so the unused packages per group are the same as for all of them together.
+pub fn workspace_groups(roots: &[Package], workspaces: &[PathBuf]) -> Vec<Vec<Package>> {
+    if workspaces.is_empty() {
+        return vec![roots.to_vec()];
+    }
+    let in_workspace = |p: &Package| workspaces.iter().any(|w| p.path.starts_with(w));
+    let shared: Vec<Package> = roots.iter().filter(|p| !in_workspace(p)).cloned().collect();
+    workspaces
+        .iter()
+        .map(|w| {
+            let mut group = shared.clone();
+            group.extend(roots.iter().filter(|p| p.path.starts_with(w)).cloned());
+            group
+        })
+        .collect()
+}
+
+/// The unused packages for each of the `workspaces` separately, and those unused by all
+/// of them. The filters are used as in [`find_unused_pkgs_within`].
+pub fn find_unused_per_workspace(
+    workspaces: &[Vec<Package>],
+    upstream: &[Package],
+    filter: &DepFilter,
+    transitive_filter: &DepFilter,
+    max_depth: Option<usize>,
+) -> (Vec<Vec<Package>>, Vec<Package>) {
+    let per_workspace: Vec<Vec<Package>> = workspaces
+        .iter()
+        .map(|ws| find_unused_pkgs_within(ws, upstream, filter, transitive_filter, max_depth))
+        .collect();
+    let intersection = match per_workspace.split_first() {
+        Some((first, rest)) => first
+            .iter()
+            .filter(|p| rest.iter().all(|unused| unused.contains(p)))
+            .cloned()
+            .collect(),
+        None => Vec::new(),
+    };
+    (per_workspace, intersection)
+}
+
+/// The packages unused by any of the `workspaces`, i.e. keeping only those needed by all of
+/// them, sorted by name and path. The filters are used as in [`find_unused_pkgs_within`].
+pub fn find_unused_by_any(
+    workspaces: &[Vec<Package>],
+    upstream: &[Package],
+    filter: &DepFilter,
+    transitive_filter: &DepFilter,
+    max_depth: Option<usize>,
+) -> Vec<Package> {
+    let (per_workspace, _) =
+        find_unused_per_workspace(workspaces, upstream, filter, transitive_filter, max_depth);
+    let mut res = per_workspace.concat();
+    dedup_packages(&mut res);
+    res
+}
+
+/// Upstream packages with dependencies that only resolve to workspace packages, along with
+/// those dependencies. Upstream shouldn't depend on downstream.
+pub fn layering_violations<'a>(
+    build_space: &[Package],
+    upstream: &'a [Package],
+    filter: &DepFilter,
+) -> Vec<(&'a Package, Vec<&'a str>)> {
+    let ws_names: HashSet<&str> = build_space.iter().map(|p| p.name.as_str()).collect();
+    let us_names: HashSet<&str> = upstream.iter().map(|p| p.name.as_str()).collect();
+    upstream
+        .iter()
+        .filter_map(|p| {
+            let offending: Vec<&str> = p
+                .deps
+                .iter()
+                .filter(|x| filter(x))
+                .map(|x| x.name.as_str())
+                .filter(|name| ws_names.contains(name) && !us_names.contains(name))
+                .collect();
+            (!offending.is_empty()).then_some((p, offending))
+        })
+        .collect()
+}
+
+/// Packages among `kept` with dependencies passing `filter` that are none of the `known`
+/// packages and match none of the `allowed` patterns, e.g. typos or system dependencies,
+/// along with those dependencies.
+pub fn missing_deps<'a>(
+    kept: &'a [Package],
+    known: &[Package],
+    filter: &DepFilter,
+    allowed: &[Pattern],
+) -> Vec<(&'a Package, Vec<&'a str>)> {
+    let names: HashSet<&str> = known.iter().map(|p| p.name.as_str()).collect();
+    kept.iter()
+        .filter_map(|p| {
+            let mut missing: Vec<&str> = p
+                .deps
+                .iter()
+                .filter(|x| filter(x))
+                .map(|x| x.name.as_str())
+                .filter(|name| !names.contains(name))
+                .filter(|name| !allowed.iter().any(|a| a.matches(name)))
+                .collect();
+            missing.sort_unstable();
+            missing.dedup();
+            (!missing.is_empty()).then_some((p, missing))
+        })
+        .collect()
+}
+
+/// The namespace of a package name: everything before the first `separator`, if any.
+pub fn namespace<'a>(name: &'a str, separator: &str) -> Option<&'a str> {
+    name.split_once(separator)
+        .map(|(prefix, _)| prefix)
+        .filter(|prefix| !prefix.is_empty())
+}
+
+/// Upstream packages sharing a [`namespace`] with any build space package.
+pub fn sibling_namespace_pkgs<'a>(
+    build_space: &[Package],
+    upstream: &'a [Package],
+    separator: &str,
+) -> Vec<&'a Package> {
+    let namespaces: HashSet<&str> = build_space
+        .iter()
+        .filter_map(|p| namespace(&p.name, separator))
+        .collect();
+    upstream
+        .iter()
+        .filter(|p| namespace(&p.name, separator).is_some_and(|ns| namespaces.contains(ns)))
+        .collect()
+}
+
+/// The `upstream` packages with one of the exact `names`, and the names that match none.
+pub fn named_pkgs<'a>(
+    upstream: &'a [Package],
+    names: &'a [String],
+) -> (Vec<&'a Package>, Vec<&'a str>) {
+    let found = upstream
+        .iter()
+        .filter(|p| names.contains(&p.name))
+        .collect();
+    let missing = names
+        .iter()
+        .filter(|n| !upstream.iter().any(|p| &p.name == *n))
+        .map(|n| n.as_str())
+        .collect();
+    (found, missing)
+}
+
+/// The packages whose dependencies are kept: the `workspace`, and the `upstream` packages
+/// whose manifest opts out of cleanup with the `ignore` directive.
+pub fn cleanup_roots(workspace: &[Package], upstream: &[Package]) -> Vec<Package> {
+    let mut roots = workspace.to_vec();
+    roots.extend(
+        upstream
+            .iter()
+            .filter(|p| p.has_directive("ignore"))
+            .cloned(),
+    );
+    roots
+}
+
+/// The upstream packages that are still needed, i.e. all of `upstream` not in `unused`.
+pub fn kept_pkgs(upstream: &[Package], unused: &[Package]) -> Vec<Package> {
+    upstream
+        .iter()
+        .filter(|p| !unused.contains(p))
+        .cloned()
+        .collect()
+}
+
+/// The upstream packages the build space needs, i.e. [`kept_pkgs`] without those that are
+/// also part of the build space.
+pub fn used_pkgs(
+    build_space: &[Package],
+    upstream: &[Package],
+    unused: &[Package],
+) -> Vec<Package> {
+    let in_build_space: HashSet<(&str, PathBuf)> = build_space
+        .iter()
+        .map(|p| (p.name.as_str(), canonical_path(p)))
+        .collect();
+    kept_pkgs(upstream, unused)
+        .into_iter()
+        .filter(|p| !in_build_space.contains(&(p.name.as_str(), canonical_path(p))))
+        .collect()
+}
+
+/// Why removing an unused package is safe: which upstream packages depend on it.
+#[derive(Debug, PartialEq)]
+pub struct RemovalSafety<'a> {
+    pub package: &'a Package,
+    /// Upstream packages directly depending on `package` under the filter
+    pub dependents: Vec<&'a Package>,
+    /// The `dependents` that are kept and would be orphaned by the removal
+    pub kept_dependents: Vec<&'a Package>,
+}
+
+impl RemovalSafety<'_> {
+    pub fn is_safe(&self) -> bool {
+        self.kept_dependents.is_empty()
+    }
+}
+
+/// The [`RemovalSafety`] of each package in `unused`.
+pub fn removal_safety<'a>(
+    upstream: &'a [Package],
+    unused: &'a [Package],
+    filter: &DepFilter,
+) -> Vec<RemovalSafety<'a>> {
     unused
-        .values()
-        .map(|&x| x.clone())
-        .collect::<Vec<Package>>()
+        .iter()
+        .map(|package| {
+            let dependents: Vec<&Package> = upstream
+                .iter()
+                .filter(|p| p.deps.iter().any(|d| filter(d) && d.name == package.name))
+                .collect();
+            let kept_dependents = dependents
+                .iter()
+                .filter(|p| !unused.contains(p))
+                .copied()
+                .collect();
+            RemovalSafety {
+                package,
+                dependents,
+                kept_dependents,
+            }
+        })
+        .collect()
 }
 
 #[cfg(test)]
@@ -112,6 +954,7 @@ mod tests {
                     dep_type: DepType::All,
                 })
                 .collect(),
+            ..Default::default()
         }
     }
 
@@ -146,6 +989,7 @@ mod tests {
                     dep_type: DepType::All,
                 },
             ],
+            ..Default::default()
         }];
         let a = Package {
             name: "a".to_string(),
@@ -154,16 +998,44 @@ mod tests {
                 name: "b".into(),
                 dep_type: DepType::Build,
             }],
+            ..Default::default()
         };
         let b = Package {
             name: "b".to_string(),
             path: ".".into(),
             deps: vec![],
+            ..Default::default()
         };
         let res = find_unused_pkgs(&ws, &[a, b], &Dependency::all);
         assert_eq!(res, []);
     }
 
+    #[test]
+    fn used_packages() {
+        let ws = vec![test_package("test", &["a", "other"])];
+        let upstream = vec![
+            test_package("a", &["b"]),
+            test_package("b", &[]),
+            test_package("c", &[]),
+            test_package("test", &[]),
+        ];
+        let unused = find_unused_pkgs(&ws, &upstream, &Dependency::all);
+        let used = used_pkgs(&ws, &upstream, &unused);
+        assert_eq!(used, &upstream[..2]);
+    }
+
+    #[test]
+    fn explains_kept_packages() {
+        let ws = vec![test_package("test", &["a", "other"])];
+        let upstream = vec![
+            test_package("a", &["b"]),
+            test_package("b", &[]),
+            test_package("c", &[]),
+        ];
+        let chains = kept_chains(&ws, &upstream, &Dependency::all, &Dependency::all, None);
+        assert_eq!(chains, [vec!["a", "test"], vec!["b", "a", "test"]]);
+    }
+
     #[test]
     fn filters() {
         let ws = vec![Package {
@@ -183,6 +1055,7 @@ mod tests {
                     dep_type: DepType::All,
                 },
             ],
+            ..Default::default()
         }];
         // Should be marked unused
         let a = Package {
@@ -192,18 +1065,21 @@ mod tests {
                 name: "b".into(),
                 dep_type: DepType::Build,
             }],
+            ..Default::default()
         };
         // Should be used
         let b = Package {
             name: "b".into(),
             path: ".".into(),
             deps: vec![],
+            ..Default::default()
         };
         // Should be used
         let c = Package {
             name: "c".into(),
             path: ".".into(),
             deps: vec![],
+            ..Default::default()
         };
         let res = find_unused_pkgs(&ws, &[a.clone(), b, c], &Dependency::build);
         assert_eq!(res, [a]);
@@ -219,4 +1095,727 @@ mod tests {
         let res = find_unused_pkgs(&ws, &ws, &Dependency::all);
         assert!(res.is_empty());
     }
+
+    #[test]
+    fn workspace_exclusion() {
+        let ws = vec![
+            test_package("robot", &["a"]),
+            test_package("robot_example", &["a", "b"]),
+        ];
+        let a = test_package("a", &[]);
+        let b = test_package("b", &[]);
+        let upstream = vec![a, b.clone()];
+        assert!(find_unused_pkgs(&ws, &upstream, &Dependency::all).is_empty());
+
+        let (ws, excluded) = split_matching(ws, &[Pattern::new("*_example").unwrap()]);
+        assert_eq!(excluded.len(), 1);
+        assert_eq!(excluded[0].name, "robot_example");
+        let res = find_unused_pkgs(&ws, &upstream, &Dependency::all);
+        assert_eq!(res, [b]);
+    }
+
+    #[test]
+    fn package_patterns() {
+        let upstream: Vec<Package> = ["nav2_core", "nav2_bt_navigator", "moveit_nav", "Nav2_x"]
+            .iter()
+            .map(|n| test_package(n, &[]))
+            .collect();
+        let selected = |pattern: &str| {
+            let (_, matched) = split_matching(upstream.clone(), &[Pattern::new(pattern).unwrap()]);
+            matched.into_iter().map(|p| p.name).collect::<Vec<_>>()
+        };
+        assert_eq!(selected("nav2_*"), ["nav2_core", "nav2_bt_navigator"]);
+        assert_eq!(selected("nav2_core"), ["nav2_core"]);
+        assert!(selected("nav2").is_empty());
+    }
+
+    #[test]
+    fn reverse_dependencies() {
+        let mut packages = vec![
+            test_package("robot", &["nav", "tf"]),
+            test_package("nav", &["tf"]),
+            test_package("viz", &["nav"]),
+            test_package("tf", &[]),
+        ];
+        packages.push(Package {
+            deps: vec![Dependency {
+                name: "tf".into(),
+                dep_type: DepType::Test,
+            }],
+            ..test_package("tf_tests", &[])
+        });
+        assert_eq!(
+            reverse_deps(&packages, "nav", &Dependency::all),
+            [vec!["robot", "nav"], vec!["viz", "nav"]]
+        );
+        assert_eq!(
+            reverse_deps(&packages, "tf", &Dependency::matcher(vec![DepType::Build])),
+            [
+                vec!["nav", "tf"],
+                vec!["robot", "tf"],
+                vec!["viz", "nav", "tf"]
+            ]
+        );
+        assert!(reverse_deps(&packages, "robot", &Dependency::all).is_empty());
+    }
+
+    #[test]
+    fn duplicate_package_names() {
+        let at = |name: &str, path: &str| Package {
+            path: path.into(),
+            ..test_package(name, &[])
+        };
+        let packages = vec![
+            at("cv_bridge", "/up/vision_opencv/cv_bridge"),
+            at("tf2", "/up/geometry2/tf2"),
+            at("cv_bridge", "/up/vendor/cv_bridge"),
+            at("tf2", "/up/geometry2/tf2"),
+        ];
+        assert_eq!(
+            duplicate_names(&packages),
+            [(
+                "cv_bridge",
+                vec![
+                    Path::new("/up/vendor/cv_bridge"),
+                    Path::new("/up/vision_opencv/cv_bridge")
+                ]
+            )]
+        );
+    }
+
+    #[test]
+    fn closure_size() {
+        let ws = vec![
+            test_package("small", &["a"]),
+            test_package("greedy", &["meta"]),
+        ];
+        let upstream = vec![
+            test_package("meta", &["a", "b", "c"]),
+            test_package("a", &[]),
+            test_package("b", &["c"]),
+            test_package("c", &["meta"]),
+        ];
+        let mut closure: Vec<_> = reachable(&ws[1], &upstream, &Dependency::all)
+            .iter()
+            .map(|p| p.name.as_str())
+            .collect();
+        closure.sort();
+        assert_eq!(closure, ["a", "b", "c", "meta"]);
+
+        let res = oversized_closures(&ws, &upstream, &Dependency::all, 2);
+        assert_eq!(res, [(&ws[1], 4)]);
+        assert!(oversized_closures(&ws, &upstream, &Dependency::all, 4).is_empty());
+    }
+
+    #[test]
+    fn sorted_deps() {
+        let dep = |name: &str, dep_type| Dependency {
+            name: name.into(),
+            dep_type,
+        };
+        let p = Package {
+            name: "test".into(),
+            path: ".".into(),
+            deps: vec![
+                dep("b", DepType::Test),
+                dep("z", DepType::All),
+                dep("a", DepType::Test),
+                dep("c", DepType::Build),
+                dep("a", DepType::All),
+            ],
+            ..Default::default()
+        };
+        let sorted = p.sorted();
+        assert_eq!(
+            sorted.deps,
+            vec![
+                dep("a", DepType::All),
+                dep("z", DepType::All),
+                dep("c", DepType::Build),
+                dep("a", DepType::Test),
+                dep("b", DepType::Test),
+            ]
+        );
+        // Declaration order is kept in the original
+        assert_eq!(p.deps[0], dep("b", DepType::Test));
+    }
+
+    #[test]
+    fn layering() {
+        let ws = vec![
+            test_package("robot", &["a"]),
+            test_package("robot_msgs", &[]),
+            test_package("a", &[]),
+        ];
+        let upstream = vec![
+            test_package("a", &[]),
+            test_package("b", &["robot_msgs", "a"]),
+        ];
+        let res = layering_violations(&ws, &upstream, &Dependency::all);
+        assert_eq!(res, [(&upstream[1], vec!["robot_msgs"])]);
+    }
+
+    #[test]
+    fn missing_dependencies() {
+        let kept = vec![
+            test_package("robot", &["a", "nonexistent_pkg", "python3-yaml"]),
+            test_package("a", &[]),
+        ];
+        let allowed = vec![Pattern::new("python3-*").unwrap()];
+        let res = missing_deps(&kept, &kept, &Dependency::all, &allowed);
+        assert_eq!(res, [(&kept[0], vec!["nonexistent_pkg"])]);
+    }
+
+    #[test]
+    fn overlapping_workspaces() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ws").join("shared");
+        std::fs::create_dir_all(&path).unwrap();
+        let shared = Package {
+            path: path.clone(),
+            ..test_package("shared", &["a", "b"])
+        };
+        let reordered = Package {
+            path: path.join("."),
+            ..test_package("shared", &["b", "a"])
+        };
+        let upstream = vec![
+            reordered,
+            test_package("a", &[]),
+            test_package("b", &[]),
+            test_package("c", &[]),
+        ];
+        let unused = find_unused_pkgs(&[shared], &upstream, &Dependency::all);
+        assert_eq!(unused, [test_package("c", &[])]);
+    }
+
+    #[test]
+    fn deps_sources() {
+        let secondary = vec![("a".to_string(), vec!["x".to_string(), "y".to_string()])];
+        let names = |source| {
+            let mut pkgs = vec![test_package("a", &["x", "z"]), test_package("b", &["z"])];
+            apply_deps_source(&mut pkgs, source, &secondary, DepType::All);
+            pkgs.iter()
+                .map(|p| p.deps.iter().map(|d| d.name.clone()).collect::<Vec<_>>())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(names(DepsSource::Manifest), [vec!["x", "z"], vec!["z"]]);
+        assert_eq!(names(DepsSource::Index), [vec!["x", "y"], vec![]]);
+        assert_eq!(names(DepsSource::Both), [vec!["x", "z", "y"], vec!["z"]]);
+    }
+
+    #[test]
+    fn group_dependencies() {
+        let ws = vec![Package {
+            group_deps: vec!["rqt_plugins".into()],
+            ..test_package("gui", &[])
+        }];
+        let member = |name: &str, deps: &[&str]| Package {
+            groups: vec!["rqt_plugins".into()],
+            ..test_package(name, deps)
+        };
+        // Members of groups depended on by upstream packages are kept too
+        let plugins = Package {
+            group_deps: vec!["plot_backends".into()],
+            ..member("rqt_plot", &["qwt"])
+        };
+        let backend = Package {
+            groups: vec!["plot_backends".into()],
+            ..test_package("matplot", &[])
+        };
+        let unrelated = test_package("rviz", &[]);
+        let upstream = vec![
+            plugins,
+            member("rqt_console", &[]),
+            test_package("qwt", &[]),
+            backend,
+            unrelated.clone(),
+        ];
+        let res = find_unused_pkgs(&ws, &upstream, &Dependency::all);
+        assert_eq!(res, [unrelated]);
+    }
+
+    #[test]
+    fn excluded_packages_are_roots() {
+        let ws = vec![test_package("robot", &["driver"])];
+        let upstream = vec![
+            test_package("driver", &[]),
+            test_package("rosidl_typesupport", &["rosidl_generator"]),
+            test_package("rosidl_generator", &["rosidl_parser"]),
+            test_package("rosidl_parser", &[]),
+            test_package("plugin", &[]),
+            test_package("unused", &[]),
+        ];
+        let names = vec![
+            "plugin".to_string(),
+            "rosidl_typesupport".to_string(),
+            "rosidl".to_string(),
+        ];
+        let (excluded, missing) = named_pkgs(&upstream, &names);
+        assert_eq!(missing, ["rosidl"]);
+        let mut roots = ws.clone();
+        roots.extend(excluded.into_iter().cloned());
+        let res = find_unused_pkgs(&roots, &upstream, &Dependency::all);
+        assert_eq!(res, [upstream[5].clone()]);
+    }
+
+    #[test]
+    fn ignored_packages_are_roots() {
+        let ws = vec![test_package("robot", &[])];
+        let mut pinned = test_package("pinned", &["dep"]);
+        pinned.directives = vec!["ignore".into()];
+        let upstream = vec![
+            pinned.clone(),
+            test_package("dep", &[]),
+            test_package("unused", &[]),
+        ];
+        let roots = cleanup_roots(&ws, &upstream);
+        assert_eq!(roots, [ws[0].clone(), pinned]);
+        let res = find_unused_pkgs(&roots, &upstream, &Dependency::all);
+        assert_eq!(res, [upstream[2].clone()]);
+    }
+
+    #[test]
+    fn runtime_types() {
+        let dep = |name: &str, dep_type| Dependency {
+            name: name.into(),
+            dep_type,
+        };
+        let ws = vec![Package {
+            name: "robot".into(),
+            deps: vec![
+                dep("headers", DepType::BuildExport),
+                dep("compiler", DepType::Build),
+                dep("launch", DepType::Exec),
+            ],
+            ..Default::default()
+        }];
+        let compiler = test_package("compiler", &[]);
+        let upstream = vec![
+            test_package("headers", &[]),
+            compiler.clone(),
+            test_package("launch", &[]),
+        ];
+        let res = find_unused_pkgs(&ws, &upstream, &Dependency::runtime);
+        assert_eq!(res, [compiler]);
+    }
+
+    #[test]
+    fn runtime_closure() {
+        let dep = |name: &str, dep_type| Dependency {
+            name: name.into(),
+            dep_type,
+        };
+        let ws = vec![Package {
+            name: "test".into(),
+            path: ".".into(),
+            deps: vec![dep("a", DepType::Exec), dep("tool", DepType::Build)],
+            ..Default::default()
+        }];
+        let a = Package {
+            name: "a".into(),
+            path: ".".into(),
+            deps: vec![
+                dep("b", DepType::Build),
+                dep("c", DepType::Exec),
+                dep("d", DepType::All),
+            ],
+            ..Default::default()
+        };
+        let b = test_package("b", &[]);
+        let c = test_package("c", &[]);
+        let d = test_package("d", &[]);
+        let tool = test_package("tool", &[]);
+        let upstream = vec![a, b.clone(), c, d, tool];
+
+        let res = find_unused_pkgs_staged(&ws, &upstream, &Dependency::all, &Dependency::runtime);
+        assert_eq!(res, [b]);
+        assert!(find_unused_pkgs(&ws, &upstream, &Dependency::all).is_empty());
+    }
+
+    #[test]
+    fn dependency_cycles_terminate() {
+        let ws = vec![test_package("ws", &["a", "x"])];
+        let upstream = vec![
+            test_package("a", &["b"]),
+            test_package("b", &["a"]),
+            test_package("x", &["y"]),
+            test_package("y", &["z"]),
+            test_package("z", &["x", "w"]),
+            test_package("w", &[]),
+            test_package("p", &["q"]),
+            test_package("q", &["p"]),
+        ];
+        let mut res = find_unused_pkgs(&ws, &upstream, &Dependency::all);
+        res.sort_by(|a, b| a.name.cmp(&b.name));
+        assert_eq!(res, [upstream[6].clone(), upstream[7].clone()]);
+    }
+
+    #[test]
+    fn long_chains_keep_the_stack() {
+        let n = 200_000;
+        let names: Vec<String> = (0..=n).map(|i| format!("p{i}")).collect();
+        let upstream: Vec<Package> = (0..n)
+            .map(|i| test_package(&names[i], &[&names[i + 1]]))
+            .collect();
+        let ws = vec![test_package("ws", &["p0"])];
+        assert!(find_unused_pkgs(&ws, &upstream, &Dependency::all).is_empty());
+    }
+
+    #[test]
+    fn dedup_output() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("a")).unwrap();
+        let mut a = test_package("a", &["x", "y"]);
+        a.path = dir.path().join("a");
+        let mut seeded = test_package("a", &["y", "x"]);
+        seeded.path = dir.path().join(".").join("a");
+        let mut other = test_package("a", &[]);
+        other.path = dir.path().join("other");
+        let b = test_package("b", &[]);
+
+        let mut pkgs = vec![b.clone(), a.clone(), other.clone(), seeded];
+        dedup_packages(&mut pkgs);
+        assert_eq!(pkgs, [a, other, b]);
+    }
+
+    #[test]
+    fn merge_strategies() {
+        let at = |name: &str, path: &str| Package {
+            path: path.into(),
+            ..test_package(name, &[])
+        };
+        let first = vec![at("a", "one/a"), at("b", "one/b")];
+        let second = vec![at("a", "two/a"), at("b", "one/b"), at("c", "two/c")];
+        let lists = [first, second];
+
+        let merged = |strategy| merge_packages(&lists, strategy).unwrap();
+        assert_eq!(
+            merged(MergeStrategy::First),
+            [at("a", "one/a"), at("b", "one/b"), at("c", "two/c")]
+        );
+        assert_eq!(
+            merged(MergeStrategy::Last),
+            [at("a", "two/a"), at("b", "one/b"), at("c", "two/c")]
+        );
+        assert_eq!(
+            merged(MergeStrategy::KeepAll),
+            [
+                at("a", "one/a"),
+                at("a", "two/a"),
+                at("b", "one/b"),
+                at("c", "two/c")
+            ]
+        );
+        merge_packages(&lists, MergeStrategy::Error).expect_err("Should have failed on 'a'");
+
+        let disjoint = [vec![at("b", "b")], vec![at("a", "a")]];
+        for strategy in [MergeStrategy::First, MergeStrategy::Error] {
+            assert_eq!(
+                merge_packages(&disjoint, strategy).unwrap(),
+                [at("a", "a"), at("b", "b")]
+            );
+        }
+    }
+
+    #[test]
+    fn removal_rationale() {
+        let ws = vec![test_package("robot", &["kept"])];
+        let upstream = vec![
+            test_package("kept", &[]),
+            test_package("base", &[]),
+            test_package("mid", &["base"]),
+            test_package("top", &["mid", "base"]),
+        ];
+        let unused = find_unused_pkgs(&ws, &upstream, &Dependency::all);
+        let safety = removal_safety(&upstream, &unused, &Dependency::all);
+        assert_eq!(safety.len(), 3);
+        let base = safety.iter().find(|s| s.package.name == "base").unwrap();
+        assert_eq!(base.dependents, [&upstream[2], &upstream[3]]);
+        assert!(safety.iter().all(RemovalSafety::is_safe));
+
+        // Removing only "base" would orphan its kept dependents
+        let partial = removal_safety(&upstream, &upstream[1..2], &Dependency::all);
+        assert_eq!(partial[0].kept_dependents, [&upstream[2], &upstream[3]]);
+        assert!(!partial[0].is_safe());
+    }
+
+    #[test]
+    fn sibling_namespaces() {
+        assert_eq!(namespace("acme_robot_driver", "_"), Some("acme"));
+        assert_eq!(namespace("acme-robot", "-"), Some("acme"));
+        assert_eq!(namespace("acme", "_"), None);
+        assert_eq!(namespace("_private", "_"), None);
+
+        let ws = vec![test_package("acme_robot", &[]), test_package("robot", &[])];
+        let upstream = vec![
+            test_package("acme_util", &["foo"]),
+            test_package("foo", &[]),
+            test_package("other_util", &[]),
+        ];
+        let protected = sibling_namespace_pkgs(&ws, &upstream, "_");
+        assert_eq!(protected, [&upstream[0]]);
+
+        let mut roots = ws.clone();
+        roots.extend(protected.into_iter().cloned());
+        let res = find_unused_pkgs(&roots, &upstream, &Dependency::all);
+        assert_eq!(res, [upstream[2].clone()]);
+    }
+
+    #[test]
+    fn promoted_subtree() {
+        let at = |name: &str, path: &str, deps: &[&str]| Package {
+            path: path.into(),
+            ..test_package(name, deps)
+        };
+        let ws = vec![at("robot", "/ws/robot", &[])];
+        let upstream = vec![
+            at("dev_a", "/upstream/dev/a", &["lib"]),
+            at("dev_b", "/upstream/dev/b", &[]),
+            at("lib", "/upstream/vendor/lib", &[]),
+            at("unused", "/upstream/vendor/unused", &[]),
+            at("devel", "/upstream/devel", &[]),
+        ];
+        let (upstream, promoted) = split_under(upstream, &["/upstream/dev".into()]);
+        assert_eq!(promoted.len(), 2);
+        let mut roots = ws.clone();
+        roots.extend(promoted);
+        let mut res: Vec<_> = find_unused_pkgs(&roots, &upstream, &Dependency::all)
+            .into_iter()
+            .map(|p| p.name)
+            .collect();
+        res.sort();
+        assert_eq!(res, ["devel", "unused"]);
+    }
+
+    #[test]
+    fn per_workspace() {
+        let ws1 = vec![test_package("robot1", &["common", "a"])];
+        let ws2 = vec![test_package("robot2", &["common"])];
+        let common = test_package("common", &[]);
+        let a = test_package("a", &[]);
+        let b = test_package("b", &[]);
+        let upstream = vec![common, a.clone(), b.clone()];
+        let all = Dependency::all;
+        let (per_ws, intersection) =
+            find_unused_per_workspace(&[ws1, ws2], &upstream, &all, &all, None);
+        assert_eq!(per_ws[0], vec![b.clone()]);
+        let mut second = per_ws[1].clone();
+        second.sort_by(|x, y| x.name.cmp(&y.name));
+        assert_eq!(second, [a, b.clone()]);
+        assert_eq!(intersection, [b]);
+    }
+
+    #[test]
+    fn per_workspace_matches_union() {
+        let robot = Package {
+            path: "/ws/robot".into(),
+            ..test_package("robot", &["a"])
+        };
+        let upstream = vec![
+            test_package("a", &["b"]),
+            test_package("b", &["c"]),
+            test_package("c", &[]),
+            test_package("d", &[]),
+        ];
+        let all = Dependency::all;
+        let names = |pkgs: &[Package]| {
+            let mut names: Vec<String> = pkgs.iter().map(|p| p.name.clone()).collect();
+            names.sort();
+            names
+        };
+        let union =
+            find_unused_pkgs_within(std::slice::from_ref(&robot), &upstream, &all, &all, Some(0));
+        assert_eq!(names(&union), ["b", "c", "d"]);
+
+        let groups = workspace_groups(std::slice::from_ref(&robot), &["/ws".into()]);
+        let (per_ws, intersection) =
+            find_unused_per_workspace(&groups, &upstream, &all, &all, Some(0));
+        assert_eq!(names(&per_ws[0]), ["b", "c", "d"]);
+        assert_eq!(names(&intersection), ["b", "c", "d"]);
+
+        // Only roots outside any workspace, e.g. from --package
+        let groups = workspace_groups(&[robot], &[]);
+        let (_, intersection) = find_unused_per_workspace(&groups, &upstream, &all, &all, None);
+        let union = find_unused_pkgs_within(&groups[0], &upstream, &all, &all, None);
+        assert_eq!(intersection, union);
+        assert_eq!(names(&union), ["d"]);
+    }
+
+    #[test]
+    fn unused_by_any_matches_union() {
+        let robot = Package {
+            path: "/ws/robot".into(),
+            ..test_package("robot", &["a"])
+        };
+        let upstream = vec![
+            test_package("a", &["b"]),
+            test_package("b", &[]),
+            test_package("c", &[]),
+        ];
+        let all = Dependency::all;
+        let names = |pkgs: Vec<Package>| pkgs.into_iter().map(|p| p.name).collect::<Vec<_>>();
+        let groups = workspace_groups(std::slice::from_ref(&robot), &["/ws".into()]);
+        assert_eq!(
+            names(find_unused_by_any(&groups, &upstream, &all, &all, Some(0))),
+            ["b", "c"]
+        );
+
+        // Only roots outside any workspace, e.g. from --package
+        let groups = workspace_groups(&[robot], &[]);
+        assert_eq!(
+            names(find_unused_by_any(&groups, &upstream, &all, &all, None)),
+            ["c"]
+        );
+    }
+
+    #[test]
+    fn replacements_are_kept() {
+        let mut ws = vec![test_package("robot", &["old_driver"])];
+        let mut upstream = vec![
+            Package {
+                replaces: vec!["old_driver".into()],
+                conflicts: vec!["other_driver".into()],
+                ..test_package("new_driver", &[])
+            },
+            test_package("other_driver", &[]),
+        ];
+        assert_eq!(find_unused_pkgs(&ws, &upstream, &Dependency::all).len(), 2);
+        let providers = upstream.clone();
+        resolve_replacements(&mut ws, &providers);
+        resolve_replacements(&mut upstream, &providers);
+        assert_eq!(
+            find_unused_pkgs(&ws, &upstream, &Dependency::all),
+            [upstream[1].clone()]
+        );
+        assert_eq!(conflicting_pkgs(&upstream), [(&upstream[0], &upstream[1])]);
+    }
+
+    #[test]
+    fn self_dependencies() {
+        let packages = vec![
+            test_package("loop", &["a", "loop"]),
+            test_package("a", &["loop"]),
+        ];
+        assert_eq!(self_dependent(&packages), [&packages[0]]);
+    }
+
+    #[test]
+    fn excluded_types() {
+        let dep = |name: &str, dep_type| Dependency {
+            name: name.into(),
+            dep_type,
+        };
+        let ws = vec![Package {
+            name: "robot".into(),
+            path: ".".into(),
+            deps: vec![
+                dep("a", DepType::All),
+                dep("b", DepType::Build),
+                dep("c", DepType::Exec),
+                dep("gtest", DepType::Test),
+            ],
+            ..Default::default()
+        }];
+        let upstream = vec![
+            test_package("a", &[]),
+            test_package("b", &[]),
+            test_package("c", &[]),
+            test_package("gtest", &[]),
+        ];
+        let no_tests = Dependency::matcher_excluding(vec![], vec![DepType::Test]);
+        assert_eq!(
+            find_unused_pkgs(&ws, &upstream, &no_tests),
+            [upstream[3].clone()]
+        );
+        let build_only = Dependency::matcher_excluding(vec![DepType::Build], vec![DepType::All]);
+        let mut unused: Vec<String> = find_unused_pkgs(&ws, &upstream, &build_only)
+            .into_iter()
+            .map(|p| p.name)
+            .collect();
+        unused.sort();
+        assert_eq!(unused, ["a", "c", "gtest"]);
+    }
+
+    #[test]
+    fn limited_depth() {
+        let ws = vec![test_package("robot", &["a", "c"])];
+        let upstream = vec![
+            test_package("a", &["b"]),
+            test_package("b", &["d"]),
+            test_package("c", &[]),
+            test_package("d", &[]),
+        ];
+        let unused = |max_depth| -> Vec<String> {
+            let all = Dependency::all;
+            let mut res: Vec<String> =
+                find_unused_pkgs_within(&ws, &upstream, &all, &all, max_depth)
+                    .into_iter()
+                    .map(|p| p.name)
+                    .collect();
+            res.sort();
+            res
+        };
+        assert_eq!(unused(Some(0)), ["b", "d"]);
+        assert_eq!(unused(Some(1)), ["d"]);
+        assert!(unused(None).is_empty());
+
+        // Reached first through the longer path, then directly
+        let ws = vec![test_package("robot", &["a", "b"])];
+        let upstream = vec![
+            test_package("a", &["b"]),
+            test_package("b", &["c"]),
+            test_package("c", &[]),
+        ];
+        let all = Dependency::all;
+        assert!(find_unused_pkgs_within(&ws, &upstream, &all, &all, Some(1)).is_empty());
+    }
+
+    #[test]
+    fn unused_by_any_workspace() {
+        let workspaces = vec![
+            vec![test_package("robot", &["common", "nav"])],
+            vec![test_package("arm", &["common", "moveit"])],
+        ];
+        let upstream = vec![
+            test_package("common", &[]),
+            test_package("moveit", &[]),
+            test_package("nav", &[]),
+        ];
+        let all = Dependency::all;
+        let unused = find_unused_by_any(&workspaces, &upstream, &all, &all, None);
+        assert_eq!(unused, &upstream[1..]);
+        let (_, by_all) = find_unused_per_workspace(&workspaces, &upstream, &all, &all, None);
+        assert!(by_all.is_empty());
+    }
+
+    #[test]
+    fn parses_dep_types() {
+        assert_eq!("build_depend".parse::<DepType>().unwrap(), DepType::Build);
+        assert_eq!("Exec".parse::<DepType>().unwrap(), DepType::Exec);
+        assert_eq!(
+            "BUILDTOOL_DEPEND".parse::<DepType>().unwrap(),
+            DepType::BuildTool
+        );
+        assert_eq!(
+            "build-export".parse::<DepType>().unwrap(),
+            DepType::BuildExport
+        );
+        assert!("runtime".parse::<DepType>().is_err());
+        for t in DepType::all_variants() {
+            let name = t.to_possible_value().unwrap();
+            assert_eq!(name.get_name().parse::<DepType>().unwrap(), *t);
+        }
+        assert_eq!(DepType::all_variants().len(), 7);
+    }
+
+    #[test]
+    fn case_insensitive_names() {
+        let mut ws = vec![test_package("robot", &["Boost"])];
+        let upstream = vec![test_package("boost", &[])];
+        assert_eq!(find_unused_pkgs(&ws, &upstream, &Dependency::all), upstream);
+        fold_name_case(&mut ws, &upstream);
+        assert_eq!(ws[0].deps[0].name, "boost");
+        assert!(find_unused_pkgs(&ws, &upstream, &Dependency::all).is_empty());
+    }
 }
@@ -0,0 +1,398 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Write,
+};
+
+use anyhow::{anyhow, Result};
+
+use crate::filtering::{DepFilter, Package};
+
+/// `s` as a quoted DOT identifier, safe for node names, labels and attribute values.
+///
+/// Inside quotes only `"` needs escaping for graphviz. Backslashes are doubled so they
+/// aren't taken as label escapes like `\n`, and line breaks become such an escape.
+pub fn dot_quote(s: &str) -> String {
+    let mut res = String::with_capacity(s.len() + 2);
+    res.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => res.push_str("\\\""),
+            '\\' => res.push_str("\\\\"),
+            '\n' => res.push_str("\\n"),
+            '\r' => {}
+            c => res.push(c),
+        }
+    }
+    res.push('"');
+    res
+}
+
+/// Dependency graph over package names, with edges sorted by name for determinism.
+struct Graph<'a> {
+    names: Vec<&'a str>,
+    edges: Vec<Vec<usize>>,
+}
+
+impl<'a> Graph<'a> {
+    fn new(packages: &'a [Package], filter: &DepFilter) -> Self {
+        let mut names: Vec<&str> = packages.iter().map(|p| p.name.as_str()).collect();
+        names.sort_unstable();
+        names.dedup();
+        let index: HashMap<&str, usize> = names.iter().enumerate().map(|(i, &n)| (n, i)).collect();
+        let mut edges = vec![Vec::new(); names.len()];
+        for p in packages {
+            let from = index[p.name.as_str()];
+            for dep in p.deps.iter().filter(|x| filter(x)) {
+                if let Some(&to) = index.get(dep.name.as_str()) {
+                    edges[from].push(to);
+                }
+            }
+        }
+        for e in edges.iter_mut() {
+            e.sort_unstable();
+            e.dedup();
+        }
+        Graph { names, edges }
+    }
+
+    /// Strongly connected components (Tarjan's algorithm).
+    fn sccs(&self) -> Vec<Vec<usize>> {
+        struct State {
+            index: Vec<Option<usize>>,
+            low: Vec<usize>,
+            on_stack: Vec<bool>,
+            stack: Vec<usize>,
+            next: usize,
+            res: Vec<Vec<usize>>,
+        }
+
+        fn connect(graph: &Graph, v: usize, s: &mut State) {
+            s.index[v] = Some(s.next);
+            s.low[v] = s.next;
+            s.next += 1;
+            s.stack.push(v);
+            s.on_stack[v] = true;
+            for &w in graph.edges[v].iter() {
+                match s.index[w] {
+                    None => {
+                        connect(graph, w, s);
+                        s.low[v] = s.low[v].min(s.low[w]);
+                    }
+                    Some(index) if s.on_stack[w] => s.low[v] = s.low[v].min(index),
+                    Some(_) => {}
+                }
+            }
+            if Some(s.low[v]) == s.index[v] {
+                let mut component = Vec::new();
+                while let Some(w) = s.stack.pop() {
+                    s.on_stack[w] = false;
+                    component.push(w);
+                    if w == v {
+                        break;
+                    }
+                }
+                component.sort_unstable();
+                s.res.push(component);
+            }
+        }
+
+        let n = self.names.len();
+        let mut state = State {
+            index: vec![None; n],
+            low: vec![0; n],
+            on_stack: vec![false; n],
+            stack: Vec::new(),
+            next: 0,
+            res: Vec::new(),
+        };
+        for v in 0..n {
+            if state.index[v].is_none() {
+                connect(self, v, &mut state);
+            }
+        }
+        state.res
+    }
+
+    /// The elementary cycles within `component`, each starting at its smallest index so that
+    /// every cycle is found once rather than once per rotation. Stops after `limit` cycles, as
+    /// densely connected components can have exponentially many.
+    fn cycles_within(&self, component: &[usize], limit: usize) -> Vec<Vec<usize>> {
+        fn extend(
+            graph: &Graph,
+            component: &[usize],
+            path: &mut Vec<usize>,
+            limit: usize,
+            res: &mut Vec<Vec<usize>>,
+        ) {
+            let start = path[0];
+            let v = *path.last().unwrap();
+            for &w in graph.edges[v].iter() {
+                if res.len() >= limit {
+                    return;
+                }
+                if w == start {
+                    res.push(path.clone());
+                } else if w > start && component.binary_search(&w).is_ok() && !path.contains(&w) {
+                    path.push(w);
+                    extend(graph, component, path, limit, res);
+                    path.pop();
+                }
+            }
+        }
+
+        let mut res = Vec::new();
+        for &start in component {
+            extend(self, component, &mut vec![start], limit, &mut res);
+        }
+        res
+    }
+}
+
+/// Deterministic integer IDs for a set of packages, in the order of their sorted names, for
+/// compact graph output.
+pub struct StableIds<'a> {
+    graph: Graph<'a>,
+}
+
+impl<'a> StableIds<'a> {
+    pub fn new(packages: &'a [Package], filter: &DepFilter) -> Self {
+        StableIds {
+            graph: Graph::new(packages, filter),
+        }
+    }
+
+    pub fn id(&self, name: &str) -> Option<usize> {
+        self.graph.names.binary_search(&name).ok()
+    }
+
+    /// Each ID with its package name, by ID.
+    pub fn legend(&self) -> impl Iterator<Item = (usize, &'a str)> + '_ {
+        self.graph.names.iter().copied().enumerate()
+    }
+
+    /// The dependency edges between the packages as `(dependent, dependency)` IDs.
+    pub fn edges(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        self.graph
+            .edges
+            .iter()
+            .enumerate()
+            .flat_map(|(from, to)| to.iter().map(move |&to| (from, to)))
+    }
+}
+
+/// A DOT digraph of `workspace` and `upstream` packages with the dependencies passing
+/// `filter`. Workspace packages are boxes and `unused` upstream packages are red and dashed.
+///
+/// With `stable_ids`, nodes are [`StableIds`] listed in a comment legend instead of names.
+pub fn to_dot(
+    workspace: &[Package],
+    upstream: &[Package],
+    unused: &[Package],
+    filter: &DepFilter,
+    stable_ids: bool,
+) -> String {
+    let all = [workspace, upstream].concat();
+    let ids = StableIds::new(&all, filter);
+    let ws_names: HashSet<&str> = workspace.iter().map(|p| p.name.as_str()).collect();
+    let unused_names: HashSet<&str> = unused.iter().map(|p| p.name.as_str()).collect();
+    let legend: Vec<(usize, &str)> = ids.legend().collect();
+    let node = |id: usize| {
+        if stable_ids {
+            id.to_string()
+        } else {
+            dot_quote(legend[id].1)
+        }
+    };
+
+    let mut res = String::from("digraph dependencies {\n");
+    for &(id, name) in legend.iter() {
+        if stable_ids {
+            // Quoted, so a line break can't end the comment early
+            writeln!(res, "  // {id}: {}", dot_quote(name)).unwrap();
+        }
+        let style = if ws_names.contains(name) {
+            " [shape=box]"
+        } else if unused_names.contains(name) {
+            " [color=red, style=dashed]"
+        } else {
+            ""
+        };
+        writeln!(res, "  {}{};", node(id), style).unwrap();
+    }
+    for (from, to) in ids.edges() {
+        writeln!(res, "  {} -> {};", node(from), node(to)).unwrap();
+    }
+    res.push_str("}\n");
+    res
+}
+
+/// The most cycles listed per group of mutually dependent packages.
+const MAX_CYCLES: usize = 100;
+
+/// The elementary cycles within each group of mutually dependent packages, each starting at
+/// its alphabetically first package. At most [`MAX_CYCLES`] are listed per group.
+pub fn dependency_cycles<'a>(packages: &'a [Package], filter: &DepFilter) -> Vec<Vec<&'a str>> {
+    let graph = Graph::new(packages, filter);
+    let mut res: Vec<Vec<&str>> = graph
+        .sccs()
+        .iter()
+        .flat_map(|component| graph.cycles_within(component, MAX_CYCLES))
+        .map(|cycle| cycle.iter().map(|&i| graph.names[i]).collect())
+        .collect();
+    res.sort();
+    res
+}
+
+/// An error listing all `cycles`, if there are any.
+pub fn check_cycles(cycles: &[Vec<&str>]) -> Result<()> {
+    if cycles.is_empty() {
+        return Ok(());
+    }
+    let listing: Vec<String> = cycles
+        .iter()
+        .map(|c| format!("  {} -> {}", c.join(" -> "), c[0]))
+        .collect();
+    Err(anyhow!(
+        "Found {} dependency cycle(s):\n{}",
+        cycles.len(),
+        listing.join("\n")
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filtering::{DepType, Dependency};
+
+    fn test_package(name: &str, deps: &[&str]) -> Package {
+        Package {
+            name: name.to_string(),
+            path: name.into(),
+            deps: deps
+                .iter()
+                .map(|n| Dependency {
+                    name: n.to_string(),
+                    dep_type: DepType::All,
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn enumerates_cycles() {
+        let pkgs = vec![
+            test_package("a", &["b"]),
+            test_package("b", &["a", "c"]),
+            test_package("c", &["d"]),
+            test_package("d", &["e", "external"]),
+            test_package("e", &["c"]),
+            test_package("f", &["a", "f"]),
+            test_package("g", &["a"]),
+        ];
+        let cycles = dependency_cycles(&pkgs, &Dependency::all);
+        assert_eq!(cycles, vec![vec!["a", "b"], vec!["c", "d", "e"], vec!["f"]]);
+        let err = check_cycles(&cycles).unwrap_err().to_string();
+        assert!(err.contains("a -> b -> a"));
+        assert!(err.contains("c -> d -> e -> c"));
+        assert!(err.contains("f -> f"));
+
+        // Overlapping cycles within one group are all listed
+        let overlapping = vec![
+            test_package("a", &["b"]),
+            test_package("b", &["a", "c"]),
+            test_package("c", &["b", "a"]),
+        ];
+        let cycles = dependency_cycles(&overlapping, &Dependency::all);
+        assert_eq!(
+            cycles,
+            vec![vec!["a", "b"], vec!["a", "b", "c"], vec!["b", "c"]]
+        );
+
+        let acyclic = vec![test_package("a", &["b"]), test_package("b", &[])];
+        let cycles = dependency_cycles(&acyclic, &Dependency::all);
+        assert!(cycles.is_empty());
+        check_cycles(&cycles).unwrap();
+    }
+
+    #[test]
+    fn assigns_stable_ids() {
+        let pkgs = vec![
+            test_package("c", &["a", "external"]),
+            test_package("a", &[]),
+            test_package("b", &["c", "a"]),
+        ];
+        let mut shuffled = pkgs.clone();
+        shuffled.reverse();
+        let ids = StableIds::new(&pkgs, &Dependency::all);
+        let again = StableIds::new(&shuffled, &Dependency::all);
+        assert_eq!(
+            ids.legend().collect::<Vec<_>>(),
+            again.legend().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            ids.edges().collect::<Vec<_>>(),
+            again.edges().collect::<Vec<_>>()
+        );
+
+        assert_eq!(
+            ids.legend().collect::<Vec<_>>(),
+            [(0, "a"), (1, "b"), (2, "c")]
+        );
+        let name = |id| ids.legend().nth(id).unwrap().1;
+        let named: Vec<(&str, &str)> = ids
+            .edges()
+            .map(|(from, to)| (name(from), name(to)))
+            .collect();
+        assert_eq!(named, [("b", "a"), ("b", "c"), ("c", "a")]);
+        assert_eq!(ids.id("c"), Some(2));
+        assert_eq!(ids.id("external"), None);
+    }
+
+    #[test]
+    fn renders_dot() {
+        let ws = vec![test_package("robot", &["driver"])];
+        let upstream = vec![
+            test_package("driver", &[]),
+            test_package("old_driver", &["driver"]),
+        ];
+        let unused = vec![upstream[1].clone()];
+        let dot = to_dot(&ws, &upstream, &unused, &Dependency::all, false);
+        assert_eq!(
+            dot,
+            r#"digraph dependencies {
+  "driver";
+  "old_driver" [color=red, style=dashed];
+  "robot" [shape=box];
+  "old_driver" -> "driver";
+  "robot" -> "driver";
+}
+"#
+        );
+        let dot = to_dot(&ws, &upstream, &unused, &Dependency::all, true);
+        assert!(dot.contains("  // 2: \"robot\"\n  2 [shape=box];\n"));
+        assert!(dot.contains("  2 -> 0;\n"));
+    }
+
+    #[test]
+    fn quotes_dot_strings() {
+        assert_eq!(dot_quote("plain_pkg"), r#""plain_pkg""#);
+        assert_eq!(dot_quote(r#"we"ird<&>"#), r#""we\"ird<&>""#);
+        assert_eq!(dot_quote(r"back\slash"), r#""back\\slash""#);
+        assert_eq!(dot_quote("two\nlines"), r#""two\nlines""#);
+
+        // The only quote left unescaped is the closing one
+        let quoted = dot_quote(r#"a"b\"c"#);
+        let inner = &quoted[1..quoted.len() - 1];
+        let mut escaped = false;
+        for c in inner.chars() {
+            assert!(escaped || c != '"', "Unescaped quote in {quoted}");
+            escaped = !escaped && c == '\\';
+        }
+
+        // Names in the legend comment stay on their line
+        let ws = vec![test_package("two\nlines", &[])];
+        let dot = to_dot(&ws, &[], &[], &Dependency::all, true);
+        assert!(dot.contains("  // 0: \"two\\nlines\"\n  0 [shape=box];\n"));
+    }
+}
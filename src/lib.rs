@@ -1,2 +1,16 @@
+pub mod actions;
+pub mod analysis;
+pub mod cache;
+pub mod conditions;
+pub mod config;
 pub mod filtering;
+pub mod graph;
+pub mod output;
 pub mod parsing;
+pub mod synthetic;
+pub mod versions;
+pub mod vfs;
+
+pub use analysis::{analyze, analyze_with, Analysis, AnalysisOptions};
+pub use filtering::{merge_packages, MergeStrategy};
+pub use output::OUTPUT_SCHEMA_VERSION;
@@ -1,196 +1,1584 @@
-#![feature(iterator_try_collect)]
 use std::{
-    fs::{self, OpenOptions},
-    io,
+    fs,
+    io::{self, IsTerminal},
     path::{Path, PathBuf},
+    process::ExitCode,
+    sync::Arc,
+    time::{Duration, Instant},
 };
 
 use anyhow::*;
-use clap::{Parser, ValueEnum};
+use clap::{parser::ValueSource, ArgMatches, CommandFactory, FromArgMatches, Parser, ValueEnum};
+use glob::Pattern;
+use indicatif::{ProgressBar, ProgressFinish, ProgressStyle};
 use ws_cleaner::{
-    filtering::{find_unused_pkgs, DepType, Dependency, Package},
-    parsing::find,
+    actions::{
+        affected_paths, check_root_kept, confirm_all, confirm_each, create_marker, decide_each,
+        empty_parents, find_markers, marker_diff, move_to_trash, remove_empty_parents,
+        trash_destination, Action, Decision,
+    },
+    analysis::{analyze_with, workspace_roots, Analysis, AnalysisOptions},
+    cache::ManifestCache,
+    config::Config,
+    filtering::{
+        apply_deps_source, assume_deps, cleanup_roots, conflicting_pkgs, dedup_packages,
+        distribution_diff, duplicate_names, find_unused_by_any, find_unused_per_workspace,
+        find_unused_pkgs, find_unused_pkgs_within, fold_name_case, kept_chains, kept_pkgs,
+        layering_violations, missing_deps, named_pkgs, oversized_closures, removal_safety,
+        resolve_replacements, reverse_deps, self_dependent, sibling_namespace_pkgs, used_pkgs,
+        workspace_groups, DepFilter, DepType, Dependency, DepsSource, Package, UnusedMode,
+    },
+    graph::{check_cycles, dependency_cycles, to_dot},
+    output::{
+        colcon_args, display_package, json_schema, show_progress, to_ndjson, write_atomically,
+        AnalysisJson, ColconArgs, DisplayOptions, OutputFormat, OutputTemplate, PrefixMap, Summary,
+    },
+    parsing::{
+        ament_index_deps, assumed_deps_from_env, assumed_deps_from_file, compile_commands_roots,
+        distribution_packages, parse_duration, read_path_list, read_patterns, repos_paths,
+        validate, ParseOptions, IGNORE_MARKERS,
+    },
+    synthetic,
+    versions::version_conflicts,
 };
 
-#[derive(ValueEnum, Clone, Debug)]
-enum Action {
-    /// Print all packages that are unused
-    Print,
-    /// Place a COLCON_IGNORE file
-    ColconIgnore,
-    /// Place a CATKIN_IGNORE file
-    CatkinIgnore,
-    /// Remove the package folder
-    Remove,
-}
-
-fn touch(path: &Path) -> Result<()> {
-    OpenOptions::new()
-        .create(true)
-        .append(true)
-        .open(path)
-        .map(|_| {})
-        .with_context(|| format!("Could not create '{}'", path.display()))
-}
-
 #[derive(Parser)]
 #[command(version, about, next_line_help(true))]
 struct Args {
     /// Remove unused packages from this path (usually the upstream workspace)
-    #[arg(short, long)]
-    upstream: PathBuf,
+    #[arg(short, long, required_unless_present_any = ["json_schema", "self_benchmark"])]
+    upstream: Option<PathBuf>,
 
-    /// Find packages whose dependencies to keep from these workspaces (multiple allowed)
+    /// Find packages whose dependencies to keep from these workspaces, or the repositories of
+    /// a vcstool .repos file, or '-' for paths read from stdin, one per line (multiple allowed).
+    /// Defaults to '.' without --package
     #[arg(short, long, group = "target")]
     workspace: Vec<PathBuf>,
 
-    /// Filter against the given packages rather than the workspace (multiple allowed)
+    /// Filter against the given packages rather than the workspace, may be a glob like
+    /// 'nav2_*' (multiple allowed)
     #[arg(short, long, group = "target")]
-    package: Vec<String>,
+    package: Vec<Pattern>,
+
+    /// Add the package names or globs in this file, one per line, to --package. Blank lines
+    /// and lines starting with '#' are skipped
+    #[arg(value_name = "FILE", long, conflicts_with = "workspace")]
+    packages_from: Option<PathBuf>,
+
+    /// Don't count dependencies of workspace packages matching this glob as used (multiple allowed)
+    #[arg(value_name = "NAME", long)]
+    workspace_exclude: Vec<Pattern>,
+
+    /// Keep this upstream package and its dependencies, whether used or not (multiple allowed)
+    #[arg(value_name = "NAME", long)]
+    exclude: Vec<String>,
+
+    /// Skip directories containing this file, instead of COLCON_IGNORE, CATKIN_IGNORE and
+    /// AMENT_IGNORE (multiple allowed)
+    #[arg(value_name = "FILE", long)]
+    ignore_marker: Vec<String>,
+
+    /// Take option defaults from this file instead of the nearest .ws_cleaner.toml
+    #[arg(value_name = "FILE", long)]
+    config: Option<PathBuf>,
+
+    /// Keep upstream packages sharing a namespace with a workspace package, e.g. 'acme_util' for
+    /// 'acme_robot'. The namespace is everything before the first --namespace-separator
+    #[arg(long)]
+    protect_sibling_namespace: bool,
+
+    /// Separator ending the namespace of a package name
+    #[arg(value_name = "SEPARATOR", long, default_value = "_")]
+    namespace_separator: String,
+
+    /// Treat the upstream packages whose sources appear in this compile_commands.json as used
+    #[arg(value_name = "FILE", long)]
+    compile_commands: Option<PathBuf>,
+
+    /// Treat upstream packages below this directory as part of the workspace (multiple allowed)
+    #[arg(value_name = "DIR", long)]
+    treat_as_workspace: Vec<PathBuf>,
+
+    /// How to combine multiple workspaces
+    #[arg(long, value_enum, default_value_t=UnusedMode::Union)]
+    unused_mode: UnusedMode,
+
+    /// Only keep upstream packages needed by every workspace, rather than by any of them
+    #[arg(long, conflicts_with = "unused_mode")]
+    require_all_workspaces: bool,
 
     /// Only consider these types (multiple allowed)
     #[arg(value_name = "DEPENDENCY TYPE", short = 't', long = "type")]
     dep_type: Vec<DepType>,
 
+    /// Don't consider these types, also when given with --type (multiple allowed)
+    #[arg(value_name = "DEPENDENCY TYPE", long, conflicts_with = "runtime")]
+    exclude_type: Vec<DepType>,
+
+    /// Only consider what is needed at runtime: exec_depend, build_export_depend and depend
+    #[arg(long, conflicts_with = "dep_type")]
+    runtime: bool,
+
+    /// Where dependencies are read from
+    #[arg(long, value_enum, default_value_t=DepsSource::Manifest)]
+    deps_source: DepsSource,
+
+    /// Install space whose ament index provides the run dependencies for --deps-source
+    #[arg(
+        value_name = "DIR",
+        long,
+        required_if_eq_any = [("deps_source", "index"), ("deps_source", "both")]
+    )]
+    index_prefix: Option<PathBuf>,
+
     /// Action to perform
     #[arg(short, long, value_enum, default_value_t=Action::Print)]
     action: Action,
+
+    /// Also collect dependency tags nested inside unknown wrapper elements of package.xml
+    #[arg(long)]
+    lenient_structure: bool,
+
+    /// Warn about manifests that fail to parse and carry on without them, instead of failing
+    #[arg(long)]
+    skip_invalid: bool,
+
+    /// Keep parsed manifests in this file and reuse those that didn't change since
+    #[arg(value_name = "FILE", long)]
+    cache: Option<PathBuf>,
+
+    /// Print how many manifests the --cache provided and an estimate of the time saved
+    #[arg(long, requires = "cache")]
+    cache_stats: bool,
+
+    /// Don't search more than this many directory levels below each workspace and upstream
+    #[arg(value_name = "N", long)]
+    max_depth: Option<usize>,
+
+    /// Skip packages less than this many directory levels below each workspace and upstream
+    #[arg(value_name = "N", long, default_value_t = 0)]
+    min_depth: usize,
+
+    /// Only follow runtime dependencies beyond the direct dependencies of the workspace, modeling
+    /// what is needed at runtime
+    #[arg(long)]
+    runtime_closure: bool,
+
+    /// Only keep dependencies up to this many levels below the direct dependencies of the
+    /// workspace, 0 keeping just those
+    #[arg(value_name = "N", long)]
+    max_transitive_depth: Option<usize>,
+
+    /// Warn if a workspace package transitively depends on more than this many packages
+    #[arg(value_name = "N", long)]
+    max_closure_size: Option<usize>,
+
+    /// Assume additional dependencies from this file ('pkg: dep1, dep2' per line). Combined
+    /// with the same format in WS_CLEANER_ASSUME_DEPS
+    #[arg(value_name = "FILE", long)]
+    assume_deps: Option<PathBuf>,
+
+    /// Keep packages that <replace> a needed dependency and warn about kept packages that
+    /// <conflict> with each other
+    #[arg(long)]
+    respect_replace_conflict: bool,
+
+    /// Match dependency names to package names regardless of case, e.g. `Boost` to `boost`
+    #[arg(long)]
+    case_insensitive_names: bool,
+
+    /// Check that all discovered manifests have the required elements
+    #[arg(long)]
+    validate: bool,
+
+    /// Explain for each unused package why removing it won't break a kept package
+    #[arg(long)]
+    explain_removal_safety: bool,
+
+    /// Warn about upstream packages whose version can't satisfy the constraints of all workspaces
+    #[arg(long)]
+    check_version_conflicts: bool,
+
+    /// Warn about kept upstream packages that depend on packages only found in the workspace
+    #[arg(long)]
+    check_layering: bool,
+
+    /// Warn about dependencies of kept packages that are found nowhere, e.g. typos
+    #[arg(long)]
+    report_missing: bool,
+
+    /// Dependency names or globs, one per line, not to report with --report-missing, e.g.
+    /// rosdep keys of system packages
+    #[arg(value_name = "FILE", long, requires = "report_missing")]
+    missing_allowlist: Option<PathBuf>,
+
+    /// Fail listing every dependency cycle among the workspace and upstream packages
+    #[arg(long)]
+    report_cycles_as_error: bool,
+
+    /// Treat warnings as errors
+    #[arg(long)]
+    strict: bool,
+
+    /// Only print a colcon argument fragment selecting the needed or skipping the unused packages
+    #[arg(value_name = "KIND", long, value_enum)]
+    emit_colcon_args: Option<ColconArgs>,
+
+    /// Rewrite printed paths starting with FROM to start with TO (multiple allowed, first match wins)
+    #[arg(value_name = "FROM=TO", long)]
+    prefix_map: Vec<PrefixMap>,
+
+    /// Show package versions in listings and structured output
+    #[arg(long)]
+    show_version: bool,
+
+    /// Show package maintainers in listings and structured output
+    #[arg(long)]
+    show_maintainers: bool,
+
+    /// Output format
+    #[arg(long, value_enum, default_value_t=OutputFormat::Text)]
+    format: OutputFormat,
+
+    /// In dot output, name nodes by integer IDs (in order of package names) with a legend
+    #[arg(long)]
+    stable_ids: bool,
+
+    /// Abort discovery if it takes longer than this (e.g. 30s, 5m)
+    #[arg(value_name = "DURATION", long, value_parser = parse_duration)]
+    timeout: Option<Duration>,
+
+    /// Look for package.xml in this subdirectory of each package rather than its root
+    #[arg(value_name = "SUBPATH", long)]
+    manifest_subpath: Option<PathBuf>,
+
+    /// Also search directories starting with a '.' (may pick up editor metadata; VCS
+    /// directories like .git are always skipped). The given workspaces and upstream are
+    /// searched even if hidden
+    #[arg(long, visible_alias = "include-hidden")]
+    include_dotdirs: bool,
+
+    /// Neither search nor parse directories with a name matching this, e.g. 'test' or
+    /// 'example*', without placing markers in them (multiple allowed)
+    #[arg(value_name = "PATTERN", long)]
+    skip_dir: Vec<Pattern>,
+
+    /// Search symlinked directories as well, each target once
+    #[arg(long)]
+    follow_symlinks: bool,
+
+    /// Fail on package.xml files larger than this
+    #[arg(value_name = "BYTES", long, default_value_t = 1024 * 1024)]
+    max_manifest_bytes: u64,
+
+    /// After removing packages, also delete parent directories left empty (up to the upstream root)
+    #[arg(long, visible_alias = "prune-empty-parents")]
+    remove_empty_dirs: bool,
+
+    /// Also recognize rosbuild manifest.xml files in directories without a package.xml
+    #[arg(long)]
+    legacy_manifest: bool,
+
+    /// Time the analysis of a generated upstream of N packages and exit
+    #[arg(value_name = "N", long, hide = true)]
+    self_benchmark: Option<usize>,
+
+    /// Only print a line per unused package in this format, with the placeholders {name},
+    /// {path}, {relpath} (relative to the upstream), {build_type}, {version} and {dep_count}
+    #[arg(value_name = "FORMAT", long)]
+    output_template: Option<OutputTemplate>,
+
+    /// Only compare the upstream packages against those released in this distribution.yaml
+    #[arg(value_name = "FILE", long)]
+    distribution: Option<PathBuf>,
+
+    /// Ask before removing each package
+    #[arg(long)]
+    confirm_each: bool,
+
+    /// Decide for each unused package whether to remove or ignore it, with --action as the
+    /// default answer
+    #[arg(long, conflicts_with_all = ["confirm_each", "yes"])]
+    interactive: bool,
+
+    /// Move removed packages into this directory instead of deleting them
+    #[arg(value_name = "DIR", long)]
+    trash: Option<PathBuf>,
+
+    /// Remove without asking for confirmation, e.g. in scripts
+    #[arg(short, long, conflicts_with = "confirm_each")]
+    yes: bool,
+
+    /// For each kept upstream package, print the dependency chain to the workspace package
+    /// keeping it
+    #[arg(long)]
+    explain: bool,
+
+    /// Print the packages depending on this one, with their dependency chains, and exit
+    #[arg(value_name = "NAME", long)]
+    reverse: Option<String>,
+
+    /// Exit with status 3 if there are unused packages, e.g. to use --action print as a CI
+    /// check. Errors exit with status 1, invalid arguments with 2
+    #[arg(long, conflicts_with_all = ["distribution", "reverse"])]
+    fail_on_unused: bool,
+
+    /// Fail if an upstream package name is found at more than one path
+    #[arg(long)]
+    strict_duplicates: bool,
+
+    /// Only print warnings and the summary line
+    #[arg(short, long)]
+    quiet: bool,
+
+    /// Log discovery decisions to stderr, -vv for every directory (RUST_LOG overrides)
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// Print the JSON Schema of the json output format and exit
+    #[arg(long)]
+    json_schema: bool,
+
+    /// Print the paths the action would create or delete, prefixed with `[dry-run]`, without
+    /// changing anything. Ignore actions also show how the markers on disk differ
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Don't list packages that already have the ignore marker of the action
+    #[arg(long)]
+    only_new: bool,
+
+    /// Only print the paths the action would create or delete, one per line
+    #[arg(long)]
+    affected_files: bool,
+
+    /// Write the results to this file instead of stdout: the unused packages, or the paths
+    /// the action changed, or the document of a structured format. Also the outputs of
+    /// `list-markers`, `--distribution`, `--emit-colcon-args`, `--output-template` and
+    /// `--affected-files`
+    #[arg(value_name = "PATH", short, long)]
+    output: Option<PathBuf>,
+
+    /// With --affected-files, list every file inside removed packages instead of their folders
+    #[arg(long, requires = "affected_files")]
+    expand: bool,
+}
+
+/// Fill in the options not given on the command line from `config`.
+fn apply_config(args: &mut Args, matches: &ArgMatches, config: Config) {
+    let from_cli = |id| matches.value_source(id) == Some(ValueSource::CommandLine);
+    if !from_cli("dep_type") && !args.runtime && !config.dep_type.is_empty() {
+        args.dep_type = config.dep_type;
+    }
+    if let (false, Some(action)) = (from_cli("action"), config.action) {
+        args.action = action;
+    }
+    if !from_cli("exclude") && !config.exclude.is_empty() {
+        args.exclude = config.exclude;
+    }
+    if !from_cli("ignore_marker") && !config.ignore_markers.is_empty() {
+        args.ignore_marker = config.ignore_markers;
+    }
+}
+
+/// A spinner counting the directories searched below `what`, if `enabled`. Cleared when
+/// dropped, also when the search fails.
+fn scan_spinner(enabled: bool, what: &str) -> Option<ProgressBar> {
+    enabled.then(|| {
+        let style =
+            ProgressStyle::with_template("{spinner} Searching {prefix}: {pos} directories, {msg}")
+                .expect("Template should be valid");
+        let spinner = ProgressBar::new_spinner()
+            .with_style(style)
+            .with_prefix(what.to_string())
+            .with_finish(ProgressFinish::AndClear);
+        spinner.enable_steady_tick(Duration::from_millis(100));
+        spinner
+    })
+}
+
+/// A bar of the `len` packages an action goes through, hidden unless `enabled`.
+fn action_bar(enabled: bool, len: usize) -> ProgressBar {
+    if !enabled {
+        return ProgressBar::hidden();
+    }
+    let style = ProgressStyle::with_template("{bar:40} {pos}/{len} packages")
+        .expect("Template should be valid");
+    ProgressBar::new(len as u64)
+        .with_style(style)
+        .with_finish(ProgressFinish::AndClear)
+}
+
+/// Write `contents` to `output`, or to stdout without one.
+fn write_output(output: Option<&Path>, contents: &str) -> Result<()> {
+    match output {
+        Some(path) => write_atomically(path, contents),
+        None => {
+            print!("{contents}");
+            Ok(())
+        }
+    }
+}
+
+/// How the upstream is searched: like the workspaces, except that packages with the marker
+/// of `action` are still found. A second run then reports them as already ignored, and keeps
+/// what they depend on, rather than losing track of them.
+fn upstream_options(options: &ParseOptions, action: &Action) -> ParseOptions {
+    let mut res = options.clone();
+    res.ignore_markers
+        .retain(|m| Some(m.as_str()) != action.marker());
+    res
+}
+
+/// Place `marker` in each of the `unused` packages, listing them on `out`. Markers that
+/// already exist are skipped. The created markers are added to `affected` and counted in
+/// `processed`.
+#[allow(clippy::too_many_arguments)]
+fn place_markers(
+    args: &Args,
+    marker: &str,
+    unused: &[Package],
+    display: &DisplayOptions,
+    bar: &ProgressBar,
+    out: &mut impl io::Write,
+    affected: &mut Vec<String>,
+    processed: &mut usize,
+) -> Result<()> {
+    let text = args.format == OutputFormat::Text && !args.quiet;
+    let prefix = if args.dry_run { "[dry-run] " } else { "" };
+    for pkg in unused {
+        bar.inc(1);
+        let p = pkg.path.join(marker);
+        let created = if args.dry_run {
+            !p.exists()
+        } else {
+            create_marker(&p)?
+        };
+        if !created && args.only_new {
+            continue;
+        }
+        if text || args.dry_run {
+            let what = if created {
+                "Creating"
+            } else {
+                "Skipping (already ignored)"
+            };
+            bar.suspend(|| writeln!(out, "{prefix}{what} '{}'", display.path(&p).display()))?;
+        }
+        if created {
+            affected.push(display.path(&p).display().to_string());
+        }
+        *processed += usize::from(created);
+    }
+    bar.finish_and_clear();
+    Ok(())
+}
+
+/// Remove the `approved` packages, or move them into `--trash`, then delete the parents left
+/// empty below `upstream` with `--remove-empty-dirs`. Refuses to remove `upstream` itself.
+/// The changed paths are added to `affected` and counted in `processed` as they go, so they
+/// are complete up to a failure.
+fn remove_packages(
+    args: &Args,
+    upstream: &Path,
+    approved: &[Package],
+    display: &DisplayOptions,
+    bar: &ProgressBar,
+    affected: &mut Vec<String>,
+    processed: &mut usize,
+) -> Result<()> {
+    check_root_kept(&Action::Remove, upstream, approved)?;
+    let text = args.format == OutputFormat::Text && !args.quiet;
+    let prefix = if args.dry_run { "[dry-run] " } else { "" };
+    for unused in approved.iter() {
+        bar.inc(1);
+        if let Some(trash) = &args.trash {
+            let dest = if args.dry_run {
+                trash_destination(&unused.path, trash)?
+            } else {
+                move_to_trash(&unused.path, trash)?
+            };
+            if text || args.dry_run {
+                bar.suspend(|| {
+                    println!(
+                        "{prefix}mv '{}' '{}'",
+                        display.path(&unused.path).display(),
+                        display.path(&dest).display()
+                    )
+                });
+            }
+            affected.push(display.path(&unused.path).display().to_string());
+            *processed += 1;
+            continue;
+        }
+        if text || args.dry_run {
+            bar.suspend(|| println!("{prefix}rm -r '{}'", display.path(&unused.path).display()));
+        }
+        if !args.dry_run {
+            fs::remove_dir_all(&unused.path)?;
+        }
+        affected.push(display.path(&unused.path).display().to_string());
+        *processed += 1;
+    }
+    bar.finish_and_clear();
+    if args.remove_empty_dirs {
+        let dirs = if args.dry_run {
+            empty_parents(upstream, approved)?
+        } else {
+            remove_empty_parents(upstream, approved)?
+        };
+        for dir in dirs {
+            if text || args.dry_run {
+                println!("{prefix}rmdir '{}'", display.path(&dir).display());
+            }
+            affected.push(display.path(&dir).display().to_string());
+        }
+    }
+    Ok(())
 }
 
-fn main() -> anyhow::Result<()> {
-    let args = Args::parse();
-    let mut ws_paths: Vec<PathBuf> = Vec::new();
-    if args.workspace.is_empty() && args.package.is_empty() {
+fn main() -> anyhow::Result<ExitCode> {
+    let matches = Args::command().get_matches();
+    let mut args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    let config = match &args.config {
+        Some(path) => Some(path.clone()),
+        None => Config::discover(&std::env::current_dir()?),
+    };
+    if let Some(path) = config {
+        apply_config(&mut args, &matches, Config::load(&path)?);
+    }
+    if let Some(file) = &args.packages_from {
+        args.package.extend(read_patterns(file)?);
+    }
+    env_logger::Builder::new()
+        .filter_level(match args.verbose {
+            0 => log::LevelFilter::Warn,
+            1 => log::LevelFilter::Debug,
+            _ => log::LevelFilter::Trace,
+        })
+        .parse_default_env()
+        .init();
+    if args.json_schema {
+        println!("{}", serde_json::to_string_pretty(&json_schema())?);
+        return Ok(ExitCode::SUCCESS);
+    }
+    if let Some(n) = args.self_benchmark {
+        let ws = synthetic::workspace(n);
+        let upstream = synthetic::upstream(n);
+        let start = Instant::now();
+        let unused = find_unused_pkgs(&ws, &upstream, &Dependency::all);
+        let elapsed = start.elapsed();
+        println!(
+            "Analyzed {} packages ({} unused) in {:.3} ms ({:.0} packages/s)",
+            n,
+            unused.len(),
+            elapsed.as_secs_f64() * 1000.0,
+            n as f64 / elapsed.as_secs_f64()
+        );
+        return Ok(ExitCode::SUCCESS);
+    }
+    let upstream = args.upstream.clone().context("No upstream given")?;
+    let mut parse_options = ParseOptions {
+        lenient_structure: args.lenient_structure,
+        manifest_subpath: args.manifest_subpath.clone().unwrap_or_default(),
+        deadline: args.timeout.map(|timeout| Instant::now() + timeout),
+        include_dotdirs: args.include_dotdirs,
+        skip_dirs: args.skip_dir.clone(),
+        legacy_manifest: args.legacy_manifest,
+        follow_symlinks: args.follow_symlinks,
+        max_manifest_bytes: args.max_manifest_bytes,
+        skip_invalid: args.skip_invalid,
+        ignore_markers: if args.ignore_marker.is_empty() {
+            IGNORE_MARKERS.iter().map(|m| m.to_string()).collect()
+        } else {
+            args.ignore_marker.clone()
+        },
+        max_depth: args.max_depth,
+        min_depth: args.min_depth,
+        ..Default::default()
+    };
+    if let Some(file) = &args.cache {
+        parse_options.cache = Some(Arc::new(ManifestCache::load(file, &parse_options)?));
+    }
+    let marker = args.action.marker();
+    let upstream_options = upstream_options(&parse_options, &args.action);
+    let display = DisplayOptions {
+        prefix_map: args.prefix_map.clone(),
+        show_version: args.show_version,
+        show_maintainers: args.show_maintainers,
+    };
+    let text = args.format == OutputFormat::Text && !args.quiet;
+    let progress = show_progress(io::stdout().is_terminal(), args.quiet, &args.format);
+    let upstream_path = upstream
+        .canonicalize()
+        .with_context(|| format!("Could not check upstream path '{}'", upstream.display()))?;
+    if args.action == Action::ListMarkers {
+        let markers = find_markers(&upstream_path)?;
+        let mut contents = String::new();
+        for (path, marker) in markers.iter() {
+            contents += &format!("{} '{}'\n", marker, display.path(path).display());
+        }
+        contents += "\n";
+        for marker in IGNORE_MARKERS {
+            let count = markers.iter().filter(|(_, m)| *m == marker).count();
+            contents += &format!("{marker}: {count}\n");
+        }
+        write_output(args.output.as_deref(), &contents)?;
+        return Ok(ExitCode::SUCCESS);
+    }
+    if args.workspace.is_empty() && args.package.is_empty() && text {
         println!(
             "Removing packages not used by '.' from upsream workspace '{}'",
-            args.upstream.display(),
+            upstream.display(),
         );
-        let default_path = PathBuf::from(".")
-            .canonicalize()
-            .with_context(|| "Invalid workspace: could not canonicalize path!")?;
-        ws_paths.push(default_path);
-    } else if !args.workspace.is_empty() {
-        // TODO: OK to leak full paths here?
-        ws_paths = args
-            .workspace
+    }
+    // TODO: OK to leak full paths here?
+    let mut ws_args = Vec::new();
+    for w in workspace_roots(&args.workspace, !args.package.is_empty()).iter() {
+        if w.as_os_str() == "-" {
+            ws_args.extend(read_path_list(io::stdin().lock())?);
+        } else if w.extension().is_some_and(|e| e == "repos") && w.is_file() {
+            ws_args.extend(repos_paths(w)?);
+        } else {
+            ws_args.push(w.clone());
+        }
+    }
+    let upstream_spinner = scan_spinner(progress, "upstream");
+    let ws_spinner = scan_spinner(progress && !ws_args.is_empty(), "workspaces");
+    let analysis_options = AnalysisOptions {
+        upstream: ParseOptions {
+            progress: upstream_spinner.clone(),
+            ..upstream_options
+        },
+        workspace: ParseOptions {
+            progress: ws_spinner.clone(),
+            ..parse_options.clone()
+        },
+        packages: args.package.clone(),
+        package_roots: match &args.compile_commands {
+            Some(file) => compile_commands_roots(file, &parse_options)?,
+            None => Vec::new(),
+        },
+        treat_as_workspace: args.treat_as_workspace.clone(),
+        workspace_exclude: args.workspace_exclude.clone(),
+        // Comparing against a distribution only needs the upstream
+        require_workspace: args.distribution.is_none(),
+        ..Default::default()
+    };
+    let analysis = analyze_with(
+        std::slice::from_ref(&upstream_path),
+        &ws_args,
+        &analysis_options,
+    );
+    for spinner in [upstream_spinner, ws_spinner].into_iter().flatten() {
+        spinner.finish_and_clear();
+    }
+    let Analysis {
+        workspace: mut ws_pkgs,
+        upstream: mut upstream_pks,
+        workspace_paths: ws_paths,
+        invalid_workspaces,
+        invalid_manifests,
+        excluded,
+        ..
+    } = analysis?;
+    for (path, e) in invalid_workspaces.iter() {
+        eprintln!(
+            "Warning: could not normalize workspace '{}': {}",
+            path.display(),
+            e
+        );
+    }
+    if args.strict && !invalid_workspaces.is_empty() {
+        return Err(anyhow!(
+            "{} workspaces are invalid",
+            invalid_workspaces.len()
+        ));
+    }
+    if let (Some(file), Some(cache)) = (&args.cache, &parse_options.cache) {
+        cache.save(file)?;
+        if args.cache_stats {
+            eprintln!("{}", cache.stats());
+        }
+    }
+
+    let duplicates = duplicate_names(&upstream_pks);
+    for (name, paths) in duplicates.iter() {
+        let paths: Vec<String> = paths
             .iter()
-            .map(|x| x.canonicalize())
-            .collect::<io::Result<Vec<PathBuf>>>()
-            .with_context(|| "Could not normalize workspaces")?;
-        ws_paths.sort();
-        ws_paths.dedup();
-    }
-    let upstream_path = args.upstream.canonicalize().with_context(|| {
-        format!(
-            "Could not check upstream path '{}'",
-            args.upstream.display()
-        )
-    })?;
+            .map(|p| format!("'{}'", display.path(p).display()))
+            .collect();
+        eprintln!(
+            "Warning: upstream package '{name}' found at {}",
+            paths.join(", ")
+        );
+    }
+    if (args.strict || args.strict_duplicates) && !duplicates.is_empty() {
+        return Err(anyhow!(
+            "{} upstream package names are used more than once",
+            duplicates.len()
+        ));
+    }
+    if let Some(file) = &args.distribution {
+        let expected = distribution_packages(file)?;
+        let (missing, extra) = distribution_diff(&expected, &upstream_pks);
+        let mut contents = String::from("Missing from the upstream:\n");
+        for name in missing.iter() {
+            contents += &format!("{name}\n");
+        }
+        contents += "\nNot in the distribution:\n";
+        for p in extra.iter() {
+            contents += &format!("{}\n", display_package(&display, p));
+        }
+        write_output(args.output.as_deref(), &contents)?;
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    if !invalid_manifests.is_empty() {
+        eprintln!(
+            "Warning: skipped {} manifest(s) that failed to parse",
+            invalid_manifests.len()
+        );
+        if args.strict {
+            return Err(anyhow!(
+                "{} manifests failed to parse",
+                invalid_manifests.len()
+            ));
+        }
+    }
+    if !args.workspace_exclude.is_empty() && text {
+        println!("Excluded workspace packages:");
+        for p in excluded {
+            println!("{}", display_package(&display, &p));
+        }
+    }
 
-    let mut upstream_pks =
-        find(&upstream_path).context("Could not enumerate upstream workspace")?;
-    upstream_pks.sort_unstable_by(|a, b| a.name.cmp(&b.name).then(a.path.cmp(&b.path)));
-    upstream_pks.dedup_by(|a, b| a.name.eq(&b.name) && a.path.eq(&b.path));
+    if args.deps_source != DepsSource::Manifest {
+        let prefix = args
+            .index_prefix
+            .as_ref()
+            .context("No --index-prefix given")?;
+        let index = ament_index_deps(prefix)?;
+        apply_deps_source(&mut ws_pkgs, args.deps_source, &index, DepType::Exec);
+        apply_deps_source(&mut upstream_pks, args.deps_source, &index, DepType::Exec);
+    }
 
-    let mut ws_pkgs: Vec<Package> = ws_paths
-        .iter()
-        .map(|x| find(x).context("Could not enumerate workspace"))
-        .try_collect::<Vec<Vec<Package>>>()?
+    let mut assumed = assumed_deps_from_env()?;
+    if let Some(file) = &args.assume_deps {
+        assumed.extend(assumed_deps_from_file(file)?);
+    }
+    assume_deps(&mut ws_pkgs, &assumed);
+    assume_deps(&mut upstream_pks, &assumed);
+    if args.case_insensitive_names {
+        let known: Vec<Package> = ws_pkgs.iter().chain(upstream_pks.iter()).cloned().collect();
+        fold_name_case(&mut ws_pkgs, &known);
+        fold_name_case(&mut upstream_pks, &known);
+    }
+    if args.respect_replace_conflict {
+        let providers = upstream_pks.clone();
+        resolve_replacements(&mut ws_pkgs, &providers);
+        resolve_replacements(&mut upstream_pks, &providers);
+    }
+
+    let looping: Vec<&Package> = self_dependent(&ws_pkgs)
         .into_iter()
-        .flatten()
+        .chain(self_dependent(&upstream_pks))
         .collect();
-    ws_pkgs.sort_unstable_by(|a, b| a.name.cmp(&b.name).then(a.path.cmp(&b.path)));
-    ws_pkgs.dedup_by(|a, b| a.name.eq(&b.name) && a.path.eq(&b.path));
+    for p in looping.iter() {
+        eprintln!(
+            "Warning: '{}' depends on itself in '{}'",
+            p.name,
+            display
+                .path(
+                    &p.path
+                        .join(&parse_options.manifest_subpath)
+                        .join("package.xml")
+                )
+                .display()
+        );
+    }
+    if args.strict && !looping.is_empty() {
+        return Err(anyhow!("{} packages depend on themselves", looping.len()));
+    }
 
-    if !args.package.is_empty() {
-        for p in upstream_pks.iter() {
-            if args.package.iter().any(|package| p.name == *package) {
-                ws_pkgs.push(p.clone());
-            }
+    for p in ws_pkgs.iter().chain(upstream_pks.iter()) {
+        if p.warn_empty_deps() {
+            eprintln!(
+                "Warning: '{}' has {} empty dependency tag(s)",
+                p.name, p.empty_deps
+            );
         }
     }
 
-    if ws_pkgs.is_empty() {
-        let mut ws_str = String::from("<none>");
-        {
-            let mut ws_iter = ws_paths.iter();
-            if let Some(w) = ws_iter.next() {
-                ws_str = w.to_string_lossy().to_string();
-            }
-            for w in ws_iter {
-                ws_str.push_str(", ");
-                ws_str.push_str(&w.to_string_lossy());
+    if args.validate {
+        let mut invalid = 0;
+        for p in ws_pkgs.iter().chain(upstream_pks.iter()) {
+            match validate(p, &parse_options) {
+                Err(e) => {
+                    eprintln!("Warning: {:#}", e);
+                    invalid += 1;
+                }
+                Result::Ok(false) => {
+                    eprintln!("Not validating the legacy manifest of '{}'", p.name)
+                }
+                Result::Ok(true) => {}
             }
         }
-        let mut pkg_str = "<none>";
-        {
-            let mut pkg_iter = args.package.iter();
-            if let Some(p) = pkg_iter.next() {
-                pkg_str = p;
-            }
-            for p in pkg_iter {
-                ws_str.push_str(", ");
-                ws_str.push_str(p);
-            }
+        if args.strict && invalid > 0 {
+            return Err(anyhow!("{} manifests failed validation", invalid));
         }
-        return Err(anyhow!("The filtered workspace is empty! This would remove all packages. Check your command line!\nRequested workspace: {}\nRequested packages: {}", ws_str, pkg_str));
     }
 
-    let need_filter = !args.dep_type.is_empty();
+    let need_filter = !args.dep_type.is_empty() || !args.exclude_type.is_empty();
+    let type_names = |types: &[DepType]| {
+        types
+            .iter()
+            .filter_map(|t| t.to_possible_value())
+            .map(|v| v.get_name().to_string())
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    let mut filter_types = if args.runtime {
+        "exec, build-export".to_string()
+    } else if !args.dep_type.is_empty() {
+        type_names(&args.dep_type)
+    } else {
+        "all".to_string()
+    };
+    if !args.exclude_type.is_empty() {
+        filter_types = format!("{filter_types} except {}", type_names(&args.exclude_type));
+    }
     // TODO: capture an iterator rather than moving the vector in?
-    let match_specified = Dependency::matcher(args.dep_type);
-    let mut filtered = find_unused_pkgs(
-        &ws_pkgs,
+    let match_specified =
+        Dependency::matcher_excluding(args.dep_type.clone(), args.exclude_type.clone());
+    let filter: &DepFilter = if args.runtime {
+        &Dependency::runtime
+    } else if need_filter {
+        &match_specified
+    } else {
+        &Dependency::all
+    };
+
+    if args.report_cycles_as_error {
+        let all_pkgs: Vec<Package> = ws_pkgs.iter().chain(upstream_pks.iter()).cloned().collect();
+        check_cycles(&dependency_cycles(&all_pkgs, filter))?;
+    }
+
+    if let Some(target) = &args.reverse {
+        let all_pkgs: Vec<Package> = ws_pkgs.iter().chain(upstream_pks.iter()).cloned().collect();
+        let chains = reverse_deps(&all_pkgs, target, filter);
+        if chains.is_empty() {
+            println!("Nothing depends on '{target}'");
+        }
+        for chain in chains {
+            println!("{}", chain.join(" -> "));
+        }
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    if let Some(max) = args.max_closure_size {
+        let oversized = oversized_closures(&ws_pkgs, &upstream_pks, filter, max);
+        for (root, size) in oversized.iter() {
+            eprintln!(
+                "Warning: '{}' transitively depends on {} packages (more than {})",
+                root.name, size, max
+            );
+        }
+        if args.strict && !oversized.is_empty() {
+            return Err(anyhow!("Dependency closures exceed --max-closure-size"));
+        }
+    }
+
+    let transitive_filter: &DepFilter = if args.runtime_closure {
+        &Dependency::runtime
+    } else {
+        filter
+    };
+    // Packages whose manifest opts out of cleanup are kept, with their dependencies
+    let mut roots = cleanup_roots(&ws_pkgs, &upstream_pks);
+    let (excluded, missing) = named_pkgs(&upstream_pks, &args.exclude);
+    for name in missing.iter() {
+        eprintln!("Warning: --exclude '{name}' matches no upstream package");
+    }
+    if args.strict && !missing.is_empty() {
+        return Err(anyhow!("Not all excluded packages were found"));
+    }
+    roots.extend(excluded.into_iter().cloned());
+    if args.protect_sibling_namespace {
+        let protected = sibling_namespace_pkgs(&ws_pkgs, &upstream_pks, &args.namespace_separator);
+        if text {
+            println!("Protected by namespace:");
+            for p in protected.iter() {
+                println!("{}", display_package(&display, p));
+            }
+        }
+        roots.extend(protected.into_iter().cloned());
+    }
+    let mut filtered = find_unused_pkgs_within(
+        &roots,
         &upstream_pks,
-        if need_filter {
-            &match_specified
-        } else {
-            &Dependency::all
-        },
+        filter,
+        transitive_filter,
+        args.max_transitive_depth,
     );
-    filtered.sort_unstable_by(|a, b| a.name.cmp(&b.name).then(a.path.cmp(&b.path)));
-    println!("Workspace packages:");
-    for ws_pkg in ws_pkgs {
-        println!("{}", ws_pkg);
+    let mut unused_by_workspace = Vec::new();
+    if args.unused_mode == UnusedMode::PerWorkspace || args.require_all_workspaces {
+        let groups = workspace_groups(&roots, &ws_paths);
+        if args.require_all_workspaces {
+            filtered = find_unused_by_any(
+                &groups,
+                &upstream_pks,
+                filter,
+                transitive_filter,
+                args.max_transitive_depth,
+            );
+        } else {
+            let (per_workspace, intersection) = find_unused_per_workspace(
+                &groups,
+                &upstream_pks,
+                filter,
+                transitive_filter,
+                args.max_transitive_depth,
+            );
+            for (w, mut unused) in ws_paths.iter().zip(per_workspace) {
+                dedup_packages(&mut unused);
+                unused_by_workspace.push((w, unused));
+            }
+            filtered = intersection;
+        }
+    }
+    dedup_packages(&mut filtered);
+    if args.respect_replace_conflict {
+        let mut present = ws_pkgs.clone();
+        present.extend(kept_pkgs(&upstream_pks, &filtered));
+        dedup_packages(&mut present);
+        let conflicts = conflicting_pkgs(&present);
+        for (p, other) in conflicts.iter() {
+            eprintln!(
+                "Warning: kept package '{}' conflicts with '{}'",
+                p.name, other.name
+            );
+        }
+        if args.strict && !conflicts.is_empty() {
+            return Err(anyhow!("Kept packages conflict with each other"));
+        }
+    }
+
+    if args.check_version_conflicts {
+        let groups: Vec<Vec<Package>> = ws_paths
+            .iter()
+            .map(|w| {
+                ws_pkgs
+                    .iter()
+                    .filter(|p| p.path.starts_with(w))
+                    .cloned()
+                    .collect()
+            })
+            .collect();
+        let conflicts = version_conflicts(&groups, &upstream_pks);
+        for conflict in conflicts.iter() {
+            let required: Vec<String> = conflict
+                .constraints
+                .iter()
+                .map(|(_, p, c)| format!("{} requires {}", p.name, c))
+                .collect();
+            eprintln!(
+                "Warning: '{}' {} can't satisfy all workspaces: {}",
+                conflict.package.name,
+                conflict.package.version.as_deref().unwrap_or_default(),
+                required.join(", ")
+            );
+        }
+        if args.strict && !conflicts.is_empty() {
+            return Err(anyhow!("Workspaces require conflicting versions"));
+        }
     }
-    println!("\nUpstream packages:");
-    for us_pkg in upstream_pks {
-        println!("{}", us_pkg);
+
+    if args.check_layering {
+        let kept = kept_pkgs(&upstream_pks, &filtered);
+        let violations = layering_violations(&ws_pkgs, &kept, filter);
+        for (p, deps) in violations.iter() {
+            eprintln!(
+                "Warning: upstream package '{}' depends on workspace package(s) {} (layering violation)",
+                p.name,
+                deps.join(", ")
+            );
+        }
+        if args.strict && !violations.is_empty() {
+            return Err(anyhow!("Upstream packages depend on the workspace"));
+        }
     }
 
-    match args.action {
-        Action::Print => {
-            println!("\nUnused:");
-            for unused in filtered {
-                println!("{}", unused);
+    if args.report_missing {
+        let allowed = match &args.missing_allowlist {
+            Some(file) => read_patterns(file)?,
+            None => Vec::new(),
+        };
+        let mut kept = ws_pkgs.clone();
+        kept.extend(kept_pkgs(&upstream_pks, &filtered));
+        let mut known = ws_pkgs.clone();
+        known.extend(upstream_pks.iter().cloned());
+        let missing = missing_deps(&kept, &known, filter, &allowed);
+        for (p, deps) in missing.iter() {
+            eprintln!(
+                "Warning: '{}' depends on {}, found nowhere",
+                p.name,
+                deps.join(", ")
+            );
+        }
+        if args.strict && !missing.is_empty() {
+            return Err(anyhow!(
+                "{} packages have missing dependencies",
+                missing.len()
+            ));
+        }
+    }
+
+    // What the action did is filled in once it ran
+    let mut summary = Summary {
+        workspace: ws_pkgs.len(),
+        upstream: upstream_pks.len(),
+        unused: filtered.len(),
+        processed: None,
+    };
+    let status = ExitCode::from(summary.exit_code(args.fail_on_unused));
+    // The outputs below are meant for other tools, so the summary goes to stderr
+    if let Some(kind) = &args.emit_colcon_args {
+        let packages = match kind {
+            ColconArgs::Select => kept_pkgs(&upstream_pks, &filtered),
+            ColconArgs::Skip => filtered,
+        };
+        let contents = format!("{}\n", colcon_args(kind, &packages));
+        write_output(args.output.as_deref(), &contents)?;
+        eprintln!("{summary}");
+        return Ok(status);
+    }
+    if let Some(template) = &args.output_template {
+        let contents: String = filtered
+            .iter()
+            .map(|p| format!("{}\n", template.render(&display, p, &upstream_path)))
+            .collect();
+        write_output(args.output.as_deref(), &contents)?;
+        eprintln!("{summary}");
+        return Ok(status);
+    }
+    if args.affected_files {
+        let prune_below = args.remove_empty_dirs.then_some(upstream_path.as_path());
+        let contents: String = affected_paths(&args.action, &filtered, args.expand, prune_below)?
+            .iter()
+            .map(|p| format!("{}\n", display.path(p).display()))
+            .collect();
+        write_output(args.output.as_deref(), &contents)?;
+        eprintln!("{summary}");
+        return Ok(status);
+    }
+    let used =
+        (args.action == Action::PrintUsed).then(|| used_pkgs(&ws_pkgs, &upstream_pks, &filtered));
+    match args.format {
+        OutputFormat::Text if args.quiet => {}
+        OutputFormat::Text => {
+            println!("Workspace packages:");
+            for ws_pkg in ws_pkgs.iter() {
+                println!("{}", display_package(&display, ws_pkg));
+            }
+            println!("\nUpstream packages:");
+            for us_pkg in upstream_pks.iter() {
+                println!("{}", display_package(&display, us_pkg));
+            }
+            for (w, unused) in unused_by_workspace.iter() {
+                println!("\nUnused by '{}':", display.path(w).display());
+                for p in unused.iter() {
+                    println!("{}", display_package(&display, p));
+                }
             }
         }
-        Action::ColconIgnore => {
-            println!("\nSetting up colcon ignore for:");
-            for unused in filtered {
-                let mut p = unused.path.clone();
-                p.push("COLCON_IGNORE");
-                println!("Creating '{}'", p.display());
-                touch(&p)?;
+        OutputFormat::Json | OutputFormat::Ndjson | OutputFormat::Dot => {
+            let doc = match args.format {
+                OutputFormat::Json => {
+                    let mut doc = AnalysisJson::new(&display, &ws_pkgs, &upstream_pks, &filtered);
+                    if let Some(used) = &used {
+                        doc = doc.with_used(&display, used);
+                    }
+                    serde_json::to_string_pretty(&doc)? + "\n"
+                }
+                OutputFormat::Ndjson => to_ndjson(&display, used.as_ref().unwrap_or(&filtered)),
+                _ => to_dot(&ws_pkgs, &upstream_pks, &filtered, filter, args.stable_ids),
+            };
+            write_output(args.output.as_deref(), &doc)?;
+        }
+    }
+
+    if args.explain_removal_safety {
+        if text {
+            println!("\nRemoval safety:");
+        }
+        let mut unsafe_removals = 0;
+        for safety in removal_safety(&upstream_pks, &filtered, filter) {
+            let name = &safety.package.name;
+            let count = safety.dependents.len();
+            if safety.is_safe() {
+                if text {
+                    println!(
+                    "{name} is unused because no workspace root reaches it under filter {filter_types}; it has {count} upstream dependents, all of which are also being removed."
+                    );
+                }
+            } else {
+                unsafe_removals += 1;
+                let kept: Vec<&str> = safety
+                    .kept_dependents
+                    .iter()
+                    .map(|p| p.name.as_str())
+                    .collect();
+                eprintln!(
+                    "Warning: {name} has {count} upstream dependents, but {} would be kept",
+                    kept.join(", ")
+                );
             }
         }
-        Action::CatkinIgnore => {
-            println!("\nSetting up catkin ignore for:");
-            for unused in filtered {
-                let mut p = unused.path.clone();
-                p.push("CATKIN_IGNORE");
-                println!("Creating '{}'", p.display());
-                touch(&p)?;
+        if args.strict && unsafe_removals > 0 {
+            return Err(anyhow!(
+                "{} unused packages are depended on by kept packages",
+                unsafe_removals
+            ));
+        }
+    }
+
+    if args.explain && text {
+        println!("\nKept:");
+        for chain in kept_chains(
+            &roots,
+            &upstream_pks,
+            filter,
+            transitive_filter,
+            args.max_transitive_depth,
+        ) {
+            println!("kept {}", chain.join(" <- "));
+        }
+    }
+
+    check_root_kept(&args.action, &upstream_path, &filtered)?;
+    if let (true, Some(marker)) = (args.dry_run, marker) {
+        let diff = marker_diff(marker, &upstream_pks, &filtered)?;
+        println!("\nWould change:");
+        for p in diff.create.iter() {
+            println!(
+                "+ create {} in '{}'",
+                marker,
+                display.path(p.parent().unwrap()).display()
+            );
+        }
+        for p in diff.present.iter() {
+            println!(
+                "= already present in '{}'",
+                display.path(p.parent().unwrap()).display()
+            );
+        }
+        for p in diff.stale.iter() {
+            println!(
+                "- stale marker in '{}' (now used)",
+                display.path(p.parent().unwrap()).display()
+            );
+        }
+    }
+    let prefix = if args.dry_run { "[dry-run] " } else { "" };
+    let mut processed = 0;
+    // The paths changed by the action, or the unused packages, for --output
+    let mut affected = Vec::new();
+    let result = (|| -> Result<()> {
+        if args.interactive {
+            if !io::stdin().is_terminal() {
+                return Err(anyhow!("--interactive needs an interactive terminal"));
             }
+            let marker = marker.unwrap_or("COLCON_IGNORE");
+            let safety = removal_safety(&upstream_pks, &filtered, filter);
+            let decisions = decide_each(
+                &safety,
+                Decision::default_for(&args.action),
+                &display,
+                &mut io::stdin().lock(),
+                &mut io::stdout(),
+            )?;
+            // Removed together after the markers are placed, to prune emptied parents once.
+            // Checked first so a refused removal doesn't leave the markers half placed.
+            let removals: Vec<Package> = decisions
+                .iter()
+                .filter(|(_, decision)| *decision == Decision::Remove)
+                .map(|(unused, _)| (*unused).clone())
+                .collect();
+            check_root_kept(&Action::Remove, &upstream_path, &removals)?;
+            for (unused, decision) in decisions {
+                match decision {
+                    Decision::Remove => continue,
+                    Decision::Ignore => {
+                        let p = unused.path.join(marker);
+                        let created = if args.dry_run {
+                            !p.exists()
+                        } else {
+                            create_marker(&p)?
+                        };
+                        let what = if created {
+                            "Creating"
+                        } else {
+                            "Skipping (already ignored)"
+                        };
+                        println!("{prefix}{what} '{}'", display.path(&p).display());
+                        if !created {
+                            continue;
+                        }
+                        affected.push(display.path(&p).display().to_string());
+                    }
+                    Decision::Skip => continue,
+                }
+                processed += 1;
+            }
+            return remove_packages(
+                &args,
+                &upstream_path,
+                &removals,
+                &display,
+                &ProgressBar::hidden(),
+                &mut affected,
+                &mut processed,
+            );
         }
-        Action::Remove => {
-            println!("\nRemoving:");
-            for unused in filtered {
-                println!("rm -r '{}'", unused.path.display());
-                fs::remove_dir_all(unused.path)?;
+        match args.action {
+            Action::ListMarkers => unreachable!("Handled before the analysis"),
+            Action::Print => {
+                if args.output.is_some() {
+                    affected.extend(filtered.iter().map(|p| display_package(&display, p)));
+                } else if text {
+                    println!("\nUnused:");
+                    for unused in filtered {
+                        println!("{}", display_package(&display, &unused));
+                    }
+                }
+            }
+            Action::PrintUsed => {
+                let used = used.unwrap_or_default();
+                if args.output.is_some() {
+                    affected.extend(used.iter().map(|p| display_package(&display, p)));
+                } else if text {
+                    println!("\nUsed:");
+                    for p in used.iter() {
+                        println!("{}", display_package(&display, p));
+                    }
+                }
+            }
+            Action::ColconIgnore | Action::CatkinIgnore => {
+                let marker = marker.expect("Ignore actions place a marker");
+                if text {
+                    // E.g. "colcon ignore" for COLCON_IGNORE
+                    let what = marker.to_lowercase().replace('_', " ");
+                    println!("\nSetting up {what} for:");
+                }
+                let bar = action_bar(progress, filtered.len());
+                place_markers(
+                    &args,
+                    marker,
+                    &filtered,
+                    &display,
+                    &bar,
+                    &mut io::stdout(),
+                    &mut affected,
+                    &mut processed,
+                )?;
+            }
+            Action::Remove => {
+                if text {
+                    println!("\nRemoving:");
+                }
+                let approved: Vec<Package> = if args.confirm_each && !args.dry_run {
+                    if !io::stdin().is_terminal() {
+                        return Err(anyhow!("--confirm-each needs an interactive terminal"));
+                    }
+                    confirm_each(
+                        &filtered,
+                        &display,
+                        &mut io::stdin().lock(),
+                        &mut io::stdout(),
+                    )?
+                    .into_iter()
+                    .cloned()
+                    .collect()
+                } else {
+                    if !args.yes && !args.dry_run && !filtered.is_empty() {
+                        if !io::stdin().is_terminal() {
+                            return Err(anyhow!(
+                            "Refusing to remove without confirmation, pass --yes when not running interactively"
+                        ));
+                        }
+                        if !confirm_all(
+                            &filtered,
+                            &display,
+                            &mut io::stdin().lock(),
+                            &mut io::stdout(),
+                        )? {
+                            return Err(anyhow!("Aborted, nothing was removed"));
+                        }
+                    }
+                    filtered
+                };
+                let bar = action_bar(progress, approved.len());
+                remove_packages(
+                    &args,
+                    &upstream_path,
+                    &approved,
+                    &display,
+                    &bar,
+                    &mut affected,
+                    &mut processed,
+                )?;
             }
         }
+        Ok(())
+    })();
+    // Structured formats were written above. Also written after a failed action, listing
+    // what was changed until then
+    if let (Some(path), OutputFormat::Text) = (&args.output, &args.format) {
+        let contents: String = affected.iter().map(|line| format!("{line}\n")).collect();
+        write_atomically(path, &contents)?;
     }
 
-    Ok(())
+    // Interactively removed and ignored packages are counted together
+    let verb = match (args.interactive, args.dry_run) {
+        (true, false) => Some("cleaned up"),
+        (true, true) => Some("would clean up"),
+        (false, dry_run) => args.action.summary_verb(dry_run),
+    };
+    summary.processed = verb.map(|verb| (verb, processed));
+    if args.format == OutputFormat::Text {
+        println!("{}{summary}", if text { "\n" } else { "" });
+    } else {
+        eprintln!("{summary}");
+    }
+    result?;
+    Ok(status)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ws_cleaner::parsing::find;
+
+    #[test]
+    fn command_line_overrides_config() {
+        let config = || {
+            Config::parse(
+                r#"
+                dep-type = ["build", "exec"]
+                action = "colcon-ignore"
+                "#,
+            )
+            .unwrap()
+        };
+        let args = |cli: &[&str]| {
+            let matches = Args::command()
+                .try_get_matches_from([&["ws_cleaner", "-u", "up"], cli].concat())
+                .unwrap();
+            let mut args = Args::from_arg_matches(&matches).unwrap();
+            apply_config(&mut args, &matches, config());
+            args
+        };
+
+        let from_config = args(&[]);
+        assert_eq!(from_config.dep_type, [DepType::Build, DepType::Exec]);
+        assert_eq!(from_config.action, Action::ColconIgnore);
+
+        let overridden = args(&["-t", "test", "--action", "print"]);
+        assert_eq!(overridden.dep_type, [DepType::Test]);
+        assert_eq!(overridden.action, Action::Print);
+
+        // The defaults of the command line don't count as given
+        let runtime = args(&["--runtime", "--action", "print"]);
+        assert!(runtime.dep_type.is_empty());
+    }
+
+    #[test]
+    fn fail_on_unused_needs_the_analysis() {
+        let parse = |cli: &[&str]| {
+            Args::try_parse_from([&["ws_cleaner", "-u", "up", "--fail-on-unused"], cli].concat())
+        };
+        assert!(parse(&["--emit-colcon-args", "skip"]).is_ok());
+        for cli in [["--reverse", "a"], ["--distribution", "distribution.yaml"]] {
+            let err = parse(&cli).err().unwrap();
+            assert_eq!(err.exit_code(), 2);
+        }
+    }
+
+    /// A workspace with `robot` depending on `used`, and an upstream with `used` and
+    /// `unused`, returning both directories.
+    fn marker_tree(dir: &Path) -> (PathBuf, PathBuf) {
+        let (ws, up) = (dir.join("ws"), dir.join("up"));
+        for (root, name, deps) in [
+            (&ws, "robot", "<depend>used</depend>"),
+            (&up, "used", ""),
+            (&up, "unused", ""),
+        ] {
+            fs::create_dir_all(root.join(name)).unwrap();
+            let manifest = format!("<package><name>{name}</name>{deps}</package>");
+            fs::write(root.join(name).join("package.xml"), manifest).unwrap();
+        }
+        (ws, up)
+    }
+
+    /// The lines `--action colcon-ignore` prints for its markers, after running the analysis
+    /// like `main` does.
+    fn ignore_run(ws: &Path, up: &Path, cli: &[&str]) -> Vec<String> {
+        let args = Args::try_parse_from(
+            [
+                &[
+                    "ws_cleaner",
+                    "-u",
+                    up.to_str().unwrap(),
+                    "-w",
+                    ws.to_str().unwrap(),
+                ],
+                &["--action", "colcon-ignore"][..],
+                cli,
+            ]
+            .concat(),
+        )
+        .unwrap();
+        let options = AnalysisOptions {
+            upstream: upstream_options(&ParseOptions::default(), &args.action),
+            ..Default::default()
+        };
+        let analysis = analyze_with(&[up.to_path_buf()], &[ws.to_path_buf()], &options).unwrap();
+        let mut out = Vec::new();
+        place_markers(
+            &args,
+            args.action.marker().unwrap(),
+            &analysis.unused,
+            &DisplayOptions::default(),
+            &ProgressBar::hidden(),
+            &mut out,
+            &mut Vec::new(),
+            &mut 0,
+        )
+        .unwrap();
+        String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(str::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn second_ignore_run_skips() {
+        let dir = tempfile::tempdir().unwrap();
+        let (ws, up) = marker_tree(dir.path());
+        let marker = up
+            .canonicalize()
+            .unwrap()
+            .join("unused")
+            .join("COLCON_IGNORE");
+
+        let first = ignore_run(&ws, &up, &[]);
+        assert_eq!(first, [format!("Creating '{}'", marker.display())]);
+        let second = ignore_run(&ws, &up, &[]);
+        assert_eq!(
+            second,
+            [format!("Skipping (already ignored) '{}'", marker.display())]
+        );
+        assert!(ignore_run(&ws, &up, &["--only-new"]).is_empty());
+    }
+
+    #[test]
+    fn dry_run_logs_match_real_runs() {
+        let dir = tempfile::tempdir().unwrap();
+        let (ws, up) = marker_tree(dir.path());
+        // Once creating the marker, then skipping it
+        for _ in 0..2 {
+            let dry = ignore_run(&ws, &up, &["--dry-run"]);
+            let real = ignore_run(&ws, &up, &[]);
+            let undry: Vec<&str> = dry
+                .iter()
+                .map(|line| line.strip_prefix("[dry-run] ").unwrap())
+                .collect();
+            assert_eq!(undry, real);
+            assert_eq!(real.len(), 1);
+        }
+    }
+
+    #[test]
+    fn dry_run_sees_what_the_run_sees() {
+        let dir = tempfile::tempdir().unwrap();
+        let (ws, up) = marker_tree(dir.path());
+        // robot -> used -> below, with used already ignored
+        fs::write(
+            up.join("used").join("package.xml"),
+            "<package><name>used</name><depend>below</depend></package>",
+        )
+        .unwrap();
+        fs::write(up.join("used").join("COLCON_IGNORE"), "").unwrap();
+        fs::create_dir_all(up.join("below")).unwrap();
+        fs::write(
+            up.join("below").join("package.xml"),
+            "<package><name>below</name></package>",
+        )
+        .unwrap();
+
+        let dry = ignore_run(&ws, &up, &["--dry-run"]);
+        let real = ignore_run(&ws, &up, &[]);
+        let up = up.canonicalize().unwrap();
+        let unused_marker = up.join("unused").join("COLCON_IGNORE");
+        assert_eq!(real, [format!("Creating '{}'", unused_marker.display())]);
+        assert_eq!(dry, [format!("[dry-run] {}", real[0])]);
+
+        let options = AnalysisOptions {
+            upstream: upstream_options(&ParseOptions::default(), &Action::ColconIgnore),
+            ..Default::default()
+        };
+        let analysis = analyze_with(std::slice::from_ref(&up), &[ws], &options).unwrap();
+        let diff = marker_diff("COLCON_IGNORE", &analysis.upstream, &analysis.unused).unwrap();
+        assert_eq!(diff.stale, [up.join("used").join("COLCON_IGNORE")]);
+        assert!(diff.create.is_empty());
+    }
+
+    #[test]
+    fn interactive_removal_keeps_root() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("lone");
+        fs::create_dir(&root).unwrap();
+        fs::write(
+            root.join("package.xml"),
+            "<package><name>lone</name></package>",
+        )
+        .unwrap();
+        let upstream = find(&root).unwrap();
+        let args = Args::try_parse_from([
+            "ws_cleaner",
+            "-u",
+            root.to_str().unwrap(),
+            "--action",
+            "colcon-ignore",
+            "--interactive",
+        ])
+        .unwrap();
+        let safety = removal_safety(&upstream, &upstream, &Dependency::all);
+        let decisions = decide_each(
+            &safety,
+            Decision::default_for(&args.action),
+            &DisplayOptions::default(),
+            &mut "r\n".as_bytes(),
+            &mut Vec::new(),
+        )
+        .unwrap();
+        assert_eq!(decisions, [(&upstream[0], Decision::Remove)]);
+
+        let (mut affected, mut processed) = (Vec::new(), 0);
+        let err = remove_packages(
+            &args,
+            &root,
+            &upstream,
+            &DisplayOptions::default(),
+            &ProgressBar::hidden(),
+            &mut affected,
+            &mut processed,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("upstream root"));
+        assert!(root.join("package.xml").exists());
+        assert_eq!(processed, 0);
+    }
 }
@@ -0,0 +1,623 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{anyhow, Context, Error, Result};
+use clap::ValueEnum;
+use schemars::{schema_for, JsonSchema};
+use serde::{Deserialize, Serialize};
+
+use crate::filtering::Package;
+
+/// Rewrites printed paths starting with `from` to start with `to` instead.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PrefixMap {
+    pub from: PathBuf,
+    pub to: PathBuf,
+}
+
+impl FromStr for PrefixMap {
+    type Err = Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (from, to) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow!("Expected 'FROM=TO', got '{s}'"))?;
+        Ok(PrefixMap {
+            from: from.into(),
+            to: to.into(),
+        })
+    }
+}
+
+/// `path` rewritten by the first matching entry of `maps`, if any.
+pub fn map_path(maps: &[PrefixMap], path: &Path) -> PathBuf {
+    maps.iter()
+        .find_map(|m| path.strip_prefix(&m.from).ok().map(|rest| m.to.join(rest)))
+        .unwrap_or_else(|| path.to_path_buf())
+}
+
+#[derive(ValueEnum, Clone, Debug, PartialEq, Default)]
+pub enum OutputFormat {
+    /// Human readable listings
+    #[default]
+    Text,
+    /// A single JSON document
+    Json,
+    /// One JSON object per unused package and line
+    Ndjson,
+    /// A Graphviz digraph of the packages and their (filtered) dependencies
+    Dot,
+}
+
+/// How packages are rendered in listings.
+#[derive(Clone, Debug, Default)]
+pub struct DisplayOptions {
+    pub prefix_map: Vec<PrefixMap>,
+    pub show_version: bool,
+    pub show_maintainers: bool,
+}
+
+impl DisplayOptions {
+    pub fn path(&self, path: &Path) -> PathBuf {
+        map_path(&self.prefix_map, path)
+    }
+}
+
+/// `package` formatted like its `Display` implementation, with the enabled extras.
+pub fn display_package(options: &DisplayOptions, package: &Package) -> String {
+    let mut res = package.name.clone();
+    if options.show_version {
+        res.push(' ');
+        res.push_str(package.version.as_deref().unwrap_or("?"));
+    }
+    res = format!("{} ({})", res, options.path(&package.path).display());
+    if options.show_maintainers && !package.maintainers.is_empty() {
+        let maintainers: Vec<String> = package.maintainers.iter().map(|m| m.to_string()).collect();
+        res.push_str(" maintained by ");
+        res.push_str(&maintainers.join(", "));
+    }
+    res
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, PartialEq)]
+pub struct MaintainerJson {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, PartialEq)]
+pub struct PackageJson {
+    pub name: String,
+    pub path: PathBuf,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub maintainers: Vec<MaintainerJson>,
+}
+
+impl PackageJson {
+    pub fn new(options: &DisplayOptions, package: &Package) -> Self {
+        PackageJson {
+            name: package.name.clone(),
+            path: options.path(&package.path),
+            version: package.version.clone().filter(|_| options.show_version),
+            maintainers: package
+                .maintainers
+                .iter()
+                .filter(|_| options.show_maintainers)
+                .map(|m| MaintainerJson {
+                    name: m.name.clone(),
+                    email: m.email.clone(),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// The `schema_version` of [`AnalysisJson`], only increased when fields change in ways that
+/// break existing consumers, i.e. not for new optional fields.
+pub const OUTPUT_SCHEMA_VERSION: u32 = 1;
+
+/// The document printed by `--format json`.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, PartialEq)]
+pub struct AnalysisJson {
+    /// [`OUTPUT_SCHEMA_VERSION`] of the tool that wrote the document
+    pub schema_version: u32,
+    pub workspace: Vec<PackageJson>,
+    pub upstream: Vec<PackageJson>,
+    pub unused: Vec<PackageJson>,
+    /// The upstream packages that are needed, with `--action print-used`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub used: Option<Vec<PackageJson>>,
+}
+
+impl AnalysisJson {
+    pub fn new(
+        options: &DisplayOptions,
+        workspace: &[Package],
+        upstream: &[Package],
+        unused: &[Package],
+    ) -> Self {
+        let convert =
+            |pkgs: &[Package]| pkgs.iter().map(|p| PackageJson::new(options, p)).collect();
+        AnalysisJson {
+            schema_version: OUTPUT_SCHEMA_VERSION,
+            workspace: convert(workspace),
+            upstream: convert(upstream),
+            unused: convert(unused),
+            used: None,
+        }
+    }
+
+    /// Also list the `used` upstream packages.
+    pub fn with_used(mut self, options: &DisplayOptions, used: &[Package]) -> Self {
+        self.used = Some(used.iter().map(|p| PackageJson::new(options, p)).collect());
+        self
+    }
+}
+
+/// `packages` as newline-delimited JSON, one object per line.
+pub fn to_ndjson(options: &DisplayOptions, packages: &[Package]) -> String {
+    packages
+        .iter()
+        .map(|p| {
+            let line = serde_json::to_string(&PackageJson::new(options, p));
+            line.expect("Package should serialize") + "\n"
+        })
+        .collect()
+}
+
+/// JSON Schema of the `--format json` output.
+pub fn json_schema() -> serde_json::Value {
+    serde_json::to_value(schema_for!(AnalysisJson)).expect("Schema should serialize")
+}
+
+/// A per-package line format such as `{name}\t{path}`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OutputTemplate {
+    parts: Vec<TemplatePart>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum TemplatePart {
+    Literal(String),
+    Name,
+    Path,
+    RelPath,
+    BuildType,
+    Version,
+    DepCount,
+}
+
+impl FromStr for OutputTemplate {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = Vec::new();
+        let mut rest = s;
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                parts.push(TemplatePart::Literal(rest[..start].to_string()));
+            }
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| anyhow!("Unclosed placeholder in template '{s}'"))?;
+            let placeholder = &rest[start + 1..start + end];
+            parts.push(match placeholder {
+                "name" => TemplatePart::Name,
+                "path" => TemplatePart::Path,
+                "relpath" => TemplatePart::RelPath,
+                "build_type" => TemplatePart::BuildType,
+                "version" => TemplatePart::Version,
+                "dep_count" => TemplatePart::DepCount,
+                _ => {
+                    return Err(anyhow!(
+                        "Unknown placeholder '{{{placeholder}}}' in template"
+                    ))
+                }
+            });
+            rest = &rest[start + end + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(TemplatePart::Literal(rest.to_string()));
+        }
+        Ok(OutputTemplate { parts })
+    }
+}
+
+impl OutputTemplate {
+    /// The line for `package`, with `{relpath}` relative to `root`. Missing values render as
+    /// `?`.
+    pub fn render(&self, options: &DisplayOptions, package: &Package, root: &Path) -> String {
+        let mut res = String::new();
+        for part in self.parts.iter() {
+            match part {
+                TemplatePart::Literal(s) => res.push_str(s),
+                TemplatePart::Name => res.push_str(&package.name),
+                TemplatePart::Path => {
+                    res.push_str(&options.path(&package.path).display().to_string())
+                }
+                TemplatePart::RelPath => {
+                    let rel = package.path.strip_prefix(root).unwrap_or(&package.path);
+                    res.push_str(&rel.display().to_string())
+                }
+                TemplatePart::BuildType => {
+                    res.push_str(package.build_type.as_deref().unwrap_or("?"))
+                }
+                TemplatePart::Version => res.push_str(package.version.as_deref().unwrap_or("?")),
+                TemplatePart::DepCount => res.push_str(&package.deps.len().to_string()),
+            }
+        }
+        res
+    }
+}
+
+#[derive(ValueEnum, Clone, Debug, PartialEq)]
+pub enum ColconArgs {
+    /// `--packages-select` with the packages that are still needed
+    Select,
+    /// `--packages-skip` with the unused packages
+    Skip,
+}
+
+/// Quote `word` for POSIX shells if it contains anything beyond a conservative safe set.
+pub fn shell_quote(word: &str) -> String {
+    let safe = !word.is_empty()
+        && word
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_-+./:=@%".contains(c));
+    if safe {
+        word.to_string()
+    } else {
+        format!("'{}'", word.replace('\'', r"'\''"))
+    }
+}
+
+/// A colcon argument fragment selecting or skipping the given packages.
+pub fn colcon_args(kind: &ColconArgs, packages: &[Package]) -> String {
+    let mut names: Vec<&str> = packages.iter().map(|p| p.name.as_str()).collect();
+    names.sort_unstable();
+    names.dedup();
+    let mut res = String::from(match kind {
+        ColconArgs::Select => "--packages-select",
+        ColconArgs::Skip => "--packages-skip",
+    });
+    for name in names {
+        res.push(' ');
+        res.push_str(&shell_quote(name));
+    }
+    res
+}
+
+/// Replace `path` with `contents` by renaming a temporary file next to it, so readers never
+/// see a partially written file.
+pub fn write_atomically(path: &Path, contents: &str) -> Result<()> {
+    let context = || format!("While writing '{}'", path.display());
+    let name = path.file_name().with_context(context)?;
+    let tmp = path.with_file_name(format!(
+        ".{}.{}.tmp",
+        name.to_string_lossy(),
+        std::process::id()
+    ));
+    fs::write(&tmp, contents).with_context(context)?;
+    fs::rename(&tmp, path).or_else(|e| {
+        let _ = fs::remove_file(&tmp);
+        Err(e).with_context(context)
+    })
+}
+
+/// Whether to draw progress bars: only for text on a terminal, so piped or structured output
+/// stays clean.
+pub fn show_progress(stdout_tty: bool, quiet: bool, format: &OutputFormat) -> bool {
+    stdout_tty && !quiet && *format == OutputFormat::Text
+}
+
+/// The one line summary ending a run, e.g.
+/// `Summary: 42 workspace, 318 upstream, 107 unused (removed 107)`.
+#[derive(Debug, PartialEq)]
+pub struct Summary {
+    pub workspace: usize,
+    pub upstream: usize,
+    pub unused: usize,
+    /// What the action did and to how many packages, counting only successes
+    pub processed: Option<(&'static str, usize)>,
+}
+
+/// The exit status when `--fail-on-unused` finds unused packages. Errors exit with 1 and
+/// invalid command lines with 2, as reported by clap.
+pub const EXIT_UNUSED: u8 = 3;
+
+impl Summary {
+    /// The exit status of a successful run: [`EXIT_UNUSED`] if `fail_on_unused` is set and
+    /// there are unused packages, 0 otherwise.
+    pub fn exit_code(&self, fail_on_unused: bool) -> u8 {
+        if fail_on_unused && self.unused > 0 {
+            EXIT_UNUSED
+        } else {
+            0
+        }
+    }
+}
+
+impl std::fmt::Display for Summary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Summary: {} workspace, {} upstream, {} unused",
+            self.workspace, self.upstream, self.unused
+        )?;
+        if let Some((verb, count)) = self.processed {
+            write!(f, " ({verb} {count})")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filtering::{find_unused_pkgs, kept_pkgs, Dependency, Package};
+
+    fn test_package(name: &str, deps: &[&str]) -> Package {
+        Package {
+            name: name.to_string(),
+            path: name.into(),
+            deps: deps
+                .iter()
+                .map(|n| Dependency {
+                    name: n.to_string(),
+                    dep_type: crate::filtering::DepType::All,
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn writes_output_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("unused.ndjson");
+        fs::write(&path, "stale\n").unwrap();
+        let packages = vec![test_package("a", &[]), test_package("b", &[])];
+        let out = to_ndjson(&DisplayOptions::default(), &packages);
+        write_atomically(&path, &out).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), out);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+        assert!(write_atomically(&dir.path().join("missing").join("out"), &out).is_err());
+    }
+
+    #[test]
+    fn summarizes_runs() {
+        let mut summary = Summary {
+            workspace: 42,
+            upstream: 318,
+            unused: 107,
+            processed: None,
+        };
+        assert_eq!(
+            summary.to_string(),
+            "Summary: 42 workspace, 318 upstream, 107 unused"
+        );
+        // A failed removal leaves fewer processed than unused
+        summary.processed = Some(("removed", 12));
+        assert_eq!(
+            summary.to_string(),
+            "Summary: 42 workspace, 318 upstream, 107 unused (removed 12)"
+        );
+
+        assert_eq!(summary.exit_code(false), 0);
+        assert_eq!(summary.exit_code(true), EXIT_UNUSED);
+        summary.unused = 0;
+        assert_eq!(summary.exit_code(true), 0);
+    }
+
+    #[test]
+    fn colcon_fragments() {
+        let ws = vec![test_package("robot", &["b"])];
+        let upstream = vec![
+            test_package("c", &[]),
+            test_package("b", &["a"]),
+            test_package("a", &[]),
+            test_package("it's", &[]),
+        ];
+        let unused = find_unused_pkgs(&ws, &upstream, &Dependency::all);
+        let kept = kept_pkgs(&upstream, &unused);
+        assert_eq!(
+            colcon_args(&ColconArgs::Select, &kept),
+            "--packages-select a b"
+        );
+        assert_eq!(
+            colcon_args(&ColconArgs::Skip, &unused),
+            r"--packages-skip c 'it'\''s'"
+        );
+    }
+
+    #[test]
+    fn prefix_maps() {
+        let maps: Vec<PrefixMap> = vec![
+            "/ws=/home/me/ws".parse().unwrap(),
+            "/=/other".parse().unwrap(),
+        ];
+        assert_eq!(
+            map_path(&maps, Path::new("/ws/src/pkg")),
+            PathBuf::from("/home/me/ws/src/pkg")
+        );
+        assert_eq!(
+            map_path(&maps[1..], Path::new("/ws/src/pkg")),
+            PathBuf::from("/other/ws/src/pkg")
+        );
+        // First match wins, even if a later map has a longer prefix
+        let reversed: Vec<PrefixMap> = maps.iter().rev().cloned().collect();
+        assert_eq!(
+            map_path(&reversed, Path::new("/ws/src/pkg")),
+            PathBuf::from("/other/ws/src/pkg")
+        );
+        // Only whole components match
+        assert_eq!(
+            map_path(&maps[..1], Path::new("/wsx/src/pkg")),
+            PathBuf::from("/wsx/src/pkg")
+        );
+        let options = DisplayOptions {
+            prefix_map: maps[..1].to_vec(),
+            ..Default::default()
+        };
+        assert_eq!(
+            display_package(&options, &test_package("pkg", &[])),
+            "pkg (pkg)"
+        );
+        "no_equals"
+            .parse::<PrefixMap>()
+            .expect_err("Should not have parsed!");
+    }
+
+    #[test]
+    fn shows_version() {
+        let options = DisplayOptions {
+            show_version: true,
+            ..Default::default()
+        };
+        let mut versioned = test_package("versioned", &[]);
+        versioned.version = Some("1.2.3".into());
+        let unversioned = test_package("unversioned", &[]);
+
+        assert_eq!(
+            display_package(&options, &versioned),
+            "versioned 1.2.3 (versioned)"
+        );
+        assert_eq!(
+            display_package(&options, &unversioned),
+            "unversioned ? (unversioned)"
+        );
+        assert_eq!(
+            display_package(&DisplayOptions::default(), &versioned),
+            "versioned (versioned)"
+        );
+
+        let json = serde_json::to_value(AnalysisJson::new(
+            &options,
+            &[],
+            &[versioned.clone(), unversioned.clone()],
+            &[unversioned],
+        ))
+        .unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "schema_version": OUTPUT_SCHEMA_VERSION,
+                "workspace": [],
+                "upstream": [
+                    {"name": "versioned", "path": "versioned", "version": "1.2.3"},
+                    {"name": "unversioned", "path": "unversioned"},
+                ],
+                "unused": [{"name": "unversioned", "path": "unversioned"}],
+            })
+        );
+        let hidden = serde_json::to_value(PackageJson::new(&DisplayOptions::default(), &versioned));
+        assert_eq!(
+            hidden.unwrap(),
+            serde_json::json!({"name": "versioned", "path": "versioned"})
+        );
+    }
+
+    #[test]
+    fn json_round_trips() {
+        let mut versioned = test_package("versioned", &[]);
+        versioned.version = Some("1.2.3".into());
+        let options = DisplayOptions {
+            show_version: true,
+            ..Default::default()
+        };
+        let doc = AnalysisJson::new(
+            &options,
+            &[test_package("ws", &[])],
+            &[versioned.clone(), test_package("unversioned", &[])],
+            &[versioned],
+        );
+        let parsed: AnalysisJson =
+            serde_json::from_str(&serde_json::to_string_pretty(&doc).unwrap()).unwrap();
+        assert_eq!(parsed, doc);
+        assert_eq!(parsed.schema_version, OUTPUT_SCHEMA_VERSION);
+    }
+
+    #[test]
+    fn schema_matches_output() {
+        let mut versioned = test_package("versioned", &[]);
+        versioned.version = Some("1.2.3".into());
+        versioned.maintainers = vec![crate::filtering::Maintainer {
+            name: "Foo Bar".into(),
+            email: Some("foo@bar.com".into()),
+        }];
+        let options = DisplayOptions {
+            show_version: true,
+            show_maintainers: true,
+            ..Default::default()
+        };
+        let sample = serde_json::to_value(AnalysisJson::new(
+            &options,
+            &[test_package("ws", &[])],
+            &[versioned, test_package("unversioned", &[])],
+            &[],
+        ))
+        .unwrap();
+        let schema = json_schema();
+        assert!(jsonschema::is_valid(&schema, &sample));
+        assert!(!jsonschema::is_valid(
+            &schema,
+            &serde_json::json!({"workspace": [{"name": 1}]})
+        ));
+    }
+
+    #[test]
+    fn renders_templates() {
+        let template: OutputTemplate = "{name}\t{relpath} [{build_type}] v{version}: {dep_count}"
+            .parse()
+            .unwrap();
+        let mut pkg = test_package("robot", &["a", "b"]);
+        pkg.path = "/ws/src/robot".into();
+        pkg.build_type = Some("ament_cmake".into());
+        pkg.version = Some("1.0.0".into());
+        let options = DisplayOptions::default();
+        assert_eq!(
+            template.render(&options, &pkg, Path::new("/ws")),
+            "robot\tsrc/robot [ament_cmake] v1.0.0: 2"
+        );
+        let plain: OutputTemplate = "{path}".parse().unwrap();
+        assert_eq!(
+            plain.render(&options, &test_package("a", &[]), Path::new("/ws")),
+            "a"
+        );
+        "{name} {bogus}"
+            .parse::<OutputTemplate>()
+            .expect_err("Should have rejected the placeholder");
+        "{name"
+            .parse::<OutputTemplate>()
+            .expect_err("Should be unclosed");
+    }
+
+    #[test]
+    fn ndjson_lines() {
+        let pkgs = vec![test_package("a", &[]), test_package("b\nc", &[])];
+        let out = to_ndjson(&DisplayOptions::default(), &pkgs);
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines.len(), 2);
+        for (line, p) in lines.iter().zip(pkgs.iter()) {
+            let value: serde_json::Value = serde_json::from_str(line).unwrap();
+            assert_eq!(value["name"], p.name.as_str());
+            assert_eq!(value["path"], p.path.to_str().unwrap());
+        }
+        assert_eq!(to_ndjson(&DisplayOptions::default(), &[]), "");
+    }
+
+    #[test]
+    fn progress_gate() {
+        assert!(show_progress(true, false, &OutputFormat::Text));
+        // Piped
+        assert!(!show_progress(false, false, &OutputFormat::Text));
+        assert!(!show_progress(true, true, &OutputFormat::Text));
+        assert!(!show_progress(true, false, &OutputFormat::Json));
+    }
+}
@@ -1,78 +1,291 @@
 use anyhow::{anyhow, Context, Result};
+use glob::Pattern;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use indicatif::ProgressBar;
+use log::{debug, trace, warn};
+use rayon::prelude::*;
+use serde::Deserialize;
 use std::{
-    convert::identity,
+    collections::{BTreeMap, HashMap, HashSet},
     fs::{self, File},
     io::prelude::*,
-    io::BufReader,
     path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
 };
-use xml::reader::{EventReader, XmlEvent};
+use xml::reader::{EventReader, ParserConfig, XmlEvent};
 
-use crate::filtering::{DepType, Dependency, Package};
+use crate::{
+    cache::ManifestCache,
+    conditions,
+    filtering::{DepType, Dependency, Maintainer, Package},
+    versions::{VersionConstraint, VersionOp},
+    vfs::{Filesystem, LocalFilesystem},
+};
 
 enum SearchOutcome {
-    Found(Package),
+    Found(Box<Package>),
+    Invalid(PathBuf, anyhow::Error),
     Ignored,
     IsFile,
     Recurse,
 }
 
-static IGNORE_MARKERS: [&str; 3] = ["COLCON_IGNORE", "CATKIN_IGNORE", "AMENT_IGNORE"];
+pub static IGNORE_MARKERS: [&str; 3] = ["COLCON_IGNORE", "CATKIN_IGNORE", "AMENT_IGNORE"];
 
-// TODO: follow symlinks?
-fn check_path(dir: &Path) -> Result<SearchOutcome> {
-    use SearchOutcome::*;
-    if !dir.is_dir() {
-        return Ok(SearchOutcome::IsFile {});
+/// Version control metadata directories, which are never searched.
+pub static VCS_DIRS: [&str; 3] = [".git", ".hg", ".svn"];
+
+/// A file in the searched directory with gitignore-style patterns, relative to it, of
+/// directories to skip, e.g. `build/`.
+pub static IGNORE_FILE: &str = ".ws_cleaner_ignore";
+
+/// Comments in `package.xml` starting with this carry directives for this tool, e.g.
+/// `<!-- ws-cleaner: ignore -->`.
+pub static DIRECTIVE_PREFIX: &str = "ws-cleaner:";
+
+/// Knobs controlling how workspaces are searched and manifests are parsed.
+#[derive(Debug, Clone)]
+pub struct ParseOptions {
+    /// Descend into unknown wrapper elements and collect dependency tags found there,
+    /// rather than only accepting them directly below `<package>`.
+    pub lenient_structure: bool,
+    /// Where the `package.xml` lives relative to the package root (usually empty).
+    pub manifest_subpath: PathBuf,
+    /// Abort the search when this point in time is reached.
+    pub deadline: Option<Instant>,
+    /// Directories containing any of these files are skipped.
+    pub ignore_markers: Vec<String>,
+    /// Also search directories whose name starts with a `.`, except for [`VCS_DIRS`].
+    pub include_dotdirs: bool,
+    /// Directories whose name matches any of these are skipped, e.g. `test` or `example*`.
+    pub skip_dirs: Vec<Pattern>,
+    /// Also accept rosbuild `manifest.xml` files where there is no `package.xml`.
+    pub legacy_manifest: bool,
+    /// Descend into symlinked directories, searching each target once. Otherwise they are
+    /// skipped.
+    pub follow_symlinks: bool,
+    /// Manifests larger than this are rejected, to keep huge XML files from blowing us up.
+    pub max_manifest_bytes: u64,
+    /// Variables for dependency conditions like `$ROS_VERSION == 2`, instead of the
+    /// environment.
+    pub condition_vars: Option<HashMap<String, String>>,
+    /// Skip manifests that fail to parse instead of failing the search.
+    pub skip_invalid: bool,
+    /// Don't search below this many levels under the searched directory, which is level 0.
+    /// Packages at this level are still found.
+    pub max_depth: Option<usize>,
+    /// Skip packages less than this many levels below the searched directory.
+    pub min_depth: usize,
+    /// Reuse manifests parsed by earlier runs, see [`ManifestCache`].
+    pub cache: Option<Arc<ManifestCache>>,
+    /// Ticked for every searched directory, with the number of packages found so far as
+    /// message.
+    pub progress: Option<ProgressBar>,
+}
+
+/// A manifest skipped because of [`ParseOptions::skip_invalid`], with why it failed to parse.
+pub type InvalidManifest = (PathBuf, anyhow::Error);
+
+impl Default for ParseOptions {
+    fn default() -> Self {
+        ParseOptions {
+            lenient_structure: false,
+            manifest_subpath: PathBuf::new(),
+            deadline: None,
+            ignore_markers: IGNORE_MARKERS.iter().map(|m| m.to_string()).collect(),
+            include_dotdirs: false,
+            skip_dirs: Vec::new(),
+            legacy_manifest: false,
+            follow_symlinks: false,
+            max_manifest_bytes: 1024 * 1024,
+            condition_vars: None,
+            skip_invalid: false,
+            max_depth: None,
+            min_depth: 0,
+            cache: None,
+            progress: None,
+        }
     }
+}
 
-    let is_dot_file = dir
-        .file_name()
-        .map(|x| x.to_string_lossy())
-        .map(|x| x.starts_with('.'));
-    if let Some(true) = is_dot_file {
-        return Ok(Ignored {});
+impl ParseOptions {
+    fn manifest_path(&self, pkg_root: &Path) -> PathBuf {
+        pkg_root.join(&self.manifest_subpath).join("package.xml")
     }
 
-    if IGNORE_MARKERS
-        .iter()
-        .any(|ignore| dir.join(ignore).try_exists().is_ok_and(identity))
-    {
-        return Ok(Ignored {});
+    fn legacy_manifest_path(&self, pkg_root: &Path) -> PathBuf {
+        pkg_root.join(&self.manifest_subpath).join("manifest.xml")
+    }
+
+    /// The contents of the manifest `path`, unless it exceeds `max_manifest_bytes`.
+    fn read_manifest(&self, reader: impl Read, path: &Path) -> Result<Vec<u8>> {
+        let mut res = Vec::new();
+        reader
+            .take(self.max_manifest_bytes + 1)
+            .read_to_end(&mut res)
+            .with_context(|| format!("While reading '{}'", path.display()))?;
+        if res.len() as u64 > self.max_manifest_bytes {
+            return Err(anyhow!(
+                "'{}' is larger than {} bytes, see --max-manifest-bytes",
+                path.display(),
+                self.max_manifest_bytes
+            ));
+        }
+        Ok(res)
+    }
+}
+
+/// Whether the search skips `dir` because of its name. Doesn't apply to the searched
+/// directory itself, which is always inspected even if hidden.
+fn skipped_name(dir: &Path, options: &ParseOptions) -> bool {
+    let Some(name) = dir.file_name().map(|x| x.to_string_lossy()) else {
+        return false;
+    };
+    if VCS_DIRS.contains(&name.as_ref()) {
+        trace!("Skipping VCS directory '{}'", dir.display());
+        return true;
+    }
+    if name.starts_with('.') && !options.include_dotdirs {
+        trace!("Skipping hidden directory '{}'", dir.display());
+        return true;
+    }
+    if options.skip_dirs.iter().any(|p| p.matches(&name)) {
+        debug!("Skipping '{}' matching --skip-dir", dir.display());
+        return true;
+    }
+    false
+}
+
+fn check_path(fs: &dyn Filesystem, dir: &Path, options: &ParseOptions) -> Result<SearchOutcome> {
+    use SearchOutcome::*;
+    if !fs.is_dir(dir) {
+        trace!("Skipping file '{}'", dir.display());
+        return Ok(SearchOutcome::IsFile {});
+    }
+
+    for ignore in &options.ignore_markers {
+        let marker = dir.join(ignore);
+        match fs.exists(&marker) {
+            Ok(true) => {
+                debug!("Ignoring '{}' because of {ignore}", dir.display());
+                return Ok(Ignored {});
+            }
+            Ok(false) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("While trying to check '{}'", marker.display()))
+            }
+        }
     }
 
-    let pkg_xml = dir.join("package.xml");
-    if pkg_xml
-        .try_exists()
+    let pkg_xml = options.manifest_path(dir);
+    if fs
+        .exists(&pkg_xml)
         .with_context(|| format!("Wile trying to check '{}'", pkg_xml.display()))?
     {
-        return parse_package(dir, &pkg_xml).map(Found);
+        let package = match parse_package(fs, dir, &pkg_xml, options) {
+            Err(e) if options.skip_invalid => return Ok(Invalid(pkg_xml, e)),
+            res => res?,
+        };
+        debug!("Found '{}' at '{}'", package.name, dir.display());
+        return Ok(Found(Box::new(package)));
+    }
+    if options.legacy_manifest {
+        let manifest = options.legacy_manifest_path(dir);
+        if fs
+            .exists(&manifest)
+            .with_context(|| format!("While trying to check '{}'", manifest.display()))?
+        {
+            let context = || format!("While trying to parse '{}'", manifest.display());
+            let parsed = (|| {
+                let f = fs.open(&manifest)?;
+                let contents = options.read_manifest(f, &manifest)?;
+                parse_legacy_contents(dir, contents.as_slice())
+            })()
+            .with_context(context);
+            let package = match parsed {
+                Err(e) if options.skip_invalid => return Ok(Invalid(manifest, e)),
+                res => res?,
+            };
+            debug!("Found legacy '{}' at '{}'", package.name, dir.display());
+            return Ok(Found(Box::new(package)));
+        }
     }
+    trace!("No package at '{}', recursing", dir.display());
     Ok(Recurse {})
 }
 
-fn parse_contents(package_path: &Path, reader: impl Read) -> Result<Package> {
-    let parser = EventReader::new(reader);
+fn parse_contents(
+    package_path: &Path,
+    reader: impl Read,
+    options: &ParseOptions,
+) -> Result<Package> {
+    let parser = ParserConfig::new()
+        .ignore_comments(false)
+        .create_reader(reader);
 
     let mut depth = 0;
 
     #[derive(PartialEq, Debug)]
     enum Pending {
         Name,
+        Version,
         Depend,
         BuildDepend,
         TestDepend,
         ExecDepend,
+        BuildExportDepend,
+        BuildToolDepend,
+        BuildToolExportDepend,
+        RunDepend,
+        DocDepend,
+        Replace,
+        Conflict,
+        GroupDepend,
+        MemberOfGroup,
+        Maintainer,
         Other,
     }
 
+    impl Pending {
+        fn is_dependency(&self) -> bool {
+            !matches!(
+                self,
+                Pending::Name
+                    | Pending::Version
+                    | Pending::Replace
+                    | Pending::Conflict
+                    | Pending::GroupDepend
+                    | Pending::MemberOfGroup
+                    | Pending::Maintainer
+                    | Pending::Other
+            )
+        }
+    }
+
     fn tag_from_name(name: &str) -> Pending {
         match name {
             "name" => Pending::Name,
+            "version" => Pending::Version,
             "depend" => Pending::Depend,
             "build_depend" => Pending::BuildDepend,
             "test_depend" => Pending::TestDepend,
             "exec_depend" => Pending::ExecDepend,
+            "build_export_depend" => Pending::BuildExportDepend,
+            "buildtool_depend" => Pending::BuildToolDepend,
+            "buildtool_export_depend" => Pending::BuildToolExportDepend,
+            "run_depend" => Pending::RunDepend,
+            "doc_depend" => Pending::DocDepend,
+            "replace" => Pending::Replace,
+            "conflict" => Pending::Conflict,
+            "group_depend" => Pending::GroupDepend,
+            "member_of_group" => Pending::MemberOfGroup,
+            "maintainer" => Pending::Maintainer,
             _ => Pending::Other,
         }
     }
@@ -80,57 +293,237 @@ fn parse_contents(package_path: &Path, reader: impl Read) -> Result<Package> {
     let mut pending = Pending::Other;
 
     let mut maybe_name = None;
+    let mut version = None;
+    let mut replaces = Vec::new();
+    let mut conflicts = Vec::new();
+    let mut groups = Vec::new();
+    let mut group_deps = Vec::new();
+    let mut maintainers = Vec::new();
+    let mut maintainer_email = None;
     let mut deps = Vec::new();
+    let mut directives = Vec::new();
+    let mut has_text = false;
+    let mut empty_deps = 0;
+    let mut pending_constraints = Vec::new();
+    let mut in_export = false;
+    let mut skip_dep = false;
+    let mut in_build_type = false;
+    let mut build_type = None;
+    let mut version_constraints = Vec::new();
+    // Manifests without a format attribute are format 1
+    let mut format = 1;
 
     for e in parser {
         match e {
-            Ok(XmlEvent::StartElement { name, .. }) => {
+            Ok(XmlEvent::StartElement {
+                name, attributes, ..
+            }) => {
                 if depth == 0 && name.local_name != "package" {
                     return Err(anyhow!("Expected 'package' as root element!"));
                 }
-                if depth == 1 {
-                    pending = tag_from_name(name.local_name.as_str());
+                if depth == 0 {
+                    if let Some(attr) = attributes.iter().find(|a| a.name.local_name == "format") {
+                        format = attr.value.trim().parse().with_context(|| {
+                            format!(
+                                "Invalid format '{}' in '{}/package.xml'",
+                                attr.value,
+                                package_path.display()
+                            )
+                        })?;
+                    }
+                }
+                let tag = tag_from_name(name.local_name.as_str());
+                has_text = false;
+                if depth == 1 && tag == Pending::Name && maybe_name.is_some() {
+                    return Err(anyhow!(
+                        "More than one 'name' in '{}/package.xml'",
+                        package_path.display()
+                    ));
+                }
+                if depth == 1 && tag == Pending::Maintainer {
+                    maintainer_email = attributes
+                        .iter()
+                        .find(|a| a.name.local_name == "email")
+                        .map(|a| a.value.clone());
                 }
-                if depth != 1 && pending != Pending::Other {
+                in_export |= depth == 1 && name.local_name == "export";
+                in_build_type = in_export && depth == 2 && name.local_name == "build_type";
+                skip_dep = false;
+                if tag.is_dependency() {
+                    if let Some(condition) =
+                        attributes.iter().find(|a| a.name.local_name == "condition")
+                    {
+                        let lookup = |var: &str| match &options.condition_vars {
+                            Some(vars) => vars.get(var).cloned(),
+                            None => std::env::var(var).ok(),
+                        };
+                        skip_dep = !conditions::evaluate(&condition.value, &lookup).with_context(
+                            || format!("In '{}/package.xml'", package_path.display()),
+                        )?;
+                    }
+                    pending_constraints = attributes
+                        .into_iter()
+                        .filter_map(|a| {
+                            VersionOp::from_attribute(&a.name.local_name).map(|op| (op, a.value))
+                        })
+                        .collect();
+                }
+                if depth == 1 {
+                    pending = tag;
+                } else if pending != Pending::Other {
                     return Err(anyhow!("Expected tag '{name}' at depth 1!"));
+                } else if options.lenient_structure && tag.is_dependency() {
+                    // Wrapped in some unknown grouping element
+                    pending = tag;
                 }
                 depth += 1;
             }
-            Ok(XmlEvent::Characters(data)) => match pending {
-                Pending::Name => {
-                    maybe_name = Some(data);
-                }
-                Pending::Depend => {
-                    deps.push(Dependency {
-                        name: data,
-                        dep_type: DepType::All,
-                    });
+            Ok(XmlEvent::Comment(comment)) => {
+                if let Some(list) = comment.trim().strip_prefix(DIRECTIVE_PREFIX) {
+                    directives.extend(
+                        list.split([',', ' '])
+                            .filter(|d| !d.is_empty())
+                            .map(String::from),
+                    );
                 }
-                Pending::BuildDepend => {
-                    deps.push(Dependency {
-                        name: data,
-                        dep_type: DepType::Build,
-                    });
+            }
+            Ok(XmlEvent::Characters(_) | XmlEvent::CData(_)) if skip_dep => {
+                has_text = true;
+            }
+            Ok(XmlEvent::Characters(data) | XmlEvent::CData(data)) => {
+                has_text = true;
+                // Pretty-printed manifests may leave whitespace around dependency names
+                let data = if pending.is_dependency() {
+                    data.trim().to_string()
+                } else {
+                    data
+                };
+                if in_build_type {
+                    build_type = Some(data.clone());
                 }
-                Pending::TestDepend => {
-                    deps.push(Dependency {
-                        name: data,
-                        dep_type: DepType::Test,
-                    });
+                if pending.is_dependency() {
+                    version_constraints.extend(pending_constraints.drain(..).map(
+                        |(op, version)| VersionConstraint {
+                            dep: data.clone(),
+                            op,
+                            version,
+                        },
+                    ));
                 }
-                Pending::ExecDepend => {
-                    deps.push(Dependency {
-                        name: data,
-                        dep_type: DepType::Exec,
-                    });
+                match pending {
+                    Pending::Name => {
+                        maybe_name = Some(data);
+                    }
+                    Pending::Version => {
+                        version = Some(data);
+                    }
+                    Pending::Depend => {
+                        deps.push(Dependency {
+                            name: data,
+                            dep_type: DepType::All,
+                        });
+                    }
+                    Pending::BuildDepend => {
+                        deps.push(Dependency {
+                            name: data,
+                            dep_type: DepType::Build,
+                        });
+                    }
+                    Pending::TestDepend => {
+                        deps.push(Dependency {
+                            name: data,
+                            dep_type: DepType::Test,
+                        });
+                    }
+                    Pending::ExecDepend => {
+                        deps.push(Dependency {
+                            name: data,
+                            dep_type: DepType::Exec,
+                        });
+                    }
+                    Pending::BuildExportDepend => {
+                        deps.push(Dependency {
+                            name: data,
+                            dep_type: DepType::BuildExport,
+                        });
+                    }
+                    Pending::BuildToolDepend => {
+                        deps.push(Dependency {
+                            name: data,
+                            dep_type: DepType::BuildTool,
+                        });
+                    }
+                    Pending::BuildToolExportDepend => {
+                        // A build tool that dependents need as well, so it counts as both
+                        deps.push(Dependency {
+                            name: data.clone(),
+                            dep_type: DepType::BuildTool,
+                        });
+                        deps.push(Dependency {
+                            name: data,
+                            dep_type: DepType::BuildExport,
+                        });
+                    }
+                    Pending::RunDepend => {
+                        // Format 2 split the format 1 run_depend into build_export_depend and
+                        // exec_depend. Later formats don't have it, take it as exec_depend
+                        if format == 1 {
+                            deps.push(Dependency {
+                                name: data.clone(),
+                                dep_type: DepType::BuildExport,
+                            });
+                        }
+                        deps.push(Dependency {
+                            name: data,
+                            dep_type: DepType::Exec,
+                        });
+                    }
+                    Pending::DocDepend => {
+                        deps.push(Dependency {
+                            name: data,
+                            dep_type: DepType::Doc,
+                        });
+                    }
+                    Pending::Replace => {
+                        replaces.push(data);
+                    }
+                    Pending::Conflict => {
+                        conflicts.push(data);
+                    }
+                    Pending::GroupDepend => {
+                        group_deps.push(data);
+                    }
+                    Pending::MemberOfGroup => {
+                        groups.push(data);
+                    }
+                    Pending::Maintainer => {
+                        maintainers.push(Maintainer {
+                            name: data.trim().to_string(),
+                            email: maintainer_email.take(),
+                        });
+                    }
+                    Pending::Other => { /* ignored */ }
                 }
-                Pending::Other => { /* ignored */ }
-            },
+            }
             Ok(XmlEvent::EndElement { name }) => {
-                if tag_from_name(name.local_name.as_str()) != pending {
+                in_build_type = false;
+                in_export &= depth > 2;
+                let tag = tag_from_name(name.local_name.as_str());
+                // In lenient mode, nested non-dependency tags (e.g. `name`) are not collected
+                let skipped_nested =
+                    options.lenient_structure && pending == Pending::Other && depth > 2;
+                if tag != pending && !skipped_nested {
                     // All the tags we care about are depth 1
-                    return Err(anyhow!("Closing tag '{}' doesn't match opening tag '{:?}' in '{}/package.xml'!", name, pending, package_path.display()));
+                    return Err(anyhow!(
+                        "Closing tag '{}' doesn't match opening tag '{:?}' in '{}/package.xml'!",
+                        name,
+                        pending,
+                        package_path.display()
+                    ));
                 } else {
+                    if pending.is_dependency() && !has_text {
+                        empty_deps += 1;
+                    }
                     pending = Pending::Other;
                 }
                 depth -= 1;
@@ -148,59 +541,706 @@ fn parse_contents(package_path: &Path, reader: impl Read) -> Result<Package> {
         name,
         path: package_path.to_path_buf(),
         deps,
+        version,
+        replaces,
+        conflicts,
+        directives,
+        empty_deps,
+        version_constraints,
+        build_type,
+        groups,
+        group_deps,
+        maintainers,
+    })
+}
+
+/// Parse a rosbuild `manifest.xml`, which declares dependencies as `<depend package="x"/>`
+/// attributes and takes its name from the package directory.
+fn parse_legacy_contents(package_path: &Path, reader: impl Read) -> Result<Package> {
+    let name = package_path
+        .file_name()
+        .context("Legacy package has no directory name")?
+        .to_string_lossy()
+        .into_owned();
+    let mut depth = 0;
+    let mut deps = Vec::new();
+    for e in EventReader::new(reader) {
+        match e? {
+            XmlEvent::StartElement {
+                name, attributes, ..
+            } => {
+                if depth == 0 && name.local_name != "package" {
+                    return Err(anyhow!("Expected 'package' as root element!"));
+                }
+                if depth == 1 && name.local_name == "depend" {
+                    let dep = attributes
+                        .into_iter()
+                        .find(|a| a.name.local_name == "package")
+                        .context("Expected a 'package' attribute on 'depend'")?;
+                    deps.push(Dependency {
+                        name: dep.value,
+                        dep_type: DepType::All,
+                    });
+                }
+                depth += 1;
+            }
+            XmlEvent::EndElement { .. } => depth -= 1,
+            _ => {}
+        }
+    }
+    Ok(Package {
+        name,
+        path: package_path.to_path_buf(),
+        deps,
+        ..Default::default()
     })
 }
 
-fn parse_package(pkg_root: &Path, xml_file: &PathBuf) -> Result<Package> {
+/// Top-level elements every manifest needs, with the minimum number of occurrences.
+/// Format 3 allows several maintainers and licenses, so those are lower bounds only.
+static REQUIRED_ELEMENTS: [(&str, usize, Option<usize>); 5] = [
+    ("name", 1, Some(1)),
+    ("version", 1, Some(1)),
+    ("description", 1, Some(1)),
+    ("maintainer", 1, None),
+    ("license", 1, None),
+];
+
+fn validate_contents(reader: impl Read) -> Result<()> {
+    let mut depth = 0;
+    let mut counts = HashMap::<String, usize>::new();
+    for e in EventReader::new(reader) {
+        match e? {
+            XmlEvent::StartElement { name, .. } => {
+                if depth == 1 {
+                    *counts.entry(name.local_name).or_default() += 1;
+                }
+                depth += 1;
+            }
+            XmlEvent::EndElement { .. } => {
+                depth -= 1;
+            }
+            _ => {}
+        }
+    }
+
+    let problems: Vec<String> = REQUIRED_ELEMENTS
+        .iter()
+        .filter_map(|(element, min, max)| {
+            let count = counts.get(*element).copied().unwrap_or_default();
+            if count < *min {
+                Some(format!("missing '{element}'"))
+            } else if max.is_some_and(|max| count > max) {
+                Some(format!("'{element}' given {count} times"))
+            } else {
+                None
+            }
+        })
+        .collect();
+    if !problems.is_empty() {
+        return Err(anyhow!("Invalid manifest: {}", problems.join(", ")));
+    }
+    Ok(())
+}
+
+/// Check that the `package.xml` of `package` has all required elements. False if it was
+/// parsed from a legacy `manifest.xml` instead, which has none of them and isn't checked.
+pub fn validate(package: &Package, options: &ParseOptions) -> Result<bool> {
+    let xml_file = options.manifest_path(&package.path);
+    if options.legacy_manifest
+        && !xml_file.exists()
+        && options.legacy_manifest_path(&package.path).exists()
+    {
+        return Ok(false);
+    }
+    let context = || format!("While trying to validate '{}'", xml_file.display());
+    let f = File::open(&xml_file).with_context(context)?;
+    let contents = options.read_manifest(f, &xml_file)?;
+    validate_contents(contents.as_slice()).with_context(context)?;
+    Ok(true)
+}
+
+/// Parse a duration like `500ms`, `30s`, `5m` or `1h`. Plain numbers are seconds.
+pub fn parse_duration(s: &str) -> Result<Duration> {
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (value, unit) = s.split_at(split);
+    let value: u64 = value
+        .parse()
+        .with_context(|| format!("Invalid duration '{s}'"))?;
+    match unit.trim() {
+        "ms" => Ok(Duration::from_millis(value)),
+        "" | "s" => Ok(Duration::from_secs(value)),
+        "m" => Ok(Duration::from_secs(value * 60)),
+        "h" => Ok(Duration::from_secs(value * 60 * 60)),
+        _ => Err(anyhow!(
+            "Invalid duration unit in '{s}', expected ms, s, m or h"
+        )),
+    }
+}
+
+/// Environment variable holding additional dependencies in the [`parse_assumed_deps`] format.
+pub static ASSUME_DEPS_VAR: &str = "WS_CLEANER_ASSUME_DEPS";
+
+/// Parse dependencies that should be assumed on top of the manifests.
+///
+/// The format is `pkg: dep1, dep2; pkg2: dep3`: segments separated by `;` or newlines, each
+/// naming a package, a colon and a comma-separated list of its extra dependencies. Lines
+/// starting with `#` and empty segments are skipped.
+pub fn parse_assumed_deps(spec: &str) -> Result<Vec<(String, Vec<String>)>> {
+    let mut res = Vec::new();
+    for segment in spec.split([';', '\n']).map(str::trim) {
+        if segment.is_empty() || segment.starts_with('#') {
+            continue;
+        }
+        let malformed =
+            || anyhow!("Malformed dependency segment '{segment}', expected 'pkg: dep1, dep2'");
+        let (pkg, deps) = segment.split_once(':').ok_or_else(malformed)?;
+        let pkg = pkg.trim();
+        let deps: Vec<String> = deps.split(',').map(|d| d.trim().to_string()).collect();
+        if pkg.is_empty() || deps.iter().any(String::is_empty) {
+            return Err(malformed());
+        }
+        res.push((pkg.to_string(), deps));
+    }
+    Ok(res)
+}
+
+/// Parse assumed dependencies from the `value` of [`ASSUME_DEPS_VAR`], none if unset.
+pub fn assumed_deps_from_var(value: Option<String>) -> Result<Vec<(String, Vec<String>)>> {
+    match value {
+        Some(spec) => {
+            parse_assumed_deps(&spec).with_context(|| format!("While reading {ASSUME_DEPS_VAR}"))
+        }
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Read assumed dependencies from [`ASSUME_DEPS_VAR`], if set.
+pub fn assumed_deps_from_env() -> Result<Vec<(String, Vec<String>)>> {
+    let value = match std::env::var(ASSUME_DEPS_VAR) {
+        Ok(spec) => Some(spec),
+        Err(std::env::VarError::NotPresent) => None,
+        Err(e) => return Err(e).with_context(|| format!("While reading {ASSUME_DEPS_VAR}")),
+    };
+    assumed_deps_from_var(value)
+}
+
+/// Read assumed dependencies from a file in the [`parse_assumed_deps`] format.
+pub fn assumed_deps_from_file(path: &Path) -> Result<Vec<(String, Vec<String>)>> {
+    let context = || format!("While reading '{}'", path.display());
+    parse_assumed_deps(&fs::read_to_string(path).with_context(context)?).with_context(context)
+}
+
+#[derive(Deserialize)]
+struct CompileCommand {
+    directory: PathBuf,
+    file: PathBuf,
+}
+
+/// The roots of the packages containing the sources compiled in a `compile_commands.json`,
+/// found by walking up from each file to the nearest `package.xml`.
+pub fn compile_commands_roots(path: &Path, options: &ParseOptions) -> Result<Vec<PathBuf>> {
+    let context = || format!("While reading '{}'", path.display());
+    let commands: Vec<CompileCommand> =
+        serde_json::from_str(&fs::read_to_string(path).with_context(context)?)
+            .with_context(context)?;
+    let mut res: Vec<PathBuf> = commands
+        .iter()
+        .filter_map(|c| {
+            let file = c.directory.join(&c.file);
+            file.ancestors()
+                .find(|dir| options.manifest_path(dir).is_file())
+                .map(|dir| dir.canonicalize().unwrap_or_else(|_| dir.to_path_buf()))
+        })
+        .collect();
+    res.sort();
+    res.dedup();
+    Ok(res)
+}
+
+/// The run dependencies each package registered in the ament index of the install space
+/// at `prefix`.
+pub fn ament_index_deps(prefix: &Path) -> Result<Vec<(String, Vec<String>)>> {
+    let dir = prefix.join("share/ament_index/resource_index/package_run_dependencies");
+    let context = || format!("While reading the ament index '{}'", dir.display());
+    let mut res = Vec::new();
+    for entry in fs::read_dir(&dir).with_context(context)? {
+        let entry = entry.with_context(context)?;
+        let deps = fs::read_to_string(entry.path()).with_context(context)?;
+        res.push((
+            entry.file_name().to_string_lossy().into_owned(),
+            deps.split(';')
+                .map(str::trim)
+                .filter(|d| !d.is_empty())
+                .map(String::from)
+                .collect(),
+        ));
+    }
+    res.sort();
+    Ok(res)
+}
+
+#[derive(Deserialize)]
+struct Distribution {
+    #[serde(default)]
+    repositories: BTreeMap<String, DistributionRepository>,
+}
+
+#[derive(Deserialize)]
+struct DistributionRepository {
+    release: Option<DistributionRelease>,
+}
+
+#[derive(Deserialize)]
+struct DistributionRelease {
+    packages: Option<Vec<String>>,
+}
+
+/// The names of the packages released in a ROS `distribution.yaml`, sorted. Released
+/// repositories without a package list contain a single package named like the repository.
+pub fn distribution_packages(path: &Path) -> Result<Vec<String>> {
+    let context = || format!("While reading '{}'", path.display());
+    let dist: Distribution = serde_yaml::from_str(&fs::read_to_string(path).with_context(context)?)
+        .with_context(context)?;
+    let mut res: Vec<String> = dist
+        .repositories
+        .into_iter()
+        .filter_map(|(name, repo)| repo.release.map(|r| r.packages.unwrap_or(vec![name])))
+        .flatten()
+        .collect();
+    res.sort();
+    res.dedup();
+    Ok(res)
+}
+
+#[derive(Deserialize)]
+struct ReposFile {
+    #[serde(default)]
+    repositories: BTreeMap<String, ReposEntry>,
+}
+
+/// A repository in a `.repos` file. Its `type`, `url` and `version` are ignored.
+#[derive(Deserialize)]
+struct ReposEntry {
+    #[serde(rename = "local-name")]
+    local_name: Option<String>,
+}
+
+/// The checkout directories listed in a vcstool `.repos` file, relative to the file. Each
+/// repository is checked out at its key, unless it gives a `local-name`.
+pub fn repos_paths(path: &Path) -> Result<Vec<PathBuf>> {
+    let context = || format!("While reading '{}'", path.display());
+    let repos: ReposFile = serde_yaml::from_str(&fs::read_to_string(path).with_context(context)?)
+        .with_context(context)?;
+    let base = path.parent().unwrap_or(Path::new(""));
+    Ok(repos
+        .repositories
+        .into_iter()
+        .map(|(key, repo)| base.join(repo.local_name.unwrap_or(key)))
+        .collect())
+}
+
+/// Glob patterns from a file, one per line. Blank lines and lines starting with `#` are
+/// skipped.
+pub fn read_patterns(path: &Path) -> Result<Vec<Pattern>> {
+    let context = || format!("While reading '{}'", path.display());
+    fs::read_to_string(path)
+        .with_context(context)?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| Pattern::new(line).with_context(context))
+        .collect()
+}
+
+/// Paths given one per line, e.g. on stdin, skipping blank lines.
+pub fn read_path_list(reader: impl BufRead) -> Result<Vec<PathBuf>> {
+    let mut res = Vec::new();
+    for line in reader.lines() {
+        let line = line.context("While reading the path list")?;
+        let line = line.trim();
+        if !line.is_empty() {
+            res.push(PathBuf::from(line));
+        }
+    }
+    Ok(res)
+}
+
+fn parse_package(
+    fs: &dyn Filesystem,
+    pkg_root: &Path,
+    xml_file: &Path,
+    options: &ParseOptions,
+) -> Result<Package> {
     let context = || format!("While trying to parse '{}'", xml_file.display());
-    let f = File::open(xml_file).with_context(context)?;
-    // Prevent huge XML files blowing us up
-    let reader = BufReader::new(f.take(1024 * 1024));
+    let cached = match &options.cache {
+        Some(cache) => {
+            let modified = fs.modified(xml_file).with_context(context)?;
+            let key = fs.canonicalize(xml_file).with_context(context)?;
+            modified.map(|modified| (cache, key, modified))
+        }
+        None => None,
+    };
+    if let Some((cache, key, modified)) = &cached {
+        if let Some(package) = cache.get(key, *modified, pkg_root) {
+            trace!("Using cached '{}'", xml_file.display());
+            return Ok(package);
+        }
+    }
+    let start = Instant::now();
+    let f = fs.open(xml_file).with_context(context)?;
+    let contents = options.read_manifest(f, xml_file)?;
 
-    parse_contents(pkg_root, reader)
+    let package = parse_contents(pkg_root, contents.as_slice(), options)?;
+    match cached {
+        // The cache file is kept canonical, and parsed packages match those taken from it
+        Some((cache, key, modified)) => {
+            let package = package.sorted();
+            cache.insert(&key, modified, &package, start.elapsed());
+            Ok(package)
+        }
+        None => Ok(package),
+    }
 }
 
-fn find_packages(dir: &Path, results: &mut Vec<Package>, recurse: bool) -> anyhow::Result<()> {
-    if !dir.is_dir() {
-        return Ok(());
+/// State shared by the threads of one search.
+struct Search<'a> {
+    fs: &'a dyn Filesystem,
+    options: &'a ParseOptions,
+    visited: Mutex<HashSet<PathBuf>>,
+    found: AtomicUsize,
+    invalid: Mutex<Vec<InvalidManifest>>,
+    /// The patterns of the [`IGNORE_FILE`] of the searched directory
+    ignored: Gitignore,
+}
+
+/// The patterns of the [`IGNORE_FILE`] in `dir`, if any.
+fn read_ignore_file(fs: &dyn Filesystem, dir: &Path) -> Result<Gitignore> {
+    let file = dir.join(IGNORE_FILE);
+    let context = || format!("While reading '{}'", file.display());
+    let mut builder = GitignoreBuilder::new(dir);
+    if fs.exists(&file).with_context(context)? {
+        let mut contents = String::new();
+        fs.open(&file)
+            .and_then(|mut f| f.read_to_string(&mut contents))
+            .with_context(context)?;
+        for line in contents.lines() {
+            builder
+                .add_line(Some(file.clone()), line)
+                .with_context(context)?;
+        }
     }
-    use SearchOutcome::*;
-    for entry in (fs::read_dir(dir)
-        .with_context(|| format!("While searching '{}'", dir.display()))?)
-    .flatten()
-    {
-        let check_outcome = check_path(&entry.path())?;
-        match check_outcome {
+    builder.build().with_context(context)
+}
+
+impl Search<'_> {
+    fn skip(&self, manifest: PathBuf, error: anyhow::Error) {
+        warn!("Skipping '{}': {error:#}", manifest.display());
+        self.invalid.lock().unwrap().push((manifest, error));
+    }
+
+    /// The packages below `dir` at `depth`, in the order of the directory listings.
+    /// Subdirectories are searched in parallel.
+    fn find_packages(&self, dir: &Path, depth: usize) -> anyhow::Result<Vec<Package>> {
+        let (fs, options) = (self.fs, self.options);
+        if !fs.is_dir(dir) {
+            return Ok(Vec::new());
+        }
+        if options
+            .deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+        {
+            return Err(anyhow!(
+                "Timed out while searching '{}' after finding {} packages",
+                dir.display(),
+                self.found.load(Ordering::Relaxed)
+            ));
+        }
+        let entries = fs
+            .read_dir(dir)
+            .with_context(|| format!("While searching '{}'", dir.display()))?;
+        trace!("Searching {} entries of '{}'", entries.len(), dir.display());
+        if let Some(progress) = &options.progress {
+            progress.inc(1);
+            progress.set_message(format!(
+                "found {} packages",
+                self.found.load(Ordering::Relaxed)
+            ));
+        }
+        let results: Vec<Vec<Package>> = entries
+            .par_iter()
+            .map(|entry| self.check_entry(entry, depth + 1))
+            .collect::<Result<_>>()?;
+        Ok(results.concat())
+    }
+
+    /// Whether the search of `root` descends to `dir` at `depth`, i.e. every directory
+    /// between them is searched rather than skipped, ignored or a package.
+    fn reaches(&self, root: &Path, dir: &Path, depth: usize) -> anyhow::Result<bool> {
+        let (fs, options) = (self.fs, self.options);
+        let mut current = root.to_path_buf();
+        let relative = dir.strip_prefix(root)?;
+        for (level, component) in relative.components().take(depth - 1).enumerate() {
+            current.push(component);
+            let searched = !skipped_name(&current, options)
+                && !self.ignored.matched(&current, true).is_ignore()
+                && (options.follow_symlinks || !fs.is_symlink(&current))
+                && options.max_depth.is_none_or(|max| level + 1 < max)
+                && matches!(check_path(fs, &current, options)?, SearchOutcome::Recurse);
+            if !searched {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    fn check_entry(&self, entry: &Path, depth: usize) -> anyhow::Result<Vec<Package>> {
+        use SearchOutcome::*;
+        let (fs, options) = (self.fs, self.options);
+        if skipped_name(entry, options) {
+            return Ok(Vec::new());
+        }
+        if self.ignored.matched(entry, true).is_ignore() {
+            debug!("Skipping '{}' listed in {IGNORE_FILE}", entry.display());
+            return Ok(Vec::new());
+        }
+        if fs.is_symlink(entry) && !options.follow_symlinks {
+            debug!("Not following symlink '{}'", entry.display());
+            return Ok(Vec::new());
+        }
+        // Directories reachable through several links (or a loop) are searched once
+        if options.follow_symlinks && fs.is_dir(entry) {
+            let target = fs
+                .canonicalize(entry)
+                .with_context(|| format!("While resolving '{}'", entry.display()))?;
+            if !self.visited.lock().unwrap().insert(target) {
+                debug!("Already searched '{}'", entry.display());
+                return Ok(Vec::new());
+            }
+        }
+        match check_path(fs, entry, options)? {
+            Found(package) if depth < options.min_depth => {
+                debug!("Skipping '{}' above --min-depth", package.name);
+                Ok(Vec::new())
+            }
             Found(entry) => {
-                results.push(entry);
+                self.found.fetch_add(1, Ordering::Relaxed);
+                Ok(vec![*entry])
             }
-            Recurse if recurse => {
-                find_packages(&entry.path(), results, recurse)?;
+            Invalid(manifest, error) => {
+                self.skip(manifest, error);
+                Ok(Vec::new())
             }
-            _ => {}
+            Recurse if options.max_depth.is_none_or(|max| depth < max) => {
+                self.find_packages(entry, depth)
+            }
+            _ => Ok(Vec::new()),
         }
     }
-    Ok(())
 }
 
+/// Canonicalize each of `paths`, sorted and deduplicated, along with the paths that failed.
+pub fn canonicalize_all(paths: &[PathBuf]) -> (Vec<PathBuf>, Vec<(PathBuf, std::io::Error)>) {
+    let mut valid = Vec::new();
+    let mut invalid = Vec::new();
+    for path in paths {
+        match path.canonicalize() {
+            Ok(canonical) => valid.push(canonical),
+            Err(e) => invalid.push((path.clone(), e)),
+        }
+    }
+    valid.sort();
+    valid.dedup();
+    (valid, invalid)
+}
+
+/// The packages in `dir` and below, sorted by name and path.
 pub fn find(dir: &Path) -> anyhow::Result<Vec<Package>> {
+    find_with_options(dir, &ParseOptions::default())
+}
+
+pub fn find_with_options(dir: &Path, options: &ParseOptions) -> anyhow::Result<Vec<Package>> {
+    find_in(&LocalFilesystem, dir, options)
+}
+
+/// Like [`find_with_options`], but searching `fs` instead of the local file system.
+pub fn find_in(
+    fs: &dyn Filesystem,
+    dir: &Path,
+    options: &ParseOptions,
+) -> anyhow::Result<Vec<Package>> {
+    find_in_reporting(fs, dir, options).map(|(packages, _)| packages)
+}
+
+/// Like [`find_in`], also returning the manifests skipped with [`ParseOptions::skip_invalid`].
+/// Packages are sorted by name and path, skipped manifests by path, independent of the order
+/// the file system lists directories in.
+pub fn find_in_reporting(
+    fs: &dyn Filesystem,
+    dir: &Path,
+    options: &ParseOptions,
+) -> anyhow::Result<(Vec<Package>, Vec<InvalidManifest>)> {
+    // A manifest given directly is the package it belongs to, at level 0
+    if dir.file_name().is_some_and(|name| name == "package.xml") && !fs.is_dir(dir) {
+        if options.min_depth > 0 {
+            return Ok((Vec::new(), Vec::new()));
+        }
+        let mut pkg_root = dir.parent();
+        for _ in options.manifest_subpath.components() {
+            pkg_root = pkg_root.and_then(Path::parent);
+        }
+        let pkg_root = match pkg_root {
+            Some(parent) if parent != Path::new("") => parent,
+            _ => Path::new("."),
+        };
+        return match parse_package(fs, pkg_root, dir, options) {
+            Err(e) if options.skip_invalid => Ok((Vec::new(), vec![(dir.to_path_buf(), e)])),
+            res => Ok((vec![res?], Vec::new())),
+        };
+    }
+    let mut visited = HashSet::new();
+    if options.follow_symlinks {
+        visited.insert(
+            fs.canonicalize(dir)
+                .with_context(|| format!("While resolving '{}'", dir.display()))?,
+        );
+    }
+    let search = Search {
+        fs,
+        options,
+        visited: Mutex::new(visited),
+        found: AtomicUsize::new(0),
+        invalid: Mutex::new(Vec::new()),
+        ignored: read_ignore_file(fs, dir)?,
+    };
     let mut res: Vec<_> = Vec::new();
-    if let SearchOutcome::Found(entry) = check_path(dir)? {
-        res.push(entry);
+    match check_path(fs, dir, options)? {
+        SearchOutcome::Found(entry) if options.min_depth == 0 => {
+            search.found.fetch_add(1, Ordering::Relaxed);
+            res.push(*entry);
+        }
+        SearchOutcome::Invalid(manifest, error) => search.skip(manifest, error),
+        _ => {}
+    }
+    if options.max_depth != Some(0) {
+        res.extend(search.find_packages(dir, 0)?);
+    }
+    // Listing order depends on the file system
+    res.sort_by(|a, b| (&a.name, &a.path).cmp(&(&b.name, &b.path)));
+    let mut invalid = search.invalid.into_inner().unwrap();
+    invalid.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok((res, invalid))
+}
+
+/// Discovered packages that can be updated one changed path at a time, e.g. on file system
+/// events, instead of rescanning the whole tree. Updates apply the same rules as the search,
+/// so the packages stay those a rescan would find.
+#[derive(Debug, Clone)]
+pub struct PackageIndex {
+    root: PathBuf,
+    options: ParseOptions,
+    packages: BTreeMap<PathBuf, Package>,
+}
+
+impl PackageIndex {
+    pub fn scan(dir: &Path, options: &ParseOptions) -> Result<Self> {
+        let mut res = PackageIndex {
+            root: dir.to_path_buf(),
+            options: options.clone(),
+            packages: BTreeMap::new(),
+        };
+        res.rescan()?;
+        Ok(res)
+    }
+
+    fn rescan(&mut self) -> Result<()> {
+        self.packages = find_with_options(&self.root, &self.options)?
+            .into_iter()
+            .map(|p| (p.path.clone(), p))
+            .collect();
+        Ok(())
+    }
+
+    /// Catch up with `path` being created, changed or removed below the indexed directory:
+    /// a manifest, an ignore marker, an [`IGNORE_FILE`] or a whole directory.
+    pub fn update(&mut self, path: &Path) -> Result<()> {
+        let is_manifest = path
+            .file_name()
+            .is_some_and(|name| name == "package.xml" || name == "manifest.xml");
+        // The directory that is searched again: the package root of a manifest, otherwise
+        // the one containing the path
+        let mut dir = path.parent();
+        if is_manifest {
+            for _ in self.options.manifest_subpath.components() {
+                dir = dir.and_then(Path::parent);
+            }
+        }
+        let dir = dir.with_context(|| format!("'{}' is not inside a package", path.display()))?;
+        let relative = dir.strip_prefix(&self.root).with_context(|| {
+            format!(
+                "'{}' is not below '{}'",
+                path.display(),
+                self.root.display()
+            )
+        })?;
+        if relative.as_os_str().is_empty() {
+            return self.rescan();
+        }
+
+        let fs = &LocalFilesystem;
+        let search = Search {
+            fs,
+            options: &self.options,
+            visited: Mutex::new(HashSet::new()),
+            found: AtomicUsize::new(0),
+            invalid: Mutex::new(Vec::new()),
+            ignored: read_ignore_file(fs, &self.root)?,
+        };
+        let depth = relative.components().count();
+        let found = match search.reaches(&self.root, dir, depth)? {
+            true => search.check_entry(dir, depth)?,
+            false => Vec::new(),
+        };
+        self.packages.retain(|root, _| !root.starts_with(dir));
+        self.packages
+            .extend(found.into_iter().map(|p| (p.path.clone(), p)));
+        Ok(())
+    }
+
+    pub fn packages(&self) -> Vec<Package> {
+        self.packages.values().cloned().collect()
     }
-    find_packages(dir, &mut res, true)?;
-    Ok(res)
 }
 
 #[cfg(test)]
 mod tests {
-    use std::path::PathBuf;
+    use std::{
+        collections::{BTreeMap, HashMap},
+        fs,
+        path::{Path, PathBuf},
+        time::{Duration, Instant},
+    };
 
-    use super::parse_contents;
-    use crate::filtering::{Dependency, Package};
+    use super::{
+        ament_index_deps, assumed_deps_from_var, canonicalize_all, compile_commands_roots,
+        distribution_packages, find, find_in, find_in_reporting, find_with_options,
+        parse_assumed_deps, parse_contents, parse_duration, parse_legacy_contents, read_patterns,
+        repos_paths, validate, validate_contents, PackageIndex, ParseOptions, IGNORE_FILE,
+    };
+    use crate::{
+        filtering::{
+            assume_deps, cleanup_roots, distribution_diff, find_unused_pkgs, DepType, Dependency,
+            Maintainer, Package,
+        },
+        vfs::{Filesystem, LocalFilesystem},
+    };
 
     fn from_str(data: &str) -> anyhow::Result<Package> {
-        parse_contents(&PathBuf::from("."), data.as_bytes())
+        from_str_with(data, &ParseOptions::default())
+    }
+
+    fn from_str_with(data: &str, options: &ParseOptions) -> anyhow::Result<Package> {
+        parse_contents(&PathBuf::from("."), data.as_bytes(), options)
     }
 
     fn dep(dep: &str) -> Dependency {
@@ -227,6 +1267,24 @@ mod tests {
             dep_type: crate::filtering::DepType::Exec,
         }
     }
+    fn btdep(dep: &str) -> Dependency {
+        Dependency {
+            name: dep.to_string(),
+            dep_type: crate::filtering::DepType::BuildTool,
+        }
+    }
+    fn ddep(dep: &str) -> Dependency {
+        Dependency {
+            name: dep.to_string(),
+            dep_type: crate::filtering::DepType::Doc,
+        }
+    }
+    fn bedep(dep: &str) -> Dependency {
+        Dependency {
+            name: dep.to_string(),
+            dep_type: crate::filtering::DepType::BuildExport,
+        }
+    }
 
     #[test]
     fn fails_on_broken() {
@@ -271,6 +1329,43 @@ mod tests {
             "#;
         let parsed: Package = from_str(manifest).unwrap();
         assert_eq!(parsed.name, "zzz_package");
+        assert_eq!(parsed.build_type.as_deref(), Some("ament_cmake"));
+        assert_eq!(parsed.version.as_deref(), Some("1.0.0"));
+        assert_eq!(parsed.deps, [btdep("ament_cmake")]);
+    }
+
+    #[test]
+    fn parses_maintainers() {
+        let parsed = from_str(
+            r#"<package format="3">
+              <name>maintained</name>
+              <version>2.1.0</version>
+              <maintainer email="foo@bar.com">Foo Bar</maintainer>
+              <maintainer>
+                Baz Bar
+              </maintainer>
+            </package>"#,
+        )
+        .unwrap();
+        assert_eq!(parsed.version(), Some("2.1.0"));
+        assert_eq!(
+            parsed.maintainers(),
+            [
+                Maintainer {
+                    name: "Foo Bar".into(),
+                    email: Some("foo@bar.com".into())
+                },
+                Maintainer {
+                    name: "Baz Bar".into(),
+                    email: None
+                }
+            ]
+        );
+        assert_eq!(parsed.maintainers()[0].to_string(), "Foo Bar <foo@bar.com>");
+
+        let bare = from_str("<package><name>bare</name></package>").unwrap();
+        assert_eq!(bare.version(), None);
+        assert!(bare.maintainers().is_empty());
     }
 
     #[test]
@@ -298,6 +1393,8 @@ mod tests {
               <exec_depend>exec_dep1</exec_depend>
               <exec_depend>exec_dep2</exec_depend>
 
+              <build_export_depend>export_dep</build_export_depend>
+
               <export>
                 <build_type>ament_cmake</build_type>
               </export>
@@ -308,6 +1405,7 @@ mod tests {
         assert_eq!(
             parsed.deps,
             vec![
+                btdep("ament_cmake"),
                 dep("dep1"),
                 dep("dep2"),
                 bdep("build_dep1"),
@@ -315,7 +1413,8 @@ mod tests {
                 tdep("test_dep1"),
                 tdep("test_dep2"),
                 edep("exec_dep1"),
-                edep("exec_dep2")
+                edep("exec_dep2"),
+                bedep("export_dep")
             ]
         );
     }
@@ -352,6 +1451,7 @@ mod tests {
         assert_eq!(
             parsed.deps,
             vec![
+                btdep("ament_cmake"),
                 dep("dep1"),
                 bdep("build_dep1"),
                 tdep("test_dep1"),
@@ -363,4 +1463,1097 @@ mod tests {
             ]
         );
     }
+
+    #[test]
+    fn lenient_structure_descends_into_wrappers() {
+        let manifest = r#"<?xml version="1.0"?>
+            <package format="3">
+              <name>zzz_package</name>
+              <depend>dep1</depend>
+              <group>
+                <depend>dep2</depend>
+                <name>not_the_name</name>
+              </group>
+            </package>
+            "#;
+        from_str(manifest).expect_err("Should not have parsed without lenient structure!");
+        let lenient = ParseOptions {
+            lenient_structure: true,
+            ..Default::default()
+        };
+        let parsed = from_str_with(manifest, &lenient).unwrap();
+        assert_eq!(parsed.name, "zzz_package");
+        assert_eq!(parsed.deps, vec![dep("dep1"), dep("dep2")]);
+    }
+
+    #[test]
+    fn validates_repeated_elements() {
+        let manifest = r#"<?xml version="1.0"?>
+            <package format="3">
+              <name>zzz_package</name>
+              <version>1.0.0</version>
+              <description>This is a cmake package</description>
+              <maintainer email="foo@bar.com">Foo Bar</maintainer>
+              <maintainer email="baz@bar.com">Baz Bar</maintainer>
+              <license>MIT</license>
+              <license>BSD</license>
+            </package>
+            "#;
+        validate_contents(manifest.as_bytes()).unwrap();
+        from_str(manifest).unwrap();
+    }
+
+    #[test]
+    fn validation_requires_maintainer_and_license() {
+        let manifest = r#"<?xml version="1.0"?>
+            <package format="3">
+              <name>zzz_package</name>
+              <version>1.0.0</version>
+              <description>This is a cmake package</description>
+            </package>
+            "#;
+        let err = validate_contents(manifest.as_bytes()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid manifest: missing 'maintainer', missing 'license'"
+        );
+    }
+
+    #[test]
+    fn skips_validating_legacy_manifests() {
+        let dir = tempfile::tempdir().unwrap();
+        let legacy = dir.path().join("legacy");
+        let current = dir.path().join("current");
+        fs::create_dir_all(&legacy).unwrap();
+        fs::create_dir_all(&current).unwrap();
+        fs::write(
+            legacy.join("manifest.xml"),
+            "<package><depend package='a'/></package>",
+        )
+        .unwrap();
+        fs::write(
+            current.join("package.xml"),
+            "<package><name>current</name></package>",
+        )
+        .unwrap();
+        let options = ParseOptions {
+            legacy_manifest: true,
+            ..Default::default()
+        };
+        let found = find_with_options(dir.path(), &options).unwrap();
+        assert_eq!(found.len(), 2);
+        for p in found.iter() {
+            match p.name.as_str() {
+                "legacy" => assert!(!validate(p, &options).unwrap()),
+                _ => {
+                    validate(p, &options).expect_err("Should have lacked a version");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn parses_assumed_deps() {
+        assert_eq!(
+            parse_assumed_deps("pkg: dep1, dep2; pkg2: dep3\n# comment\npkg3:dep4;").unwrap(),
+            vec![
+                (
+                    "pkg".to_string(),
+                    vec!["dep1".to_string(), "dep2".to_string()]
+                ),
+                ("pkg2".to_string(), vec!["dep3".to_string()]),
+                ("pkg3".to_string(), vec!["dep4".to_string()]),
+            ]
+        );
+        let err = parse_assumed_deps("pkg: dep1; pkg2 dep3").unwrap_err();
+        assert!(err.to_string().contains("'pkg2 dep3'"));
+        parse_assumed_deps("pkg: dep1,").expect_err("Should not have parsed!");
+        parse_assumed_deps(": dep1").expect_err("Should not have parsed!");
+    }
+
+    #[test]
+    fn assumed_deps_from_environment() {
+        let mut ws = vec![from_str("<package><name>ws</name></package>").unwrap()];
+        let upstream = vec![from_str("<package><name>plugin</name></package>").unwrap()];
+        assert_eq!(find_unused_pkgs(&ws, &upstream, &Dependency::all), upstream);
+
+        assert!(assumed_deps_from_var(None).unwrap().is_empty());
+        assert!(assumed_deps_from_var(Some("ws plugin".into())).is_err());
+        let assumed = assumed_deps_from_var(Some("ws: plugin".into())).unwrap();
+        assume_deps(&mut ws, &assumed);
+        assert!(find_unused_pkgs(&ws, &upstream, &Dependency::all).is_empty());
+    }
+
+    #[test]
+    fn finds_manifest_in_subpath() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("ws");
+        let pkg = root.join("pkg");
+        fs::create_dir_all(pkg.join("ros")).unwrap();
+        fs::write(
+            pkg.join("ros").join("package.xml"),
+            "<package><name>pkg</name></package>",
+        )
+        .unwrap();
+
+        let options = ParseOptions {
+            manifest_subpath: "ros".into(),
+            ..Default::default()
+        };
+        let found = find_with_options(&root, &options).unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].name, "pkg");
+        assert_eq!(found[0].path, pkg);
+
+        let found = find_with_options(&root, &ParseOptions::default()).unwrap();
+        assert_eq!(found[0].path, pkg.join("ros"));
+    }
+
+    #[test]
+    fn compile_commands_seed_packages() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap().join("upstream");
+        for name in ["a", "b"] {
+            let pkg = root.join(name);
+            fs::create_dir_all(pkg.join("src")).unwrap();
+            fs::write(
+                pkg.join("package.xml"),
+                format!("<package><name>{name}</name></package>"),
+            )
+            .unwrap();
+        }
+        let commands = dir.path().join("compile_commands.json");
+        let build = root.join("build").join("a");
+        let json = serde_json::json!([
+            {"directory": build, "file": root.join("a/src/main.cpp"), "command": "c++"},
+            {"directory": root.join("a"), "file": "src/util.cpp", "command": "c++"},
+            {"directory": build, "file": "/nowhere/generated.cpp", "command": "c++"},
+        ]);
+        fs::write(&commands, json.to_string()).unwrap();
+
+        let roots = compile_commands_roots(&commands, &ParseOptions::default()).unwrap();
+        assert_eq!(roots, [root.join("a")]);
+        let used: Vec<String> = find(&root)
+            .unwrap()
+            .into_iter()
+            .filter(|p| roots.contains(&p.path))
+            .map(|p| p.name)
+            .collect();
+        assert_eq!(used, ["a"]);
+    }
+
+    #[test]
+    fn canonicalizes_valid_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let valid = dir.path().join("ws");
+        fs::create_dir(&valid).unwrap();
+        let missing = dir.path().join("missing");
+        let (ok, failed) = canonicalize_all(&[missing.clone(), valid.clone(), valid.join(".")]);
+        assert_eq!(ok, [valid.canonicalize().unwrap()]);
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].0, missing);
+    }
+
+    #[test]
+    fn reads_ament_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let index = dir
+            .path()
+            .join("share/ament_index/resource_index/package_run_dependencies");
+        fs::create_dir_all(&index).unwrap();
+        fs::write(index.join("robot"), "rclcpp;std_msgs\n").unwrap();
+        fs::write(index.join("leaf"), "").unwrap();
+        assert_eq!(
+            ament_index_deps(dir.path()).unwrap(),
+            [
+                ("leaf".to_string(), vec![]),
+                (
+                    "robot".to_string(),
+                    vec!["rclcpp".to_string(), "std_msgs".to_string()]
+                ),
+            ]
+        );
+        ament_index_deps(&dir.path().join("missing")).expect_err("Should have failed");
+    }
+
+    #[test]
+    fn parses_legacy_manifest() {
+        let manifest = r#"<package>
+              <description brief="old">An old package</description>
+              <license>BSD</license>
+              <depend package="roscpp"/>
+              <depend package="std_msgs" />
+              <rosdep name="boost"/>
+              <export><cpp cflags="-I${prefix}/include"/></export>
+            </package>"#;
+        let parsed = parse_legacy_contents(Path::new("src/old_pkg"), manifest.as_bytes()).unwrap();
+        assert_eq!(parsed.name, "old_pkg");
+        assert_eq!(parsed.deps, [dep("roscpp"), dep("std_msgs")]);
+        parse_legacy_contents(Path::new("x"), "<depend package='a'/>".as_bytes())
+            .expect_err("Should have required a package root");
+
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("ws");
+        let both = root.join("both");
+        let legacy = root.join("legacy");
+        for pkg in [&both, &legacy] {
+            fs::create_dir_all(pkg).unwrap();
+            fs::write(pkg.join("manifest.xml"), manifest).unwrap();
+        }
+        fs::write(
+            both.join("package.xml"),
+            "<package><name>new</name></package>",
+        )
+        .unwrap();
+        let options = ParseOptions {
+            legacy_manifest: true,
+            ..Default::default()
+        };
+        let mut found = find_with_options(&root, &options).unwrap();
+        found.sort_by(|a, b| a.name.cmp(&b.name));
+        assert_eq!(found.len(), 2);
+        assert_eq!(
+            (found[0].name.as_str(), &found[0].path),
+            ("legacy", &legacy)
+        );
+        assert_eq!(found[1].name, "new");
+        assert_eq!(find(&root).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn skips_oversized_legacy_manifest() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("ws");
+        for (name, padding) in [("small", 0), ("huge", 200)] {
+            fs::create_dir_all(root.join(name)).unwrap();
+            let manifest = format!(
+                "<package><description>{}</description></package>",
+                "x".repeat(padding)
+            );
+            fs::write(root.join(name).join("manifest.xml"), manifest).unwrap();
+        }
+        let options = ParseOptions {
+            legacy_manifest: true,
+            max_manifest_bytes: 100,
+            ..Default::default()
+        };
+        find_with_options(&root, &options).expect_err("Should have been too large");
+        let skipping = ParseOptions {
+            skip_invalid: true,
+            ..options
+        };
+        let (found, invalid) = find_in_reporting(&LocalFilesystem, &root, &skipping).unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].name, "small");
+        assert_eq!(invalid.len(), 1);
+        assert_eq!(invalid[0].0, root.join("huge").join("manifest.xml"));
+    }
+
+    #[test]
+    fn parses_version_constraints() {
+        let parsed = from_str(
+            r#"<package>
+              <name>robot</name>
+              <depend version_gte="1.2" version_lt="2.0">lib</depend>
+              <exec_depend version_eq="0.1.0">tool</exec_depend>
+              <build_depend>plain</build_depend>
+            </package>"#,
+        )
+        .unwrap();
+        let constraints: Vec<String> = parsed
+            .version_constraints
+            .iter()
+            .map(|c| c.to_string())
+            .collect();
+        assert_eq!(constraints, ["lib >= 1.2", "lib < 2.0", "tool = 0.1.0"]);
+        assert_eq!(parsed.deps, [dep("lib"), edep("tool"), bdep("plain")]);
+    }
+
+    #[test]
+    fn legacy_formats() {
+        let parsed = from_str(
+            r#"<package>
+              <name>old_robot</name>
+              <build_depend>roscpp</build_depend>
+              <run_depend>roscpp</run_depend>
+            </package>"#,
+        )
+        .unwrap();
+        assert_eq!(
+            parsed.deps,
+            [bdep("roscpp"), bedep("roscpp"), edep("roscpp")]
+        );
+
+        let parsed = from_str(
+            r#"<package format="2">
+              <name>robot</name>
+              <build_export_depend>headers</build_export_depend>
+              <buildtool_export_depend>cmake_macros</buildtool_export_depend>
+              <exec_depend>launch</exec_depend>
+              <run_depend>legacy</run_depend>
+            </package>"#,
+        )
+        .unwrap();
+        assert_eq!(
+            parsed.deps,
+            [
+                bedep("headers"),
+                btdep("cmake_macros"),
+                bedep("cmake_macros"),
+                edep("launch"),
+                edep("legacy")
+            ]
+        );
+        assert!(from_str(r#"<package format="two"><name>x</name></package>"#).is_err());
+    }
+
+    #[test]
+    fn rejects_ambiguous_names() {
+        let err = from_str("<package><name>a</name><name>b</name></package>").unwrap_err();
+        assert!(err.to_string().starts_with("More than one 'name'"));
+        let err = from_str("<package><version>1.0</version></package>").unwrap_err();
+        assert_eq!(err.to_string(), "Field 'name' missing from package.xml");
+        assert_eq!(
+            from_str("<package><name>a</name></package>").unwrap().name,
+            "a"
+        );
+    }
+
+    #[test]
+    fn doc_dependencies() {
+        let parsed = from_str(
+            r#"<package format="3">
+              <name>docs_only</name>
+              <doc_depend>rosdoc2</doc_depend>
+            </package>"#,
+        )
+        .unwrap();
+        assert_eq!(parsed.deps, [ddep("rosdoc2")]);
+        assert!(Dependency::matcher(vec![DepType::Doc])(&parsed.deps[0]));
+        assert!(Dependency::all(&parsed.deps[0]));
+    }
+
+    #[test]
+    fn trims_dependency_names() {
+        let parsed = from_str(
+            "<package><name>pkg</name><depend> dep1 </depend><depend>
+                dep2
+            </depend></package>",
+        )
+        .unwrap();
+        assert_eq!(parsed.deps, [dep("dep1"), dep("dep2")]);
+    }
+
+    #[test]
+    fn cdata_and_commented_dependencies() {
+        let parsed = from_str(
+            "<package><name>vendored</name>
+              <!-- <depend>old_dep</depend> -->
+              <depend><![CDATA[dep1]]></depend>
+              <exec_depend><!-- unused --></exec_depend>
+            </package>",
+        )
+        .unwrap();
+        assert_eq!(parsed.deps, [dep("dep1")]);
+        assert_eq!(parsed.empty_deps, 1);
+    }
+
+    #[test]
+    fn dependency_groups() {
+        let parsed = from_str(
+            r#"<package format="3">
+              <name>rqt_plot</name>
+              <group_depend>plot_backends</group_depend>
+              <member_of_group>rqt_plugins</member_of_group>
+            </package>"#,
+        )
+        .unwrap();
+        assert_eq!(parsed.groups, ["rqt_plugins"]);
+        assert_eq!(parsed.group_deps, ["plot_backends"]);
+        assert!(parsed.deps.is_empty());
+    }
+
+    #[test]
+    fn conditional_dependencies() {
+        let options = ParseOptions {
+            condition_vars: Some(HashMap::from([("ROS_VERSION".into(), "2".into())])),
+            ..Default::default()
+        };
+        let parsed = from_str_with(
+            r#"<package>
+              <name>robot</name>
+              <depend condition="$ROS_VERSION == 2">rclcpp</depend>
+              <depend condition="$ROS_VERSION == 1">roscpp</depend>
+              <exec_depend condition="$ROS_VERSION == 1" version_gte="1.0">rospy</exec_depend>
+              <depend>common</depend>
+            </package>"#,
+            &options,
+        )
+        .unwrap();
+        assert_eq!(parsed.deps, [dep("rclcpp"), dep("common")]);
+        assert!(parsed.version_constraints.is_empty());
+        assert_eq!(parsed.empty_deps, 0);
+
+        let err = from_str_with(
+            r#"<package><name>bad</name><depend condition="$ROS_VERSION =">x</depend></package>"#,
+            &options,
+        )
+        .unwrap_err();
+        assert!(format!("{err:#}").contains("condition"));
+    }
+
+    #[test]
+    fn comment_directives() {
+        let ignored = from_str(
+            r#"<package>
+              <!-- ws-cleaner: ignore -->
+              <!-- An ordinary comment -->
+              <name>ignored</name>
+              <depend>dep</depend>
+            </package>"#,
+        )
+        .unwrap();
+        assert_eq!(ignored.directives, ["ignore"]);
+        assert_eq!(ignored.deps, [dep("dep")]);
+
+        let ws = vec![Package {
+            name: "ws".into(),
+            ..Default::default()
+        }];
+        let upstream = vec![
+            ignored.clone(),
+            from_str("<package><name>dep</name></package>").unwrap(),
+        ];
+        let pinned = cleanup_roots(&ws, &upstream);
+        assert!(find_unused_pkgs(&pinned, &upstream, &Dependency::all).is_empty());
+        assert_eq!(find_unused_pkgs(&ws, &upstream, &Dependency::all).len(), 2);
+
+        let empty = r#"<package>
+              <name>empty</name>
+              <depend></depend>
+              <exec_depend/>
+              <depend>real</depend>
+            </package>"#;
+        let parsed = from_str(empty).unwrap();
+        assert_eq!(parsed.empty_deps, 2);
+        assert!(parsed.warn_empty_deps());
+        let allowed =
+            from_str(&empty.replace("<name>", "<!--ws-cleaner: allow-empty-dep--><name>")).unwrap();
+        assert_eq!(allowed.empty_deps, 2);
+        assert!(!allowed.warn_empty_deps());
+    }
+
+    #[test]
+    fn reads_pattern_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("packages.txt");
+        fs::write(&file, "# Generated\nnav2_core\n\n  rviz_*  \n# old_pkg\n").unwrap();
+        let patterns: Vec<String> = read_patterns(&file)
+            .unwrap()
+            .iter()
+            .map(|p| p.to_string())
+            .collect();
+        assert_eq!(patterns, ["nav2_core", "rviz_*"]);
+        fs::write(&file, "[broken\n").unwrap();
+        assert!(read_patterns(&file).is_err());
+    }
+
+    #[test]
+    fn reads_repos_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("my_ws.repos");
+        fs::write(
+            &file,
+            r#"repositories:
+  navigation/nav2:
+    type: git
+    url: https://example.com/nav2.git
+    version: main
+  tools:
+    type: hg
+    url: https://example.com/tools
+    local-name: vendor/tools
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            repos_paths(&file).unwrap(),
+            [
+                dir.path().join("navigation/nav2"),
+                dir.path().join("vendor/tools")
+            ]
+        );
+        fs::write(&file, "repositories: [not, a, map]").unwrap();
+        assert!(repos_paths(&file).is_err());
+    }
+
+    #[test]
+    fn reads_distribution() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("distribution.yaml");
+        fs::write(
+            &file,
+            r#"%YAML 1.1
+---
+release_platforms:
+  ubuntu: [noble]
+repositories:
+  common:
+    doc: {type: git, url: "https://example.com/common.git"}
+    release:
+      packages: [common_a, common_b]
+      version: 1.0.0-1
+  single:
+    release:
+      version: 0.1.0-1
+  source_only:
+    source: {type: git, url: "https://example.com/src.git"}
+type: distribution
+version: 2
+"#,
+        )
+        .unwrap();
+        let expected = distribution_packages(&file).unwrap();
+        assert_eq!(expected, ["common_a", "common_b", "single"]);
+
+        let discovered = vec![
+            from_str("<package><name>common_a</name></package>").unwrap(),
+            from_str("<package><name>single</name></package>").unwrap(),
+            from_str("<package><name>vendored</name></package>").unwrap(),
+        ];
+        let (missing, extra) = distribution_diff(&expected, &discovered);
+        assert_eq!(missing, ["common_b"]);
+        assert_eq!(extra, [&discovered[2]]);
+    }
+
+    /// Files kept in memory; directories are implied by the file paths.
+    struct MemoryFilesystem(BTreeMap<PathBuf, String>);
+
+    impl Filesystem for MemoryFilesystem {
+        fn is_dir(&self, path: &Path) -> bool {
+            self.0.keys().any(|f| f != path && f.starts_with(path))
+        }
+
+        fn exists(&self, path: &Path) -> std::io::Result<bool> {
+            Ok(self.0.contains_key(path) || self.is_dir(path))
+        }
+
+        fn read_dir(&self, path: &Path) -> std::io::Result<Vec<PathBuf>> {
+            let mut res: Vec<PathBuf> = self
+                .0
+                .keys()
+                .filter_map(|f| f.strip_prefix(path).ok())
+                .filter_map(|rest| rest.components().next())
+                .map(|first| path.join(first))
+                .collect();
+            res.dedup();
+            Ok(res)
+        }
+
+        fn open(&self, path: &Path) -> std::io::Result<Box<dyn std::io::Read + '_>> {
+            let contents = self.0.get(path).ok_or(std::io::ErrorKind::NotFound)?;
+            Ok(Box::new(contents.as_bytes()))
+        }
+    }
+
+    #[test]
+    fn finds_in_memory_tree() {
+        let manifest = |name: &str, dep: &str| {
+            format!("<package><name>{name}</name><depend>{dep}</depend></package>")
+        };
+        let fs = MemoryFilesystem(BTreeMap::from([
+            ("/remote/ws/a/package.xml".into(), manifest("a", "b")),
+            ("/remote/ws/group/b/package.xml".into(), manifest("b", "c")),
+            (
+                "/remote/ws/group/b/nested/package.xml".into(),
+                manifest("nested", "x"),
+            ),
+            (
+                "/remote/ws/ignored/package.xml".into(),
+                manifest("ignored", "x"),
+            ),
+            ("/remote/ws/ignored/COLCON_IGNORE".into(), String::new()),
+            (
+                "/remote/ws/.hidden/package.xml".into(),
+                manifest("hidden", "x"),
+            ),
+        ]));
+        let mut found = find_in(&fs, Path::new("/remote/ws"), &ParseOptions::default()).unwrap();
+        found.sort_by(|a, b| a.name.cmp(&b.name));
+        let found: Vec<(&str, &Path)> = found
+            .iter()
+            .map(|p| (p.name.as_str(), p.path.as_path()))
+            .collect();
+        assert_eq!(
+            found,
+            [
+                ("a", Path::new("/remote/ws/a")),
+                ("b", Path::new("/remote/ws/group/b"))
+            ]
+        );
+    }
+
+    /// Fails to probe one path, like a directory the user may not search.
+    struct DeniedFilesystem(MemoryFilesystem, PathBuf);
+
+    impl Filesystem for DeniedFilesystem {
+        fn is_dir(&self, path: &Path) -> bool {
+            self.0.is_dir(path)
+        }
+
+        fn exists(&self, path: &Path) -> std::io::Result<bool> {
+            if path == self.1 {
+                return Err(std::io::ErrorKind::PermissionDenied.into());
+            }
+            self.0.exists(path)
+        }
+
+        fn read_dir(&self, path: &Path) -> std::io::Result<Vec<PathBuf>> {
+            self.0.read_dir(path)
+        }
+
+        fn open(&self, path: &Path) -> std::io::Result<Box<dyn std::io::Read + '_>> {
+            self.0.open(path)
+        }
+    }
+
+    #[test]
+    fn reports_marker_probe_errors() {
+        let tree = || {
+            MemoryFilesystem(BTreeMap::from([(
+                "/ws/restricted/a/package.xml".into(),
+                "<package><name>a</name></package>".into(),
+            )]))
+        };
+        let fs = DeniedFilesystem(tree(), "/ws/restricted/CATKIN_IGNORE".into());
+        let err = find_in(&fs, Path::new("/ws"), &ParseOptions::default()).unwrap_err();
+        assert!(format!("{err:#}").contains("'/ws/restricted/CATKIN_IGNORE'"));
+
+        let fs = DeniedFilesystem(tree(), "/ws/elsewhere".into());
+        let found = find_in(&fs, Path::new("/ws"), &ParseOptions::default()).unwrap();
+        assert_eq!(found.len(), 1);
+    }
+
+    #[test]
+    fn skips_invalid_manifests() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("ws");
+        for (name, contents) in [
+            ("valid", "<package><name>valid</name></package>"),
+            ("broken", "<package><name>broken</name>"),
+        ] {
+            fs::create_dir_all(root.join(name)).unwrap();
+            fs::write(root.join(name).join("package.xml"), contents).unwrap();
+        }
+        assert!(find_with_options(&root, &ParseOptions::default()).is_err());
+
+        let options = ParseOptions {
+            skip_invalid: true,
+            ..Default::default()
+        };
+        let (found, invalid) = find_in_reporting(&LocalFilesystem, &root, &options).unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].name, "valid");
+        assert_eq!(invalid.len(), 1);
+        assert_eq!(invalid[0].0, root.join("broken").join("package.xml"));
+    }
+
+    #[test]
+    fn honors_ignore_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("ws");
+        for path in ["src/a", "build/b", "src/vendor/c", "src/build/d"] {
+            let name = Path::new(path).file_name().unwrap().to_str().unwrap();
+            fs::create_dir_all(root.join(path)).unwrap();
+            fs::write(
+                root.join(path).join("package.xml"),
+                format!("<package><name>{name}</name></package>"),
+            )
+            .unwrap();
+        }
+        let names = || -> Vec<String> {
+            let found = find(&root).unwrap();
+            found.into_iter().map(|p| p.name).collect()
+        };
+        assert_eq!(names(), ["a", "b", "c", "d"]);
+        fs::write(root.join(IGNORE_FILE), "# Not ours\n/build/\nsrc/vendor\n").unwrap();
+        assert_eq!(names(), ["a", "d"]);
+        fs::write(root.join(IGNORE_FILE), "build/\n").unwrap();
+        assert_eq!(names(), ["a", "c"]);
+    }
+
+    #[test]
+    fn limits_search_depth() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("ws");
+        for path in ["a", "x/b", "x/y/c"] {
+            let name = Path::new(path).file_name().unwrap().to_str().unwrap();
+            fs::create_dir_all(root.join(path)).unwrap();
+            fs::write(
+                root.join(path).join("package.xml"),
+                format!("<package><name>{name}</name></package>"),
+            )
+            .unwrap();
+        }
+        let names = |max_depth, min_depth| -> Vec<String> {
+            let options = ParseOptions {
+                max_depth,
+                min_depth,
+                ..Default::default()
+            };
+            let found = find_with_options(&root, &options).unwrap();
+            found.into_iter().map(|p| p.name).collect()
+        };
+        assert_eq!(names(None, 0), ["a", "b", "c"]);
+        assert_eq!(names(Some(1), 0), ["a"]);
+        assert_eq!(names(Some(2), 0), ["a", "b"]);
+        assert_eq!(names(None, 2), ["b", "c"]);
+        assert!(names(Some(0), 0).is_empty());
+    }
+
+    #[test]
+    fn finds_manifest_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("ws");
+        fs::create_dir_all(root.join("a").join("b")).unwrap();
+        for (path, name) in [("a", "a"), ("a/b", "b")] {
+            fs::write(
+                root.join(path).join("package.xml"),
+                format!("<package><name>{name}</name></package>"),
+            )
+            .unwrap();
+        }
+        let found = find(&root.join("a").join("package.xml")).unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].name, "a");
+        assert_eq!(found[0].path, root.join("a"));
+        assert!(find(&root.join("a").join("missing.xml"))
+            .unwrap()
+            .is_empty());
+
+        // The subpath is stripped from a manifest given directly
+        fs::create_dir_all(root.join("c").join("ros")).unwrap();
+        let manifest = root.join("c").join("ros").join("package.xml");
+        fs::write(&manifest, "<package><name>c</name></package>").unwrap();
+        let options = ParseOptions {
+            manifest_subpath: "ros".into(),
+            ..Default::default()
+        };
+        let found = find_with_options(&manifest, &options).unwrap();
+        assert_eq!(found[0].path, root.join("c"));
+
+        // A broken manifest is an error, unless it is below the minimum depth
+        fs::write(&manifest, "<package>").unwrap();
+        assert!(find_with_options(&manifest, &options).is_err());
+        let options = ParseOptions {
+            min_depth: 1,
+            ..options
+        };
+        assert!(find_with_options(&manifest, &options).unwrap().is_empty());
+    }
+
+    #[test]
+    fn parallel_search_matches_serial() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("ws");
+        for i in 0..300 {
+            let mut path = root.clone();
+            for level in 0..i % 6 {
+                path.push(format!("d{}", (i >> level) % 3));
+            }
+            path.push(format!("pkg{i}"));
+            fs::create_dir_all(&path).unwrap();
+            fs::write(
+                path.join("package.xml"),
+                format!(
+                    "<package><name>pkg{i}</name><depend>pkg{}</depend></package>",
+                    i / 2
+                ),
+            )
+            .unwrap();
+        }
+        let serial = rayon::ThreadPoolBuilder::new()
+            .num_threads(1)
+            .build()
+            .unwrap()
+            .install(|| find(&root))
+            .unwrap();
+        assert_eq!(serial.len(), 300);
+        assert!(serial
+            .windows(2)
+            .all(|w| (&w[0].name, &w[0].path) < (&w[1].name, &w[1].path)));
+        for _ in 0..3 {
+            assert_eq!(find(&root).unwrap(), serial);
+        }
+    }
+
+    #[test]
+    fn rejects_oversized_manifests() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("ws");
+        let pkg = root.join("huge");
+        fs::create_dir_all(&pkg).unwrap();
+        let manifest = format!(
+            "<package><name>huge</name><description>{}</description><depend>last</depend></package>",
+            "x".repeat(4096)
+        );
+        fs::write(pkg.join("package.xml"), &manifest).unwrap();
+
+        let options = ParseOptions {
+            max_manifest_bytes: 1024,
+            ..Default::default()
+        };
+        let err = find_with_options(&root, &options).unwrap_err();
+        assert!(format!("{err:#}").contains("is larger than 1024 bytes"));
+
+        let options = ParseOptions {
+            max_manifest_bytes: manifest.len() as u64,
+            ..Default::default()
+        };
+        let found = find_with_options(&root, &options).unwrap();
+        assert_eq!(found[0].deps, [dep("last")]);
+    }
+
+    #[test]
+    fn skips_matching_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("ws");
+        for path in ["test/a", "src/unit_test_pkg", "examples/demo", "src/b"] {
+            let name = Path::new(path).file_name().unwrap().to_str().unwrap();
+            fs::create_dir_all(root.join(path)).unwrap();
+            fs::write(
+                root.join(path).join("package.xml"),
+                format!("<package><name>{name}</name></package>"),
+            )
+            .unwrap();
+        }
+        let names = |patterns: &[&str]| -> Vec<String> {
+            let options = ParseOptions {
+                skip_dirs: patterns
+                    .iter()
+                    .map(|p| glob::Pattern::new(p).unwrap())
+                    .collect(),
+                ..Default::default()
+            };
+            let found = find_with_options(&root, &options).unwrap();
+            found.into_iter().map(|p| p.name).collect()
+        };
+        assert_eq!(names(&["test"]), ["b", "demo", "unit_test_pkg"]);
+        assert_eq!(names(&["test", "example*"]), ["b", "unit_test_pkg"]);
+        assert_eq!(names(&["*test*"]), ["b", "demo"]);
+    }
+
+    #[test]
+    fn dot_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("ws");
+        for (sub, name) in [(".hidden", "hidden"), (".git", "vcs"), ("src", "visible")] {
+            let pkg = root.join(sub).join(name);
+            fs::create_dir_all(&pkg).unwrap();
+            fs::write(
+                pkg.join("package.xml"),
+                format!("<package><name>{name}</name></package>"),
+            )
+            .unwrap();
+        }
+        let names = |options: &ParseOptions| {
+            let mut found: Vec<String> = find_with_options(&root, options)
+                .unwrap()
+                .into_iter()
+                .map(|p| p.name)
+                .collect();
+            found.sort();
+            found
+        };
+        assert_eq!(names(&ParseOptions::default()), ["visible"]);
+        let options = ParseOptions {
+            include_dotdirs: true,
+            ..Default::default()
+        };
+        assert_eq!(names(&options), ["hidden", "visible"]);
+
+        // Given directly, hidden directories are searched
+        let found = find(&root.join(".hidden")).unwrap();
+        assert_eq!(found[0].name, "hidden");
+        let pkg = dir.path().join(".ci_pkg");
+        fs::create_dir_all(&pkg).unwrap();
+        fs::write(
+            pkg.join("package.xml"),
+            "<package><name>ci</name></package>",
+        )
+        .unwrap();
+        assert_eq!(find(&pkg).unwrap()[0].name, "ci");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn follows_symlinks() {
+        use std::os::unix::fs::symlink;
+
+        let dir = tempfile::tempdir().unwrap();
+        let pool = dir.path().join("pool").join("vendored");
+        fs::create_dir_all(&pool).unwrap();
+        fs::write(
+            pool.join("package.xml"),
+            "<package><name>vendored</name></package>",
+        )
+        .unwrap();
+        let root = dir.path().join("ws");
+        fs::create_dir_all(root.join("src")).unwrap();
+        symlink(&pool, root.join("src").join("vendored")).unwrap();
+        symlink(&root, root.join("src").join("loop")).unwrap();
+
+        assert!(find(&root).unwrap().is_empty());
+        let options = ParseOptions {
+            follow_symlinks: true,
+            ..Default::default()
+        };
+        let found = find_with_options(&root, &options).unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].name, "vendored");
+        assert_eq!(found[0].path, root.join("src").join("vendored"));
+    }
+
+    #[test]
+    fn incremental_update_matches_rescan() {
+        let dir = tempfile::tempdir().unwrap();
+        let ws_root = dir.path().join("ws");
+        let us_root = dir.path().join("upstream");
+        let write = |path: PathBuf, contents: &str| {
+            fs::create_dir_all(&path).unwrap();
+            fs::write(path.join("package.xml"), contents).unwrap();
+        };
+        write(
+            ws_root.join("robot"),
+            "<package><name>robot</name><depend>a</depend></package>",
+        );
+        write(us_root.join("a"), "<package><name>a</name></package>");
+        write(us_root.join("b"), "<package><name>b</name></package>");
+        write(us_root.join("c"), "<package><name>c</name></package>");
+
+        let options = ParseOptions::default();
+        let ws = find_with_options(&ws_root, &options).unwrap();
+        let mut index = PackageIndex::scan(&us_root, &options).unwrap();
+        let unused = |upstream: &[Package]| {
+            let mut res = find_unused_pkgs(&ws, upstream, &Dependency::all);
+            res.sort_by(|a, b| a.name.cmp(&b.name));
+            res
+        };
+        let names = |pkgs: Vec<Package>| pkgs.into_iter().map(|p| p.name).collect::<Vec<_>>();
+        assert_eq!(names(unused(&index.packages())), ["b", "c"]);
+
+        // a now needs b, c is gone
+        write(
+            us_root.join("a"),
+            "<package><name>a</name><depend>b</depend></package>",
+        );
+        index
+            .update(&us_root.join("a").join("package.xml"))
+            .unwrap();
+        fs::remove_dir_all(us_root.join("c")).unwrap();
+        index
+            .update(&us_root.join("c").join("package.xml"))
+            .unwrap();
+
+        let mut rescanned = find_with_options(&us_root, &options).unwrap();
+        rescanned.sort_by(|a, b| a.path.cmp(&b.path));
+        assert_eq!(index.packages(), rescanned);
+        assert_eq!(unused(&index.packages()), unused(&rescanned));
+        assert!(unused(&index.packages()).is_empty());
+    }
+
+    #[test]
+    fn incremental_update_applies_search_rules() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("upstream");
+        let write = |path: &Path, name: &str| {
+            fs::create_dir_all(path).unwrap();
+            let manifest = format!("<package><name>{name}</name></package>");
+            fs::write(path.join("package.xml"), manifest).unwrap();
+        };
+        write(&root.join("a"), "a");
+        fs::create_dir_all(root.join("ignored")).unwrap();
+        fs::write(root.join("ignored").join("COLCON_IGNORE"), "").unwrap();
+        let options = ParseOptions {
+            skip_dirs: vec![glob::Pattern::new("test").unwrap()],
+            max_depth: Some(2),
+            ..Default::default()
+        };
+        let mut index = PackageIndex::scan(&root, &options).unwrap();
+        let check = |index: &mut PackageIndex, changed: PathBuf| {
+            index.update(&changed).unwrap();
+            let mut rescanned = find_with_options(&root, &options).unwrap();
+            rescanned.sort_by(|a, b| a.path.cmp(&b.path));
+            assert_eq!(index.packages(), rescanned, "after '{}'", changed.display());
+            index.packages().len()
+        };
+
+        // New manifests that the search doesn't reach
+        write(&root.join("ignored").join("b"), "b");
+        assert_eq!(check(&mut index, root.join("ignored/b/package.xml")), 1);
+        write(&root.join("test").join("c"), "c");
+        assert_eq!(check(&mut index, root.join("test/c/package.xml")), 1);
+        write(&root.join("x").join("y").join("d"), "d");
+        assert_eq!(check(&mut index, root.join("x/y/d/package.xml")), 1);
+        write(&root.join("x").join("e"), "e");
+        assert_eq!(check(&mut index, root.join("x/e/package.xml")), 2);
+
+        // Markers coming and going
+        fs::write(root.join("a").join("COLCON_IGNORE"), "").unwrap();
+        assert_eq!(check(&mut index, root.join("a/COLCON_IGNORE")), 1);
+        fs::remove_file(root.join("ignored").join("COLCON_IGNORE")).unwrap();
+        assert_eq!(check(&mut index, root.join("ignored/COLCON_IGNORE")), 2);
+        fs::write(root.join(IGNORE_FILE), "x/\n").unwrap();
+        assert_eq!(check(&mut index, root.join(IGNORE_FILE)), 1);
+    }
+
+    #[test]
+    fn parses_replace_and_conflict() {
+        let manifest = r#"<package format="3">
+              <name>new_driver</name>
+              <replace>old_driver</replace>
+              <conflict>other_driver</conflict>
+            </package>
+            "#;
+        let parsed = from_str(manifest).unwrap();
+        assert_eq!(parsed.replaces, ["old_driver"]);
+        assert_eq!(parsed.conflicts, ["other_driver"]);
+        assert!(parsed.deps.is_empty());
+    }
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("500ms").unwrap(), Duration::from_millis(500));
+        assert_eq!(parse_duration("30").unwrap(), Duration::from_secs(30));
+        assert_eq!(parse_duration("5m").unwrap(), Duration::from_secs(300));
+        parse_duration("5 parsecs").expect_err("Should not have parsed!");
+        parse_duration("s").expect_err("Should not have parsed!");
+    }
+
+    #[test]
+    fn scan_times_out() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("ws");
+        for i in 0..50 {
+            let pkg = root.join(format!("group{}", i % 5)).join(format!("pkg{i}"));
+            fs::create_dir_all(&pkg).unwrap();
+            fs::write(
+                pkg.join("package.xml"),
+                format!("<package><name>pkg{i}</name></package>"),
+            )
+            .unwrap();
+        }
+        let expired = ParseOptions {
+            deadline: Some(Instant::now()),
+            ..Default::default()
+        };
+        let err = find_with_options(&root, &expired).unwrap_err();
+        assert!(err.to_string().starts_with("Timed out while searching"));
+
+        let generous = ParseOptions {
+            deadline: Some(Instant::now() + Duration::from_secs(600)),
+            ..Default::default()
+        };
+        assert_eq!(find_with_options(&root, &generous).unwrap().len(), 50);
+    }
 }
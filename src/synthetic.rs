@@ -0,0 +1,87 @@
+//! Generated package sets of arbitrary size, for benchmarks.
+
+use std::{fs, path::Path};
+
+use anyhow::{Context, Result};
+
+use crate::filtering::{DepType, Dependency, Package};
+
+/// `n` upstream packages `pkg0` to `pkg{n-1}`, where each package `i > 0` depends on
+/// `i / 2` and `i / 3`. This gives a connected graph with shared dependencies, in which
+/// depending on `pkg{k}` keeps roughly a logarithmic share of the packages.
+pub fn upstream(n: usize) -> Vec<Package> {
+    (0..n)
+        .map(|i| {
+            let mut deps: Vec<usize> = if i == 0 { vec![] } else { vec![i / 2, i / 3] };
+            deps.dedup();
+            Package {
+                name: format!("pkg{i}"),
+                path: format!("group{}/pkg{}", i % 16, i).into(),
+                deps: deps
+                    .into_iter()
+                    .map(|d| Dependency {
+                        name: format!("pkg{d}"),
+                        dep_type: DepType::All,
+                    })
+                    .collect(),
+                ..Default::default()
+            }
+        })
+        .collect()
+}
+
+/// A single workspace package depending on the last few of `n` [`upstream`] packages.
+pub fn workspace(n: usize) -> Vec<Package> {
+    vec![Package {
+        name: "robot".into(),
+        path: "robot".into(),
+        deps: (n.saturating_sub(3)..n)
+            .map(|i| Dependency {
+                name: format!("pkg{i}"),
+                dep_type: DepType::All,
+            })
+            .collect(),
+        ..Default::default()
+    }]
+}
+
+/// Write the [`upstream`] packages of size `n` as `package.xml` files below `root`.
+pub fn write_tree(root: &Path, n: usize) -> Result<()> {
+    for p in upstream(n) {
+        let dir = root.join(&p.path);
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("Could not create '{}'", dir.display()))?;
+        let deps: String = p
+            .deps
+            .iter()
+            .map(|d| format!("<depend>{}</depend>", d.name))
+            .collect();
+        fs::write(
+            dir.join("package.xml"),
+            format!("<package><name>{}</name>{}</package>", p.name, deps),
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{filtering::find_unused_pkgs, parsing::find};
+
+    #[test]
+    fn tree_matches_packages() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("upstream");
+        write_tree(&root, 40).unwrap();
+        let mut found = find(&root).unwrap();
+        found.sort_by_key(|p| p.name[3..].parse::<usize>().unwrap());
+        let expected = upstream(40);
+        assert_eq!(found.len(), 40);
+        for (f, e) in found.iter().zip(expected.iter()) {
+            assert_eq!((&f.name, &f.deps), (&e.name, &e.deps));
+        }
+        let unused = find_unused_pkgs(&workspace(40), &expected, &Dependency::all);
+        assert!(!unused.is_empty() && unused.len() < 40);
+    }
+}
@@ -0,0 +1,202 @@
+use std::{cmp::Ordering, collections::BTreeMap};
+
+use serde::{Deserialize, Serialize};
+
+use crate::filtering::Package;
+
+/// The comparison of a `version_*` attribute on a dependency tag.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum VersionOp {
+    Lt,
+    Lte,
+    Eq,
+    Gte,
+    Gt,
+}
+
+impl VersionOp {
+    /// The operator for a dependency tag attribute like `version_gte`.
+    pub fn from_attribute(name: &str) -> Option<VersionOp> {
+        match name {
+            "version_lt" => Some(VersionOp::Lt),
+            "version_lte" => Some(VersionOp::Lte),
+            "version_eq" => Some(VersionOp::Eq),
+            "version_gte" => Some(VersionOp::Gte),
+            "version_gt" => Some(VersionOp::Gt),
+            _ => None,
+        }
+    }
+
+    fn symbol(&self) -> &'static str {
+        match self {
+            VersionOp::Lt => "<",
+            VersionOp::Lte => "<=",
+            VersionOp::Eq => "=",
+            VersionOp::Gte => ">=",
+            VersionOp::Gt => ">",
+        }
+    }
+}
+
+/// A version requirement a package places on one of its dependencies.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct VersionConstraint {
+    pub dep: String,
+    pub op: VersionOp,
+    pub version: String,
+}
+
+impl VersionConstraint {
+    pub fn allows(&self, version: &str) -> bool {
+        let ord = compare_versions(version, &self.version);
+        match self.op {
+            VersionOp::Lt => ord == Ordering::Less,
+            VersionOp::Lte => ord != Ordering::Greater,
+            VersionOp::Eq => ord == Ordering::Equal,
+            VersionOp::Gte => ord != Ordering::Less,
+            VersionOp::Gt => ord == Ordering::Greater,
+        }
+    }
+}
+
+impl std::fmt::Display for VersionConstraint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {} {}", self.dep, self.op.symbol(), self.version)
+    }
+}
+
+/// Compare dotted versions component-wise, numerically where possible. Missing components
+/// count as 0, so `1.2` equals `1.2.0`.
+pub fn compare_versions(a: &str, b: &str) -> Ordering {
+    let mut a = a.trim().split('.');
+    let mut b = b.trim().split('.');
+    loop {
+        let ord = match (a.next(), b.next()) {
+            (None, None) => return Ordering::Equal,
+            (x, y) => {
+                let (x, y) = (x.unwrap_or("0"), y.unwrap_or("0"));
+                match (x.parse::<u64>(), y.parse::<u64>()) {
+                    (Ok(x), Ok(y)) => x.cmp(&y),
+                    _ => x.cmp(y),
+                }
+            }
+        };
+        if ord != Ordering::Equal {
+            return ord;
+        }
+    }
+}
+
+/// Version constraints on an upstream package, from different workspaces, that its version
+/// does not satisfy all at once.
+#[derive(Debug, PartialEq)]
+pub struct VersionConflict<'a> {
+    pub package: &'a Package,
+    /// Every constraint with the index of its workspace and the package declaring it
+    pub constraints: Vec<(usize, &'a Package, &'a VersionConstraint)>,
+}
+
+impl VersionConflict<'_> {
+    /// The constraints the upstream version violates.
+    pub fn violated(&self) -> impl Iterator<Item = &(usize, &Package, &VersionConstraint)> {
+        let version = self.package.version.as_deref().unwrap_or_default();
+        self.constraints
+            .iter()
+            .filter(move |(_, _, c)| !c.allows(version))
+    }
+}
+
+/// Upstream packages constrained by more than one of `workspaces` whose version doesn't
+/// satisfy all of those constraints. Packages without a version are skipped.
+pub fn version_conflicts<'a>(
+    workspaces: &'a [Vec<Package>],
+    upstream: &'a [Package],
+) -> Vec<VersionConflict<'a>> {
+    let mut constrained = BTreeMap::<&str, Vec<(usize, &Package, &VersionConstraint)>>::new();
+    for (i, ws) in workspaces.iter().enumerate() {
+        for p in ws {
+            for c in p.version_constraints.iter() {
+                constrained.entry(&c.dep).or_default().push((i, p, c));
+            }
+        }
+    }
+    upstream
+        .iter()
+        .filter(|p| p.version.is_some())
+        .filter_map(|package| {
+            let constraints = constrained.get(package.name.as_str())?.clone();
+            let mut sources: Vec<usize> = constraints.iter().map(|(i, _, _)| *i).collect();
+            sources.dedup();
+            let conflict = VersionConflict {
+                package,
+                constraints,
+            };
+            (sources.len() > 1 && conflict.violated().next().is_some()).then_some(conflict)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn constrained(name: &str, dep: &str, op: VersionOp, version: &str) -> Package {
+        Package {
+            name: name.into(),
+            version_constraints: vec![VersionConstraint {
+                dep: dep.into(),
+                op,
+                version: version.into(),
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn compares_versions() {
+        assert_eq!(compare_versions("1.10.0", "1.9.3"), Ordering::Greater);
+        assert_eq!(compare_versions("1.2", "1.2.0"), Ordering::Equal);
+        assert_eq!(compare_versions("0.9", "1.0"), Ordering::Less);
+        let c = VersionConstraint {
+            dep: "x".into(),
+            op: VersionOp::Lt,
+            version: "2.0".into(),
+        };
+        assert!(c.allows("1.99.0"));
+        assert!(!c.allows("2.0.0"));
+        assert_eq!(c.to_string(), "x < 2.0");
+    }
+
+    #[test]
+    fn reports_cross_workspace_conflicts() {
+        let upstream = vec![Package {
+            name: "lib".into(),
+            version: Some("2.1.0".into()),
+            ..Default::default()
+        }];
+        let new = constrained("new_robot", "lib", VersionOp::Gte, "2.0");
+        let old = constrained("old_robot", "lib", VersionOp::Lt, "2.0");
+        let workspaces = vec![vec![new.clone()], vec![old.clone()]];
+
+        let conflicts = version_conflicts(&workspaces, &upstream);
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].package.name, "lib");
+        let sources: Vec<&str> = conflicts[0]
+            .constraints
+            .iter()
+            .map(|(_, p, _)| p.name.as_str())
+            .collect();
+        assert_eq!(sources, ["new_robot", "old_robot"]);
+        let violated: Vec<String> = conflicts[0]
+            .violated()
+            .map(|(_, p, c)| format!("{}: {}", p.name, c))
+            .collect();
+        assert_eq!(violated, ["old_robot: lib < 2.0"]);
+
+        // Compatible constraints, or all from one workspace, are no conflict
+        let compatible = vec![vec![new.clone()], vec![new.clone()]];
+        assert!(version_conflicts(&compatible, &upstream).is_empty());
+        let single = vec![vec![new, old]];
+        assert!(version_conflicts(&single, &upstream).is_empty());
+    }
+}
@@ -0,0 +1,64 @@
+use std::{
+    fs::{self, File},
+    io::{self, Read},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+/// The read-only file system operations package discovery needs, so trees can be served from
+/// somewhere other than the local disk, e.g. a remote machine or an archive.
+///
+/// Implementations are shared by the threads of a search.
+pub trait Filesystem: Sync {
+    fn is_dir(&self, path: &Path) -> bool;
+    fn exists(&self, path: &Path) -> io::Result<bool>;
+    /// The paths of the entries of the directory at `path`.
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>>;
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read + '_>>;
+    fn is_symlink(&self, _path: &Path) -> bool {
+        false
+    }
+    /// The path with all symlinks resolved.
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        Ok(path.to_path_buf())
+    }
+    /// When the file at `path` was last modified, if known. Needed for the manifest cache.
+    fn modified(&self, _path: &Path) -> io::Result<Option<SystemTime>> {
+        Ok(None)
+    }
+}
+
+/// [`Filesystem`] backed by `std::fs`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LocalFilesystem;
+
+impl Filesystem for LocalFilesystem {
+    fn is_dir(&self, path: &Path) -> bool {
+        path.is_dir()
+    }
+
+    fn exists(&self, path: &Path) -> io::Result<bool> {
+        path.try_exists()
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        // Entries that vanish while listing are skipped
+        Ok(fs::read_dir(path)?.flatten().map(|e| e.path()).collect())
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read + '_>> {
+        Ok(Box::new(File::open(path)?))
+    }
+
+    fn is_symlink(&self, path: &Path) -> bool {
+        path.is_symlink()
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        path.canonicalize()
+    }
+
+    fn modified(&self, path: &Path) -> io::Result<Option<SystemTime>> {
+        fs::metadata(path)?.modified().map(Some)
+    }
+}